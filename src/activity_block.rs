@@ -0,0 +1,823 @@
+//! Reading and writing of the activity block (.ablk) format described in data_formats.md.
+//!
+//! Every consumer of .ablk files should go through the types in this module rather than parsing
+//! the format by hand.
+
+use std::{fs::File, io::{BufReader, BufWriter, Read, Write}};
+
+use crate::{demographics::Demographics, encodings::ActivityCategory, error::{Error, Result}};
+
+/// the header at the start of every activity block file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActivityBlockHeader {
+    /// the version of the format the file is stored in
+    pub version: u16,
+
+    /// the number of blocks in each day
+    pub blocks_per_day: u32,
+
+    /// the number of days in the file
+    pub day_count: u64,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    pub day_start: u32,
+
+    /// identifies the mapping from original activity codes to activity categories
+    pub category_scheme: u16,
+
+    /// whether the file contains a metadata section describing each day
+    pub has_metadata: bool,
+}
+
+impl ActivityBlockHeader {
+    /// the bytes identifying a versioned activity block file
+    pub const MAGIC: [u8; 4] = *b"ABLK";
+
+    /// the latest version of the format
+    pub const VERSION: u16 = 2;
+
+    /// the version of the format used by files without magic bytes
+    pub const LEGACY_VERSION: u16 = 1;
+
+    /// the size of the latest header in bytes
+    pub const SIZE: usize = 32;
+
+    /// the size of the legacy header in bytes
+    pub const LEGACY_SIZE: usize = 12;
+
+    /// set in the flags of the header when the file contains a metadata section
+    const METADATA_FLAG: u32 = 1;
+
+    /// creates a header for days divided into blocks of block_duration minutes
+    pub fn with_block_duration(block_duration: u32, day_count: u64) -> Result<Self> {
+        if block_duration == 0 || 24 * 60 % block_duration != 0 {
+            return Err(Error::InvalidBlockDuration(block_duration));
+        }
+
+        Ok(Self {
+            version: Self::VERSION,
+            blocks_per_day: 24 * 60 / block_duration,
+            day_count,
+            day_start: 0,
+            category_scheme: ActivityCategory::CATEGORY_SCHEME,
+            has_metadata: false,
+        })
+    }
+
+    /// the duration of each block in minutes
+    pub fn block_duration(&self) -> u32 {
+        24 * 60 / self.blocks_per_day
+    }
+
+    /// reads a header from the start of a file, accepting both the latest and legacy layouts
+    fn read<R: Read>(reader: &mut R, path: &str) -> Result<Self> {
+        let leading: [u8; 4] = read_bytes(reader, path, None)?;
+
+        let header = if leading == Self::MAGIC {
+            let version = u16::from_le_bytes(read_bytes(reader, path, None)?);
+            if version != Self::VERSION {
+                return Err(Error::HeaderMismatch {
+                    path: path.to_string(),
+                    field: "version",
+                    expected: Self::VERSION as u64,
+                    found: version as u64,
+                });
+            }
+
+            let category_scheme = u16::from_le_bytes(read_bytes(reader, path, None)?);
+            let block_duration = u32::from_le_bytes(read_bytes(reader, path, None)?);
+            let day_start = u32::from_le_bytes(read_bytes(reader, path, None)?);
+            let flags = u32::from_le_bytes(read_bytes(reader, path, None)?);
+            let _reserved: [u8; 4] = read_bytes(reader, path, None)?;
+            let day_count = u64::from_le_bytes(read_bytes(reader, path, None)?);
+
+            if block_duration == 0 || 24 * 60 % block_duration != 0 {
+                return Err(Error::InvalidBlockDuration(block_duration));
+            }
+
+            if day_start >= 24 * 60 {
+                return Err(Error::InvalidDayStart(day_start));
+            }
+
+            Self {
+                version,
+                blocks_per_day: 24 * 60 / block_duration,
+                day_count,
+                day_start,
+                category_scheme,
+                has_metadata: flags & Self::METADATA_FLAG != 0,
+            }
+        } else {
+            let blocks_per_day = u32::from_le_bytes(leading);
+            let day_count = u64::from_le_bytes(read_bytes(reader, path, None)?);
+
+            if blocks_per_day == 0 || 24 * 60 % blocks_per_day != 0 {
+                return Err(Error::InvalidBlocksPerDay(blocks_per_day));
+            }
+
+            Self {
+                version: Self::LEGACY_VERSION,
+                blocks_per_day,
+                day_count,
+                day_start: 0,
+                category_scheme: ActivityCategory::CATEGORY_SCHEME,
+                has_metadata: false,
+            }
+        };
+
+        if header.category_scheme != ActivityCategory::CATEGORY_SCHEME {
+            return Err(Error::HeaderMismatch {
+                path: path.to_string(),
+                field: "category scheme",
+                expected: ActivityCategory::CATEGORY_SCHEME as u64,
+                found: header.category_scheme as u64,
+            });
+        }
+
+        Ok(header)
+    }
+
+    /// writes the header to the start of a file, using the layout of its version
+    fn write<W: Write>(&self, writer: &mut W, path: &str) -> Result<()> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+
+        if self.version == Self::LEGACY_VERSION {
+            bytes.extend_from_slice(&self.blocks_per_day.to_le_bytes());
+            bytes.extend_from_slice(&self.day_count.to_le_bytes());
+        } else {
+            let flags = if self.has_metadata { Self::METADATA_FLAG } else { 0 };
+
+            bytes.extend_from_slice(&Self::MAGIC);
+            bytes.extend_from_slice(&self.version.to_le_bytes());
+            bytes.extend_from_slice(&self.category_scheme.to_le_bytes());
+            bytes.extend_from_slice(&self.block_duration().to_le_bytes());
+            bytes.extend_from_slice(&self.day_start.to_le_bytes());
+            bytes.extend_from_slice(&flags.to_le_bytes());
+            bytes.extend_from_slice(&[0; 4]);
+            bytes.extend_from_slice(&self.day_count.to_le_bytes());
+        }
+
+        writer.write_all(&bytes).map_err(Error::io(path))
+    }
+}
+
+/// information about the respondent and survey behind a single day
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DayMetadata {
+    /// the year the day was surveyed
+    pub year: u32,
+
+    /// the ATUS case id of the respondent
+    pub case_id: u64,
+
+    /// the survey weight of the day
+    pub weight: f64,
+
+    /// the demographic characteristics of the respondent
+    pub demographics: Demographics,
+}
+
+impl DayMetadata {
+    /// the size of the metadata of a single day in bytes
+    pub const SIZE: usize = 28;
+
+    /// reads the metadata of a single day
+    fn read<R: Read>(reader: &mut R, path: &str, day: u64) -> Result<Self> {
+        Ok(Self {
+            year: u32::from_le_bytes(read_bytes(reader, path, Some(day))?),
+            case_id: u64::from_le_bytes(read_bytes(reader, path, Some(day))?),
+            weight: f64::from_le_bytes(read_bytes(reader, path, Some(day))?),
+            demographics: Demographics {
+                family_income: u16::from_le_bytes(read_bytes(reader, path, Some(day))?),
+                tenure: u16::from_le_bytes(read_bytes(reader, path, Some(day))?),
+                housetype: u16::from_le_bytes(read_bytes(reader, path, Some(day))?),
+                schooling: u16::from_le_bytes(read_bytes(reader, path, Some(day))?),
+            },
+        })
+    }
+
+    /// writes the metadata of a single day
+    fn write<W: Write>(&self, writer: &mut W, path: &str) -> Result<()> {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.year.to_le_bytes());
+        bytes[4..12].copy_from_slice(&self.case_id.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.weight.to_le_bytes());
+        bytes[20..22].copy_from_slice(&self.demographics.family_income.to_le_bytes());
+        bytes[22..24].copy_from_slice(&self.demographics.tenure.to_le_bytes());
+        bytes[24..26].copy_from_slice(&self.demographics.housetype.to_le_bytes());
+        bytes[26..28].copy_from_slice(&self.demographics.schooling.to_le_bytes());
+        writer.write_all(&bytes).map_err(Error::io(path))
+    }
+}
+
+/// reads a fixed number of bytes, treating an early end of file as truncation
+fn read_bytes<R: Read, const N: usize>(reader: &mut R, path: &str, day: Option<u64>) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes).map_err(read_error(path, day))?;
+    Ok(bytes)
+}
+
+/// converts errors from reading a file, treating an early end of file as truncation
+fn read_error(path: &str, day: Option<u64>) -> impl FnOnce(std::io::Error) -> Error + '_ {
+    move |e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::TruncatedBlockFile { path: path.to_string(), day },
+        _ => Error::Io { path: path.to_string(), source: e },
+    }
+}
+
+/// the 64 bit FNV-1a hash of a sequence of bytes
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+}
+
+/// converts the codes of a day into activities, failing on codes with no matching activity
+fn decode_day(codes: &[u8], activities: &mut Vec<ActivityCategory>, path: &str, day: u64) -> Result<()> {
+    activities.clear();
+    for (block, &code) in codes.iter().enumerate() {
+        let activity = ActivityCategory::from_code(code).ok_or_else(|| Error::InvalidBlockCode {
+            path: path.to_string(),
+            day,
+            block: block as u32,
+            code,
+        })?;
+        activities.push(activity);
+    }
+    Ok(())
+}
+
+/// reads the days of an activity block file one at a time without loading the whole file
+pub struct ActivityBlockReader<R: Read> {
+    /// the underlying reader, positioned at the start of the next day
+    reader: R,
+
+    /// the path of the file, used for error reporting
+    path: String,
+
+    header: ActivityBlockHeader,
+
+    /// the index of the next day to be read
+    next_day: u64,
+
+    /// buffer for the raw codes of the current day
+    codes: Vec<u8>,
+
+    /// buffer for the decoded activities of the current day
+    activities: Vec<ActivityCategory>,
+}
+
+impl ActivityBlockReader<BufReader<File>> {
+    /// opens an activity block file for reading
+    pub fn open(path: &str) -> Result<Self> {
+        let f = File::open(path).map_err(Error::io(path))?;
+        Self::new(BufReader::new(f), path)
+    }
+}
+
+impl<R: Read> ActivityBlockReader<R> {
+    /// creates a reader from the start of an activity block file
+    /// path is only used for error reporting
+    pub fn new(mut reader: R, path: &str) -> Result<Self> {
+        let header = ActivityBlockHeader::read(&mut reader, path)?;
+        let blocks_per_day = header.blocks_per_day as usize;
+
+        Ok(Self {
+            reader,
+            path: path.to_string(),
+            header,
+            next_day: 0,
+            codes: vec![0; blocks_per_day],
+            activities: Vec::with_capacity(blocks_per_day),
+        })
+    }
+
+    /// the header of the file being read
+    pub fn header(&self) -> &ActivityBlockHeader {
+        &self.header
+    }
+
+    /// reads the next day in the file, or None if every day has been read
+    pub fn next_day(&mut self) -> Result<Option<&[ActivityCategory]>> {
+        if self.next_day >= self.header.day_count {
+            return Ok(None);
+        }
+
+        let day = self.next_day;
+        self.reader.read_exact(&mut self.codes).map_err(read_error(&self.path, Some(day)))?;
+        decode_day(&self.codes, &mut self.activities, &self.path, day)?;
+        self.next_day += 1;
+
+        Ok(Some(&self.activities))
+    }
+
+    /// reads the metadata section of the file, skipping any days that have not been read
+    /// returns None if the file has no metadata section
+    pub fn read_metadata(mut self) -> Result<Option<Vec<DayMetadata>>> {
+        if !self.header.has_metadata {
+            return Ok(None);
+        }
+
+        while self.next_day < self.header.day_count {
+            let day = self.next_day;
+            self.reader.read_exact(&mut self.codes).map_err(read_error(&self.path, Some(day)))?;
+            self.next_day += 1;
+        }
+
+        let mut metadata = Vec::with_capacity(self.header.day_count as usize);
+        for day in 0..self.header.day_count {
+            metadata.push(DayMetadata::read(&mut self.reader, &self.path, day)?);
+        }
+
+        Ok(Some(metadata))
+    }
+}
+
+/// an activity block file loaded into memory, allowing random access to its days
+#[derive(Debug, Clone)]
+pub struct ActivityBlockFile {
+    header: ActivityBlockHeader,
+
+    /// the activities of every day, stored contiguously
+    activities: Vec<ActivityCategory>,
+
+    /// the metadata of every day, if the file has any
+    metadata: Option<Vec<DayMetadata>>,
+
+    /// the FNV-1a hash of the bytes the file was read from
+    source_hash: u64,
+}
+
+impl ActivityBlockFile {
+    /// reads an entire activity block file into memory
+    pub fn open(path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(Error::io(path))?;
+        Self::from_bytes(&bytes, path)
+    }
+
+    /// reads an entire activity block file from bytes already in memory
+    /// path is only used for error reporting
+    pub fn from_bytes(bytes: &[u8], path: &str) -> Result<Self> {
+        let source_hash = fnv1a(bytes);
+
+        let mut reader = ActivityBlockReader::new(bytes, path)?;
+        let header = *reader.header();
+
+        // every block is a byte of the file, so a corrupt day count cannot reserve more than was read
+        let capacity = (header.blocks_per_day as u64).saturating_mul(header.day_count).min(bytes.len() as u64);
+        let mut activities = Vec::with_capacity(capacity as usize);
+        while let Some(day) = reader.next_day()? {
+            activities.extend_from_slice(day);
+        }
+        let metadata = reader.read_metadata()?;
+
+        Ok(Self { header, activities, metadata, source_hash })
+    }
+
+    /// the header of the file
+    pub fn header(&self) -> &ActivityBlockHeader {
+        &self.header
+    }
+
+    /// the number of blocks in each day
+    pub fn blocks_per_day(&self) -> usize {
+        self.header.blocks_per_day as usize
+    }
+
+    /// the number of days in the file
+    pub fn day_count(&self) -> usize {
+        self.header.day_count as usize
+    }
+
+    /// gets the activities of the day at the given index
+    pub fn day(&self, index: usize) -> Option<&[ActivityCategory]> {
+        let blocks_per_day = self.blocks_per_day();
+        self.activities.get(index * blocks_per_day..(index + 1) * blocks_per_day)
+    }
+
+    /// gets the metadata of the day at the given index, if the file has any
+    pub fn day_metadata(&self, index: usize) -> Option<&DayMetadata> {
+        self.metadata.as_ref()?.get(index)
+    }
+
+    /// the FNV-1a hash of the bytes the file was read from, identifying the data a model was trained on
+    pub fn source_hash(&self) -> u64 {
+        self.source_hash
+    }
+
+    /// iterates over the activities of every day in the file
+    pub fn days(&self) -> impl ExactSizeIterator<Item = &[ActivityCategory]> {
+        self.activities.chunks_exact(self.blocks_per_day())
+    }
+
+    /// creates a file of the days at the given indices, in the order given
+    /// the source hash of the subset is the hash of the bytes it is saved as
+    /// panics if any index is not less than the number of days in the file
+    pub fn subset(&self, day_indices: impl IntoIterator<Item = usize>) -> Self {
+        let day_indices: Vec<usize> = day_indices.into_iter().collect();
+
+        let mut activities = Vec::with_capacity(day_indices.len() * self.blocks_per_day());
+        for &index in &day_indices {
+            activities.extend_from_slice(self.day(index).expect("day index out of range"));
+        }
+        let metadata = self.metadata.as_ref()
+            .map(|metadata| day_indices.iter().map(|&index| metadata[index]).collect());
+
+        let mut subset = Self {
+            header: ActivityBlockHeader { day_count: day_indices.len() as u64, ..self.header },
+            activities,
+            metadata,
+            source_hash: 0,
+        };
+
+        // every day was already validated when this file was read, so writing to memory cannot fail
+        let bytes = subset.write(Vec::new(), "<memory>").expect("failed to encode a subset of a valid file");
+        subset.source_hash = fnv1a(&bytes);
+        subset
+    }
+
+    /// writes the file to the given path
+    pub fn save(&self, path: &str) -> Result<()> {
+        let f = File::create(path).map_err(Error::io(path))?;
+        self.write(BufWriter::new(f), path)?;
+        Ok(())
+    }
+
+    /// writes the file to a writer, returning the writer
+    /// path is only used for error reporting
+    fn write<W: Write>(&self, writer: W, path: &str) -> Result<W> {
+        let mut writer = ActivityBlockWriter::new(writer, path, self.header)?;
+        for day in self.days() {
+            writer.write_day(day)?;
+        }
+        for metadata in self.metadata.iter().flatten() {
+            writer.write_metadata(*metadata)?;
+        }
+        writer.finish()
+    }
+}
+
+/// writes an activity block file one day at a time, validating each day as it is written
+pub struct ActivityBlockWriter<W: Write> {
+    /// the underlying writer
+    writer: W,
+
+    /// the path of the file, used for error reporting
+    path: String,
+
+    header: ActivityBlockHeader,
+
+    /// the number of days written so far
+    days_written: u64,
+
+    /// buffer for the codes of the day being written
+    codes: Vec<u8>,
+
+    /// the metadata of each day, written after every day has been written
+    metadata: Vec<DayMetadata>,
+}
+
+impl ActivityBlockWriter<BufWriter<File>> {
+    /// creates an activity block file at the given path
+    pub fn create(path: &str, header: ActivityBlockHeader) -> Result<Self> {
+        let f = File::create(path).map_err(Error::io(path))?;
+        Self::new(BufWriter::new(f), path, header)
+    }
+}
+
+impl<W: Write> ActivityBlockWriter<W> {
+    /// creates a writer, immediately writing the header
+    /// path is only used for error reporting
+    pub fn new(mut writer: W, path: &str, header: ActivityBlockHeader) -> Result<Self> {
+        header.write(&mut writer, path)?;
+
+        Ok(Self {
+            writer,
+            path: path.to_string(),
+            header,
+            days_written: 0,
+            codes: Vec::with_capacity(header.blocks_per_day as usize),
+            metadata: Vec::new(),
+        })
+    }
+
+    /// the header of the file being written
+    pub fn header(&self) -> &ActivityBlockHeader {
+        &self.header
+    }
+
+    /// checks that another day of the given length may be written
+    fn check_day(&self, len: usize) -> Result<()> {
+        if len != self.header.blocks_per_day as usize {
+            return Err(Error::HeaderMismatch {
+                path: self.path.clone(),
+                field: "blocks per day",
+                expected: self.header.blocks_per_day as u64,
+                found: len as u64,
+            });
+        }
+
+        if self.days_written >= self.header.day_count {
+            return Err(Error::HeaderMismatch {
+                path: self.path.clone(),
+                field: "day count",
+                expected: self.header.day_count,
+                found: self.days_written + 1,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// writes the activities of a single day
+    pub fn write_day(&mut self, day: &[ActivityCategory]) -> Result<()> {
+        self.check_day(day.len())?;
+
+        self.codes.clear();
+        self.codes.extend(day.iter().map(ActivityCategory::into_code));
+        self.writer.write_all(&self.codes).map_err(Error::io(&self.path))?;
+        self.days_written += 1;
+
+        Ok(())
+    }
+
+    /// writes a single day given as raw activity codes, failing if any code is invalid
+    pub fn write_codes(&mut self, codes: &[u8]) -> Result<()> {
+        self.check_day(codes.len())?;
+
+        for (block, &code) in codes.iter().enumerate() {
+            if ActivityCategory::from_code(code).is_none() {
+                return Err(Error::InvalidBlockCode {
+                    path: self.path.clone(),
+                    day: self.days_written,
+                    block: block as u32,
+                    code,
+                });
+            }
+        }
+
+        self.writer.write_all(codes).map_err(Error::io(&self.path))?;
+        self.days_written += 1;
+
+        Ok(())
+    }
+
+    /// records the metadata of the next day, in the same order days are written
+    /// fails if the header does not specify a metadata section
+    pub fn write_metadata(&mut self, metadata: DayMetadata) -> Result<()> {
+        if !self.header.has_metadata {
+            return Err(Error::HeaderMismatch {
+                path: self.path.clone(),
+                field: "metadata flag",
+                expected: 1,
+                found: 0,
+            });
+        }
+
+        self.metadata.push(metadata);
+        Ok(())
+    }
+
+    /// writes the metadata section and flushes the file
+    /// fails if fewer days or metadata entries were written than the header specifies
+    pub fn finish(mut self) -> Result<W> {
+        if self.days_written != self.header.day_count {
+            return Err(Error::HeaderMismatch {
+                path: self.path,
+                field: "day count",
+                expected: self.header.day_count,
+                found: self.days_written,
+            });
+        }
+
+        if self.header.has_metadata {
+            if self.metadata.len() as u64 != self.header.day_count {
+                return Err(Error::HeaderMismatch {
+                    path: self.path,
+                    field: "metadata count",
+                    expected: self.header.day_count,
+                    found: self.metadata.len() as u64,
+                });
+            }
+
+            for metadata in &self.metadata {
+                metadata.write(&mut self.writer, &self.path)?;
+            }
+        }
+
+        self.writer.flush().map_err(Error::io(&self.path))?;
+        Ok(self.writer)
+    }
+}
+
+/// a file of 6 four hour blocks per day, with a day of each of the given activity sequences
+#[cfg(test)]
+pub(crate) fn test_block_file(days: &[[u8; 6]]) -> ActivityBlockFile {
+    let header = ActivityBlockHeader::with_block_duration(4 * 60, days.len() as u64).unwrap();
+    let mut writer = ActivityBlockWriter::new(Vec::new(), "test", header).unwrap();
+    for day in days {
+        writer.write_codes(day).unwrap();
+    }
+    ActivityBlockFile::from_bytes(&writer.finish().unwrap(), "test").unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a day of 6 four hour blocks, all spent on the activity with the given code
+    fn day(code: u8) -> Vec<ActivityCategory> {
+        vec![ActivityCategory::from_code(code).unwrap(); 6]
+    }
+
+    fn metadata(case_id: u64) -> DayMetadata {
+        DayMetadata {
+            year: 2022,
+            case_id,
+            weight: 1234.5,
+            demographics: Demographics { family_income: 9, tenure: 1, housetype: 2, schooling: 3 },
+        }
+    }
+
+    /// writes the given days to memory, with metadata if any is given
+    fn write(header: ActivityBlockHeader, days: &[Vec<ActivityCategory>], metadata: &[DayMetadata]) -> Vec<u8> {
+        let mut writer = ActivityBlockWriter::new(Vec::new(), "test", header).unwrap();
+        for day in days {
+            writer.write_day(day).unwrap();
+        }
+        for &metadata in metadata {
+            writer.write_metadata(metadata).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn header(day_count: u64) -> ActivityBlockHeader {
+        ActivityBlockHeader::with_block_duration(4 * 60, day_count).unwrap()
+    }
+
+    #[test]
+    fn latest_version_round_trips() {
+        let days = [day(0), day(5), day(20)];
+        let header = ActivityBlockHeader { day_start: 240, ..header(3) };
+        let bytes = write(header, &days, &[]);
+        assert_eq!(bytes.len(), ActivityBlockHeader::SIZE + 3 * 6);
+
+        let file = ActivityBlockFile::from_bytes(&bytes, "test").unwrap();
+        assert_eq!(*file.header(), header);
+        assert!(file.days().eq(days.iter().map(Vec::as_slice)));
+        assert_eq!(file.day_metadata(0), None);
+        assert_eq!(file.source_hash(), fnv1a(&bytes));
+    }
+
+    #[test]
+    fn legacy_version_round_trips() {
+        let days = [day(1), day(2)];
+        let header = ActivityBlockHeader { version: ActivityBlockHeader::LEGACY_VERSION, ..header(2) };
+        let bytes = write(header, &days, &[]);
+        assert_eq!(bytes.len(), ActivityBlockHeader::LEGACY_SIZE + 2 * 6);
+
+        let file = ActivityBlockFile::from_bytes(&bytes, "test").unwrap();
+        assert_eq!(*file.header(), header);
+        assert!(file.days().eq(days.iter().map(Vec::as_slice)));
+    }
+
+    #[test]
+    fn hand_written_legacy_files_are_accepted() {
+        // a 12 byte header of blocks per day and day count, followed by the codes of each day
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&6u32.to_le_bytes());
+        bytes.extend_from_slice(&2u64.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0, 1, 1, 2, 2]);
+        bytes.extend_from_slice(&[20; 6]);
+
+        let file = ActivityBlockFile::from_bytes(&bytes, "test").unwrap();
+        assert_eq!(file.header().version, ActivityBlockHeader::LEGACY_VERSION);
+        assert_eq!(file.header().block_duration(), 4 * 60);
+        assert_eq!(file.header().day_start, 0);
+        assert!(!file.header().has_metadata);
+        assert_eq!(file.day(0).unwrap()[2], ActivityCategory::from_code(1).unwrap());
+        assert_eq!(file.day(1), Some([ActivityCategory::MissingData; 6].as_slice()));
+    }
+
+    #[test]
+    fn legacy_blocks_per_day_must_divide_the_day() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&7u32.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes());
+
+        let result = ActivityBlockFile::from_bytes(&bytes, "test");
+        assert!(matches!(result, Err(Error::InvalidBlocksPerDay(7))));
+    }
+
+    #[test]
+    fn day_metadata_round_trips() {
+        let days = [day(3), day(4)];
+        let metadata = [metadata(1), metadata(2)];
+        let bytes = write(ActivityBlockHeader { has_metadata: true, ..header(2) }, &days, &metadata);
+        assert_eq!(bytes.len(), ActivityBlockHeader::SIZE + 2 * 6 + 2 * DayMetadata::SIZE);
+
+        let file = ActivityBlockFile::from_bytes(&bytes, "test").unwrap();
+        assert_eq!(file.day_metadata(0), Some(&metadata[0]));
+        assert_eq!(file.day_metadata(1), Some(&metadata[1]));
+
+        // the metadata can also be read without reading the days first
+        let reader = ActivityBlockReader::new(bytes.as_slice(), "test").unwrap();
+        assert_eq!(reader.read_metadata().unwrap(), Some(metadata.to_vec()));
+    }
+
+    #[test]
+    fn writer_checks_the_day_and_metadata_counts() {
+        let mut writer = ActivityBlockWriter::new(Vec::new(), "test", header(1)).unwrap();
+        writer.write_day(&day(0)).unwrap();
+        assert!(matches!(writer.write_day(&day(0)), Err(Error::HeaderMismatch { field: "day count", .. })));
+        assert!(matches!(writer.write_metadata(metadata(1)), Err(Error::HeaderMismatch { field: "metadata flag", .. })));
+
+        let writer = ActivityBlockWriter::new(Vec::new(), "test", ActivityBlockHeader { has_metadata: true, ..header(1) }).unwrap();
+        assert!(matches!(writer.finish(), Err(Error::HeaderMismatch { field: "day count", expected: 1, found: 0, .. })));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let bytes = write(ActivityBlockHeader { has_metadata: true, ..header(2) }, &[day(0), day(1)], &[metadata(1), metadata(2)]);
+
+        let truncated_header = ActivityBlockFile::from_bytes(&bytes[..ActivityBlockHeader::SIZE - 1], "test");
+        assert!(matches!(truncated_header, Err(Error::TruncatedBlockFile { day: None, .. })));
+
+        let truncated_day = ActivityBlockFile::from_bytes(&bytes[..ActivityBlockHeader::SIZE + 6 + 3], "test");
+        assert!(matches!(truncated_day, Err(Error::TruncatedBlockFile { day: Some(1), .. })));
+
+        let truncated_metadata = ActivityBlockFile::from_bytes(&bytes[..bytes.len() - 1], "test");
+        assert!(matches!(truncated_metadata, Err(Error::TruncatedBlockFile { day: Some(1), .. })));
+    }
+
+    #[test]
+    fn oversized_day_counts_are_rejected() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&6u32.to_le_bytes());
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0; 6]);
+
+        let result = ActivityBlockFile::from_bytes(&bytes, "test");
+        assert!(matches!(result, Err(Error::TruncatedBlockFile { day: Some(1), .. })));
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() {
+        let mut bytes = write(header(1), &[day(0)], &[]);
+        bytes[4..6].copy_from_slice(&3u16.to_le_bytes());
+
+        let result = ActivityBlockFile::from_bytes(&bytes, "test");
+        assert!(matches!(result, Err(Error::HeaderMismatch { field: "version", expected: 2, found: 3, .. })));
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        // without the magic bytes the file is read as a legacy file, whose blocks per day this is not
+        let mut bytes = write(header(1), &[day(0)], &[]);
+        bytes[0..4].copy_from_slice(b"ABLX");
+
+        let blocks_per_day = u32::from_le_bytes(*b"ABLX");
+        let result = ActivityBlockFile::from_bytes(&bytes, "test");
+        assert!(matches!(result, Err(Error::InvalidBlocksPerDay(found)) if found == blocks_per_day));
+    }
+
+    #[test]
+    fn invalid_activity_codes_are_rejected() {
+        let invalid = ActivityCategory::COUNT as u8;
+
+        let mut writer = ActivityBlockWriter::new(Vec::new(), "test", header(1)).unwrap();
+        let result = writer.write_codes(&[0, 0, invalid, 0, 0, 0]);
+        assert!(matches!(result, Err(Error::InvalidBlockCode { day: 0, block: 2, .. })));
+
+        let mut bytes = write(header(2), &[day(0), day(0)], &[]);
+        bytes[ActivityBlockHeader::SIZE + 6 + 4] = invalid;
+        let result = ActivityBlockFile::from_bytes(&bytes, "test");
+        assert!(matches!(result, Err(Error::InvalidBlockCode { day: 1, block: 4, .. })));
+    }
+
+    #[test]
+    fn subset_keeps_the_selected_days_and_metadata() {
+        let days = [day(0), day(1), day(2)];
+        let metadata = [metadata(1), metadata(2), metadata(3)];
+        let bytes = write(ActivityBlockHeader { has_metadata: true, ..header(3) }, &days, &metadata);
+        let file = ActivityBlockFile::from_bytes(&bytes, "test").unwrap();
+
+        let subset = file.subset([2, 0]);
+        assert_eq!(subset.day_count(), 2);
+        assert_eq!(subset.day(0), Some(days[2].as_slice()));
+        assert_eq!(subset.day(1), Some(days[0].as_slice()));
+        assert_eq!(subset.day_metadata(0), Some(&metadata[2]));
+
+        // the hash of a subset is the hash of the file it would be saved as
+        let reread = ActivityBlockFile::from_bytes(&subset.write(Vec::new(), "test").unwrap(), "test").unwrap();
+        assert_eq!(reread.source_hash(), subset.source_hash());
+    }
+
+    #[test]
+    #[should_panic]
+    fn subset_panics_on_out_of_range_days() {
+        let bytes = write(header(1), &[day(0)], &[]);
+        ActivityBlockFile::from_bytes(&bytes, "test").unwrap().subset([1]);
+    }
+}
@@ -0,0 +1,638 @@
+//! Implementation of a DayForecaster that forecasts with the rest of the historical days whose
+//! beginnings most closely match what has been done so far.
+//!
+//! Historical days are indexed block by block rather than day by day, so the distance from a
+//! beginning to every historical day is found in a single pass over contiguous memory that the
+//! compiler can vectorize, keeping searches of hundreds of thousands of days interactive.
+
+use std::{cell::RefCell, rc::Rc};
+
+use rand::{Rng, RngExt, SeedableRng};
+
+use crate::{activity_block::ActivityBlockFile, day_forecaster::{DayForecaster, DistributionForecast, DistributionForecaster, Forecast, Reseed}, encodings::ActivityCategory, error::Result, markov_chain::{self, TrainingOptions}};
+
+/// the number of days whose edit distances are computed together
+const EDIT_CHUNK: usize = 256;
+
+/// the smaller of two distances, which needs none of the NaN handling of f64::min as distances
+/// are never NaN
+fn smaller(a: f64, b: f64) -> f64 {
+    if a < b { a } else { b }
+}
+
+/// how the distance between the beginnings of two days is measured
+/// blocks either day is missing data for never add to the distance
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PrefixDistance {
+    /// the number of blocks the days differ in
+    #[default]
+    Hamming,
+
+    /// the cheapest way to turn one day into the other by substituting, inserting and deleting
+    /// blocks, so a day doing the same things slightly earlier or later is still close
+    Edit {
+        /// the cost of replacing the activity of a block
+        substitution: f64,
+
+        /// the cost of inserting or deleting a block
+        indel: f64,
+
+        /// the greatest number of blocks an activity may be shifted by
+        max_shift: usize,
+    },
+}
+
+/// options controlling how close historical days are found
+#[derive(Debug, Clone, Copy)]
+pub struct AnalogOptions {
+    pub distance: PrefixDistance,
+
+    /// how much less each block counts than the block after it, so recent blocks can matter more
+    /// than the start of the day
+    /// 1.0 counts every block equally
+    pub decay: f64,
+
+    /// the number of closest days distribution forecasts are made from
+    pub neighbours: usize,
+}
+
+impl Default for AnalogOptions {
+    /// compares undecayed hamming distances, making distribution forecasts from 50 days
+    fn default() -> Self {
+        Self {
+            distance: PrefixDistance::Hamming,
+            decay: 1.0,
+            neighbours: 50,
+        }
+    }
+}
+
+impl AnalogOptions {
+    pub fn with_distance(mut self, distance: PrefixDistance) -> Self {
+        self.distance = distance;
+        self
+    }
+
+    pub fn with_decay(mut self, decay: f64) -> Self {
+        assert!(decay > 0.0 && decay <= 1.0, "decay must be greater than 0.0 and at most 1.0");
+        self.decay = decay;
+        self
+    }
+
+    pub fn with_neighbours(mut self, neighbours: usize) -> Self {
+        self.neighbours = neighbours;
+        self
+    }
+}
+
+/// a historical day found close to a beginning
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Neighbour {
+    /// the index of the day within the index
+    pub day: usize,
+
+    pub distance: f64,
+}
+
+/// historical days stored block by block so that they can be searched by their beginnings
+pub struct AnalogIndex {
+    /// the number of blocks in each day
+    blocks_per_day: usize,
+
+    /// the activity code of every day in each block, with columns[block * day_count + day] being
+    /// the code of the given day and block
+    columns: Vec<u8>,
+
+    /// the weight of every day, used to choose between equally close days
+    weights: Vec<f64>,
+}
+
+impl AnalogIndex {
+    /// indexes every day of a loaded activity block file selected by the training options
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions) -> Result<Self> {
+        let blocks_per_day = file.blocks_per_day();
+
+        let mut days = Vec::new();
+        let mut weights = Vec::new();
+        markov_chain::for_each_training_day(file, path, options, |activities, weight| {
+            if weight > 0.0 {
+                days.extend(activities.iter().map(ActivityCategory::into_code));
+                weights.push(weight);
+            }
+        })?;
+
+        let day_count = weights.len();
+        let mut columns = vec![0; days.len()];
+        for (day, codes) in days.chunks_exact(blocks_per_day).enumerate() {
+            for (block, &code) in codes.iter().enumerate() {
+                columns[block * day_count + day] = code;
+            }
+        }
+
+        Ok(Self {
+            blocks_per_day,
+            columns,
+            weights,
+        })
+    }
+
+    /// indexes every day of the activity block file at the given path selected by the training options
+    pub fn from_block_file(path: &str, options: &TrainingOptions) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options)
+    }
+
+    /// the number of blocks in each day
+    pub fn blocks_per_day(&self) -> usize {
+        self.blocks_per_day
+    }
+
+    /// the number of days indexed
+    pub fn day_count(&self) -> usize {
+        self.weights.len()
+    }
+
+    /// the activities of a day, or None if there is no such day
+    pub fn day(&self, day: usize) -> Option<Vec<ActivityCategory>> {
+        (day < self.day_count()).then(|| self.activities(day, 0).collect())
+    }
+
+    /// the activity codes of every day in a block
+    fn column(&self, block: usize) -> &[u8] {
+        let day_count = self.day_count();
+        &self.columns[block * day_count..(block + 1) * day_count]
+    }
+
+    /// the weight of a day, used to choose between equally close days
+    pub(crate) fn weight(&self, day: usize) -> f64 {
+        self.weights[day]
+    }
+
+    /// the activities of a day from the given block onwards
+    pub(crate) fn activities(&self, day: usize, from: usize) -> impl Iterator<Item = ActivityCategory> + '_ {
+        (from..self.blocks_per_day).map(move |block| ActivityCategory::from_code(self.column(block)[day]).unwrap())
+    }
+
+    /// the k days whose beginnings are closest to initial_conditions, from closest to furthest
+    /// days equally close to the k-th closest are chosen between at random in proportion to their
+    /// weights, and fewer than k days are returned only if fewer are indexed
+    /// no days are returned if initial_conditions leave no block of the day to forecast
+    pub fn nearest<R: Rng + ?Sized>(
+        &self,
+        initial_conditions: &[ActivityCategory],
+        k: usize,
+        options: &AnalogOptions,
+        rng: &mut R,
+    ) -> Vec<Neighbour> {
+        if k == 0 || self.day_count() == 0 || initial_conditions.len() >= self.blocks_per_day {
+            return Vec::new();
+        }
+
+        let query: Vec<u8> = initial_conditions.iter().map(ActivityCategory::into_code).collect();
+        // the weight of each block of the beginning, with the last block counting fully
+        let block_weights: Vec<f64> = (0..query.len())
+            .map(|i| options.decay.powi((query.len() - 1 - i) as i32))
+            .collect();
+
+        let distances = match options.distance {
+            PrefixDistance::Hamming => self.hamming_distances(&query, &block_weights),
+            PrefixDistance::Edit { substitution, indel, max_shift } => {
+                self.edit_distances(&query, &block_weights, substitution, indel, max_shift)
+            }
+        };
+
+        self.choose(&distances, k, rng)
+    }
+
+    /// the weighted number of blocks every day differs from query in
+    fn hamming_distances(&self, query: &[u8], block_weights: &[f64]) -> Vec<f64> {
+        let missing = ActivityCategory::MissingData.into_code();
+
+        let mut distances = vec![0.0; self.day_count()];
+        for (block, (&code, &weight)) in query.iter().zip(block_weights).enumerate() {
+            if code == missing {
+                continue;
+            }
+
+            // written without branches so the loop can be vectorized
+            for (distance, &other) in distances.iter_mut().zip(self.column(block)) {
+                *distance += weight * ((other != code) & (other != missing)) as u8 as f64;
+            }
+        }
+        distances
+    }
+
+    /// the weighted edit distance between query and the beginning of every day, only aligning
+    /// blocks at most max_shift apart
+    fn edit_distances(&self, query: &[u8], block_weights: &[f64], substitution: f64, indel: f64, max_shift: usize) -> Vec<f64> {
+        let missing = ActivityCategory::MissingData.into_code();
+        let length = query.len();
+        let day_count = self.day_count();
+
+        let mut distances = Vec::with_capacity(day_count);
+        if length == 0 {
+            distances.resize(day_count, 0.0);
+            return distances;
+        }
+
+        // row[j * EDIT_CHUNK + d] is the distance between the first j blocks of the query and the
+        // blocks of day d of the chunk seen so far
+        let mut row = vec![f64::INFINITY; (length + 1) * EDIT_CHUNK];
+        let mut next = row.clone();
+
+        for chunk_start in (0..day_count).step_by(EDIT_CHUNK) {
+            let chunk = EDIT_CHUNK.min(day_count - chunk_start);
+
+            row.fill(f64::INFINITY);
+            let mut inserted = 0.0;
+            for j in 0..=length.min(max_shift) {
+                row[j * EDIT_CHUNK..j * EDIT_CHUNK + chunk].fill(inserted);
+                if j < length {
+                    inserted += indel * block_weights[j];
+                }
+            }
+
+            for block in 0..length {
+                let codes = &self.column(block)[chunk_start..chunk_start + chunk];
+                // deleting a block of the historical day costs as much as a difference at its time
+                let deletion = indel * block_weights[block];
+
+                let first = (block + 1).saturating_sub(max_shift);
+                let last = (block + 1 + max_shift).min(length);
+
+                // the cells either side of the band are read before being written, so they must
+                // not hold distances left from earlier blocks
+                if first > 0 {
+                    next[(first - 1) * EDIT_CHUNK..first * EDIT_CHUNK].fill(f64::INFINITY);
+                }
+                if last < length {
+                    next[(last + 1) * EDIT_CHUNK..(last + 2) * EDIT_CHUNK].fill(f64::INFINITY);
+                }
+
+                for j in first..=last {
+                    let (before, current) = next.split_at_mut(j * EDIT_CHUNK);
+                    let current = &mut current[..chunk];
+
+                    if j == 0 {
+                        for (cell, &above) in current.iter_mut().zip(&row[..chunk]) {
+                            *cell = above + deletion;
+                        }
+                        continue;
+                    }
+
+                    let weight = block_weights[j - 1];
+                    let target = query[j - 1];
+                    let replace = if target == missing { 0.0 } else { substitution * weight };
+                    let insertion = indel * weight;
+
+                    let diagonal = &row[(j - 1) * EDIT_CHUNK..(j - 1) * EDIT_CHUNK + chunk];
+                    let above = &row[j * EDIT_CHUNK..j * EDIT_CHUNK + chunk];
+                    let left = &before[(j - 1) * EDIT_CHUNK..(j - 1) * EDIT_CHUNK + chunk];
+
+                    // written without branches so the loop can be vectorized
+                    let previous = diagonal.iter().zip(above).zip(left).zip(codes);
+                    for (cell, (((&diagonal, &above), &left), &code)) in current.iter_mut().zip(previous) {
+                        let differs = (code != target) & (code != missing);
+                        *cell = smaller(smaller(diagonal + replace * differs as u8 as f64, above + deletion), left + insertion);
+                    }
+                }
+
+                std::mem::swap(&mut row, &mut next);
+            }
+
+            distances.extend_from_slice(&row[length * EDIT_CHUNK..length * EDIT_CHUNK + chunk]);
+        }
+
+        distances
+    }
+
+    /// picks the k closest days given the distance to every day
+    fn choose<R: Rng + ?Sized>(&self, distances: &[f64], k: usize, rng: &mut R) -> Vec<Neighbour> {
+        let mut neighbours: Vec<Neighbour> = if k >= distances.len() {
+            distances.iter().enumerate().map(|(day, &distance)| Neighbour { day, distance }).collect()
+        } else {
+            // the distance of the k-th closest day
+            let mut sorted = distances.to_vec();
+            let (_, &mut bound, _) = sorted.select_nth_unstable_by(k - 1, f64::total_cmp);
+
+            let mut neighbours = Vec::with_capacity(k);
+            let mut tied = Vec::new();
+            for (day, &distance) in distances.iter().enumerate() {
+                if distance < bound {
+                    neighbours.push(Neighbour { day, distance });
+                } else if distance == bound {
+                    tied.push(day);
+                }
+            }
+
+            let remaining = k - neighbours.len();
+            neighbours.extend(self.sample(&tied, remaining, rng).into_iter().map(|day| Neighbour { day, distance: bound }));
+            neighbours
+        };
+
+        neighbours.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        neighbours
+    }
+
+    /// picks count of the given days at random in proportion to their weights
+    fn sample<R: Rng + ?Sized>(&self, days: &[usize], count: usize, rng: &mut R) -> Vec<usize> {
+        if count * 4 > days.len() {
+            // give every day a random key and keep the largest
+            let mut keyed: Vec<(f64, usize)> = days.iter()
+                .map(|&day| (rng.random::<f64>().ln() / self.weights[day], day))
+                .collect();
+            keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+            return keyed.into_iter().take(count).map(|(_, day)| day).collect();
+        }
+
+        // few days are wanted, so pick positions along the cumulative weights, retrying days
+        // already picked
+        let cumulative: Vec<f64> = days.iter()
+            .scan(0.0, |total, &day| {
+                *total += self.weights[day];
+                Some(*total)
+            })
+            .collect();
+        let total_weight = cumulative[cumulative.len() - 1];
+
+        let mut chosen = Vec::with_capacity(count);
+        while chosen.len() < count {
+            let position = rng.random::<f64>() * total_weight;
+            let day = days[cumulative.partition_point(|&total| total <= position).min(days.len() - 1)];
+            if !chosen.contains(&day) {
+                chosen.push(day);
+            }
+        }
+        chosen
+    }
+}
+
+/// forecasts days with the rest of the historical days whose beginnings are closest to what has
+/// been done so far, with the closest days forecast with the most certainty
+pub struct AnalogForecaster<R: Rng, const BLOCK_DURATION: u32> {
+    index: AnalogIndex,
+
+    options: AnalogOptions,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+
+    /// the rng used to choose between equally close days
+    rng_cell: RefCell<R>,
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> AnalogForecaster<R, BLOCK_DURATION> {
+    /// creates a new AnalogForecaster from an index of historical days, consuming the rng used to
+    /// choose between equally close days
+    pub fn new(index: AnalogIndex, rng: R) -> Self {
+        assert!(
+            index.blocks_per_day() == Forecast::<BLOCK_DURATION>::block_count(),
+            "a forecaster with block_duration {} requires an index with {} blocks per day",
+            BLOCK_DURATION, Forecast::<BLOCK_DURATION>::block_count()
+        );
+
+        Self {
+            index,
+            options: AnalogOptions::default(),
+            day_start: 0,
+            rng_cell: RefCell::new(rng),
+        }
+    }
+
+    /// sets how close historical days are found
+    pub fn with_options(mut self, options: AnalogOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// creates a new AnalogForecaster indexing the days of the activity block file at the given path
+    pub fn from_block_file(path: &str, options: &TrainingOptions, rng: R) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options, rng)
+    }
+
+    /// creates a new AnalogForecaster indexing the days of a loaded activity block file selected by
+    /// the training options
+    /// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions, rng: R) -> Result<Self> {
+        markov_chain::check_block_count::<BLOCK_DURATION>(file.blocks_per_day(), path)?;
+
+        let index = AnalogIndex::train(file, path, options)?;
+        Ok(Self::new(index, rng).with_day_start(file.header().day_start))
+    }
+
+    /// the index of historical days forecasts are copied from
+    pub fn index(&self) -> &AnalogIndex {
+        &self.index
+    }
+
+    /// gets the rng from the forecaster, destroying it
+    pub fn rng(self) -> R {
+        self.rng_cell.into_inner()
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for AnalogForecaster<R, BLOCK_DURATION> {
+    /// forecasts the rest of the forecast_count closest days, with a certainty proportional to
+    /// 1 / (1 + distance)
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        let neighbours = self.index.nearest(&initial_conditions, forecast_count, &self.options, &mut *self.rng_cell.borrow_mut());
+
+        let similarities: Vec<f64> = neighbours.iter().map(|neighbour| 1.0 / (1.0 + neighbour.distance)).collect();
+        let total_similarity: f64 = similarities.iter().sum();
+
+        neighbours.iter()
+            .zip(similarities)
+            .map(|(neighbour, similarity)| {
+                let forecast_data = self.index.activities(neighbour.day, initial_conditions.len()).collect();
+
+                Box::new(Forecast::new(
+                    initial_conditions.clone(),
+                    forecast_data,
+                    (similarity / total_similarity).clamp(0.0, 1.0),
+                ))
+            })
+            .collect()
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+}
+
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for AnalogForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for AnalogForecaster<R, BLOCK_DURATION> {
+    /// the proportion of the closest days performing each activity in every later block, with each
+    /// day counted in proportion to 1 / (1 + distance)
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        let neighbours = self.index.nearest(&initial_conditions, self.options.neighbours, &self.options, &mut *self.rng_cell.borrow_mut());
+        let total_similarity: f64 = neighbours.iter().map(|neighbour| 1.0 / (1.0 + neighbour.distance)).sum();
+
+        let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_conditions.len();
+        let mut distributions = vec![[0.0; ActivityCategory::COUNT]; additional_block_count];
+        for neighbour in &neighbours {
+            let share = 1.0 / (1.0 + neighbour.distance) / total_similarity;
+            let continuation = self.index.activities(neighbour.day, initial_conditions.len());
+            for (distribution, activity) in distributions.iter_mut().zip(continuation) {
+                distribution[activity.into_code() as usize] += share;
+            }
+        }
+
+        DistributionForecast::new(initial_conditions, distributions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity_block::test_block_file;
+    use rand::rngs::StdRng;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn index(days: &[[u8; 6]]) -> AnalogIndex {
+        AnalogIndex::train(&test_block_file(days), "test", &TrainingOptions::default()).unwrap()
+    }
+
+    fn activities(codes: &[u8]) -> Vec<ActivityCategory> {
+        codes.iter().map(|&code| ActivityCategory::from_code(code).unwrap()).collect()
+    }
+
+    /// the banded weighted edit distance between query and the beginning of day, filling the whole
+    /// table rather than chunks of days
+    fn naive_edit_distance(day: &[u8], query: &[u8], block_weights: &[f64], substitution: f64, indel: f64, max_shift: usize) -> f64 {
+        let missing = ActivityCategory::MissingData.into_code();
+        let length = query.len();
+
+        // table[i][j] is the distance between the first i blocks of the day and the first j of the query
+        let mut table = vec![vec![f64::INFINITY; length + 1]; length + 1];
+        for i in 0..=length {
+            for j in 0..=length {
+                if i.abs_diff(j) > max_shift {
+                    continue;
+                }
+
+                table[i][j] = if i == 0 && j == 0 {
+                    0.0
+                } else {
+                    let mut best = f64::INFINITY;
+                    if i > 0 && j > 0 {
+                        let differs = day[i - 1] != query[j - 1] && day[i - 1] != missing && query[j - 1] != missing;
+                        let replace = if differs { substitution * block_weights[j - 1] } else { 0.0 };
+                        best = best.min(table[i - 1][j - 1] + replace);
+                    }
+                    if i > 0 {
+                        best = best.min(table[i - 1][j] + indel * block_weights[i - 1]);
+                    }
+                    if j > 0 {
+                        best = best.min(table[i][j - 1] + indel * block_weights[j - 1]);
+                    }
+                    best
+                };
+            }
+        }
+        table[length][length]
+    }
+
+    #[test]
+    fn edit_distances_match_a_naive_dynamic_program_across_chunks() {
+        // enough days that the last chunk is only partly filled
+        let mut rng = StdRng::seed_from_u64(2);
+        let days: Vec<[u8; 6]> = (0..EDIT_CHUNK + 45)
+            .map(|_| std::array::from_fn(|_| if rng.random_bool(0.1) { 20 } else { rng.random_range(0..4) }))
+            .collect();
+        let index = index(&days);
+
+        for length in 1..6usize {
+            let query: Vec<u8> = (0..length).map(|block| if block == 1 { 20 } else { rng.random_range(0..4) }).collect();
+            let block_weights: Vec<f64> = (0..length).map(|i| 0.8f64.powi((length - 1 - i) as i32)).collect();
+
+            for max_shift in [0, 1, 2, 5] {
+                let distances = index.edit_distances(&query, &block_weights, 1.0, 0.7, max_shift);
+                assert_eq!(distances.len(), days.len());
+
+                for (day, (codes, &distance)) in days.iter().zip(&distances).enumerate() {
+                    let expected = naive_edit_distance(codes, &query, &block_weights, 1.0, 0.7, max_shift);
+                    assert!(
+                        (distance - expected).abs() < TOLERANCE,
+                        "day {day}, query {query:?}, max_shift {max_shift}: {distance}, expected {expected}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hamming_distances_count_recent_blocks_more_with_decay() {
+        let index = index(&[
+            [1, 2, 3, 0, 0, 0],
+            [9, 2, 3, 0, 0, 0],
+            [1, 9, 3, 0, 0, 0],
+            [1, 2, 9, 0, 0, 0],
+            [9, 9, 20, 0, 0, 0],
+        ]);
+        let options = AnalogOptions::default().with_decay(0.5);
+
+        let mut neighbours = index.nearest(&activities(&[1, 2, 3]), 5, &options, &mut StdRng::seed_from_u64(0));
+        neighbours.sort_by_key(|neighbour| neighbour.day);
+        let distances: Vec<f64> = neighbours.iter().map(|neighbour| neighbour.distance).collect();
+        // the block the last day is missing data for is not compared
+        assert_eq!(distances, [0.0, 0.25, 0.5, 1.0, 0.75]);
+
+        // blocks the beginning is missing data for are not compared either
+        let distances = index.hamming_distances(&[20, 2, 3], &[0.25, 0.5, 1.0]);
+        assert_eq!(distances, [0.0, 0.0, 0.5, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn choose_keeps_closer_days_and_samples_distinct_tied_days() {
+        let index = index(&[[0; 6]; 10]);
+        // day 0 is closest, days 1 to 8 are tied and day 9 is furthest
+        let distances = [0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 2.0];
+
+        // few and many tied days are wanted, so both ways of sampling are used
+        for k in [3, 6] {
+            let mut seen = [false; 10];
+            for seed in 0..50 {
+                let neighbours = index.choose(&distances, k, &mut StdRng::seed_from_u64(seed));
+                assert_eq!(neighbours.len(), k);
+                assert_eq!(neighbours[0], Neighbour { day: 0, distance: 0.0 });
+
+                let mut days: Vec<usize> = neighbours.iter().map(|neighbour| neighbour.day).collect();
+                days.sort();
+                days.dedup();
+                assert_eq!(days.len(), k, "tied days must not be chosen twice");
+
+                for neighbour in &neighbours[1..] {
+                    assert!((1..9).contains(&neighbour.day));
+                    assert_eq!(neighbour.distance, 1.0);
+                    seen[neighbour.day] = true;
+                }
+            }
+            assert!(seen[1..9].iter().all(|&seen| seen), "every tied day should sometimes be chosen");
+        }
+
+        // asking for every day returns them all from closest to furthest
+        let neighbours = index.choose(&distances, 20, &mut StdRng::seed_from_u64(0));
+        assert_eq!(neighbours.len(), 10);
+        assert_eq!(neighbours[9], Neighbour { day: 9, distance: 2.0 });
+    }
+}
@@ -1,29 +1,191 @@
-use egui::debug_text::print;
+use std::rc::Rc;
 
-use crate::{day_forecaster::DayForecaster, encodings::ActivityCategory};
+use crate::{day_forecaster::{DayForecaster, DistributionForecast, DistributionForecaster, Forecast, Reseed}, encodings::ActivityCategory};
 
-pub struct ActivityForecastApp<F: DayForecaster<BLOCK_DURATION>, const BLOCK_DURATION: u32> {
+/// the number of forecasts to request from the forecaster
+const FORECAST_COUNT: usize = 5;
+
+/// the height of the probability timeline in points
+const PROBABILITY_TIMELINE_HEIGHT: f32 = 240.0;
+
+/// how the forecast for the rest of the day is displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ForecastView {
+    /// a handful of individual forecast days
+    Days,
+
+    /// the probability of each activity in every remaining block
+    Probabilities,
+}
+
+pub struct ActivityForecastApp<F, const BLOCK_DURATION: u32>
+where F: DayForecaster<BLOCK_DURATION> + DistributionForecaster<BLOCK_DURATION> + Reseed {
     forecaster: Box<F>,
     filled_activities: Vec<ActivityCategory>,
+    #[allow(clippy::vec_box)]
+    forecasts: Vec<Box<Forecast<BLOCK_DURATION>>>,
+    distribution: Option<DistributionForecast<BLOCK_DURATION>>,
+    view: ForecastView,
+
+    /// the seed the forecaster is reset to before every forecast, so forecasts can be replayed
+    seed: u64,
+
+    /// the contents of the seed text box
+    seed_text: String,
 }
 
 impl<F, const BLOCK_DURATION: u32> ActivityForecastApp<F, BLOCK_DURATION>
-where F: DayForecaster<BLOCK_DURATION> {
-    pub fn new(_cc: &eframe::CreationContext<'_>, forecaster: Box<F>) -> Self {
+where F: DayForecaster<BLOCK_DURATION> + DistributionForecaster<BLOCK_DURATION> + Reseed {
+    pub fn new(_cc: &eframe::CreationContext<'_>, forecaster: Box<F>, seed: u64) -> Self {
         // Customize egui here with cc.egui_ctx.set_fonts and cc.egui_ctx.set_visuals.
         // Restore app state using cc.storage (requires the "persistence" feature).
         // Use the cc.gl (a glow::Context) to create graphics shaders and buffers that you can use
         // for e.g. egui::PaintCallback.
 
         Self {
-            forecaster: forecaster,
+            forecaster,
             filled_activities: Vec::new(),
+            forecasts: Vec::new(),
+            distribution: None,
+            view: ForecastView::Days,
+            seed,
+            seed_text: seed.to_string(),
         }
     }
+
+    /// regenerates the forecasts based on the currently filled activities
+    fn refresh_forecasts(&mut self) {
+        if self.filled_activities.is_empty()
+            || self.filled_activities.len() >= Forecast::<BLOCK_DURATION>::block_count() {
+            self.forecasts.clear();
+            self.distribution = None;
+            return;
+        }
+
+        let initial_conditions = Rc::new(self.filled_activities.clone());
+
+        self.forecaster.reseed(self.seed);
+        self.forecasts = self.forecaster.forecast(initial_conditions.clone(), FORECAST_COUNT);
+        self.distribution = Some(self.forecaster.forecast_distribution(initial_conditions));
+    }
+}
+
+/// formats the start time of a block as "hours:minutes"
+fn format_block_time<const BLOCK_DURATION: u32>(block_index: usize, day_start: u32) -> String {
+    let minutes = Forecast::<BLOCK_DURATION>::block_start_time(block_index, day_start);
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// lists each run of consecutive identical activities alongside the time it begins
+fn activity_timeline<const BLOCK_DURATION: u32>(
+    ui: &mut egui::Ui,
+    first_block: usize,
+    day_start: u32,
+    activities: &[ActivityCategory]
+) {
+    let mut previous: Option<ActivityCategory> = None;
+    for (offset, &activity) in activities.iter().enumerate() {
+        if previous != Some(activity) {
+            ui.label(format!(
+                "{} {}",
+                format_block_time::<BLOCK_DURATION>(first_block + offset, day_start),
+                activity.into_str()
+            ));
+            previous = Some(activity);
+        }
+    }
+}
+
+/// the color used to draw an activity
+fn activity_color(activity: ActivityCategory) -> egui::Color32 {
+    if activity == ActivityCategory::MissingData {
+        return egui::Color32::GRAY;
+    }
+
+    let hue = activity.into_code() as f32 / ActivityCategory::VALID_COUNT as f32;
+    let value = if activity.into_code().is_multiple_of(2) { 0.85 } else { 0.65 };
+    egui::ecolor::Hsva::new(hue, 0.65, value, 1.0).into()
+}
+
+/// draws the probability of each activity in every forecast block as stacked columns
+fn probability_timeline<const BLOCK_DURATION: u32>(
+    ui: &mut egui::Ui,
+    day_start: u32,
+    distribution: &DistributionForecast<BLOCK_DURATION>,
+) {
+    let distributions = distribution.distributions();
+    if distributions.is_empty() {
+        return;
+    }
+    let first_block = distribution.initial_conditions().len();
+
+    let (response, painter) = ui.allocate_painter(
+        egui::vec2(ui.available_width(), PROBABILITY_TIMELINE_HEIGHT),
+        egui::Sense::hover()
+    );
+    let rect = response.rect;
+    let column_width = rect.width() / distributions.len() as f32;
+
+    for (i, probabilities) in distributions.iter().enumerate() {
+        let left = rect.left() + i as f32 * column_width;
+        let mut bottom = rect.bottom();
+
+        for activity in ActivityCategory::iter() {
+            let height = probabilities[activity.into_code() as usize] as f32 * rect.height();
+            if height <= 0.0 {
+                continue;
+            }
+
+            painter.rect_filled(
+                egui::Rect::from_min_max(
+                    egui::pos2(left, bottom - height),
+                    egui::pos2(left + column_width, bottom)
+                ),
+                0.0,
+                activity_color(activity)
+            );
+            bottom -= height;
+        }
+    }
+
+    // show the most likely activities of the hovered block
+    if let Some(position) = response.hover_pos() {
+        let i = (((position.x - rect.left()) / column_width) as usize).min(distributions.len() - 1);
+        response.on_hover_ui_at_pointer(|ui| {
+            ui.label(format_block_time::<BLOCK_DURATION>(first_block + i, day_start));
+
+            let mut activities: Vec<ActivityCategory> = ActivityCategory::iter().collect();
+            activities.sort_by(|a, b| distribution.probability(i, *b).total_cmp(&distribution.probability(i, *a)));
+            for activity in activities.into_iter().take(3) {
+                ui.label(format!("{} {:.1}%", activity.into_str(), distribution.probability(i, activity) * 100.0));
+            }
+        });
+    }
+
+    ui.horizontal(|ui| {
+        ui.label(format_block_time::<BLOCK_DURATION>(first_block, day_start));
+        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+            ui.label(format_block_time::<BLOCK_DURATION>(first_block + distributions.len(), day_start));
+        });
+    });
+
+    // legend of every activity that appears in the forecast
+    ui.horizontal_wrapped(|ui| {
+        for activity in ActivityCategory::iter() {
+            let code = activity.into_code() as usize;
+            if distributions.iter().all(|p| p[code] <= 0.0) {
+                continue;
+            }
+
+            let (swatch, _) = ui.allocate_exact_size(egui::vec2(12.0, 12.0), egui::Sense::hover());
+            ui.painter().rect_filled(swatch, 2.0, activity_color(activity));
+            ui.label(activity.into_str());
+        }
+    });
 }
 
 impl<F, const BLOCK_DURATION: u32> eframe::App for ActivityForecastApp<F, BLOCK_DURATION>
-where F: DayForecaster<BLOCK_DURATION> {
+where F: DayForecaster<BLOCK_DURATION> + DistributionForecaster<BLOCK_DURATION> + Reseed {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // the activity to add at the end of the list
         let mut added_activity: Option<ActivityCategory> = None;
@@ -31,6 +193,29 @@ where F: DayForecaster<BLOCK_DURATION> {
         // whether to delete the last added activity
         let mut pop_activity = false;
 
+        // the seed to replace the current seed with
+        let mut new_seed: Option<u64> = None;
+
+        egui::TopBottomPanel::top("seed_selector").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("seed:");
+                let response = ui.text_edit_singleline(&mut self.seed_text);
+                if response.lost_focus() {
+                    match self.seed_text.trim().parse::<u64>() {
+                        Ok(seed) => new_seed = Some(seed),
+                        Err(_) => self.seed_text = self.seed.to_string(),
+                    }
+                }
+                if ui.button("random seed").clicked() {
+                    new_seed = Some(rand::random());
+                }
+
+                ui.separator();
+                ui.radio_value(&mut self.view, ForecastView::Days, "forecast days");
+                ui.radio_value(&mut self.view, ForecastView::Probabilities, "activity probabilities");
+            });
+        });
+
         egui::TopBottomPanel::bottom("activity_selector").show(ctx, |ui| {
             ui.label("add an activity:");
             ui.with_layout(
@@ -56,16 +241,63 @@ where F: DayForecaster<BLOCK_DURATION> {
             })
         });
 
+        let day_start = self.forecaster.day_start();
+
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.heading("hi lol");
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.heading("your day");
+                ui.label(format!(
+                    "next block begins at {}",
+                    format_block_time::<BLOCK_DURATION>(self.filled_activities.len(), day_start)
+                ));
+                activity_timeline::<BLOCK_DURATION>(ui, 0, day_start, &self.filled_activities);
+
+                ui.separator();
+                ui.heading("forecasts");
+                match self.view {
+                    ForecastView::Days => {
+                        for (i, forecast) in self.forecasts.iter().enumerate() {
+                            egui::CollapsingHeader::new(format!(
+                                "forecast {} ({:.1}% certainty)",
+                                i + 1,
+                                forecast.certainty() * 100.0
+                            ))
+                                .id_salt(i)
+                                .show(ui, |ui| {
+                                    activity_timeline::<BLOCK_DURATION>(
+                                        ui,
+                                        forecast.initial_conditions().len(),
+                                        day_start,
+                                        forecast.forecast_data()
+                                    );
+                                });
+                        }
+                    }
+                    ForecastView::Probabilities => {
+                        if let Some(distribution) = &self.distribution {
+                            probability_timeline::<BLOCK_DURATION>(ui, day_start, distribution);
+                        }
+                    }
+                }
+            });
         });
 
-        if let Some(activity) = added_activity {
+        if let Some(activity) = added_activity
+            && self.filled_activities.len() < Forecast::<BLOCK_DURATION>::block_count() {
             self.filled_activities.push(activity);
+            self.refresh_forecasts();
         }
 
         if pop_activity {
-        	self.filled_activities.pop();
+            self.filled_activities.pop();
+            self.refresh_forecasts();
+        }
+
+        if let Some(seed) = new_seed
+            && seed != self.seed {
+            self.seed = seed;
+            self.seed_text = seed.to_string();
+            self.refresh_forecasts();
         }
     }
 }
@@ -0,0 +1,614 @@
+//! Simple DayForecasters serving as reference points new models can be compared against:
+//! repeating the current activity, forecasting the most common activity of each block, sampling
+//! each block from its time of day ignoring history, and copying the most similar historical day.
+
+use std::{cell::RefCell, rc::Rc};
+
+use rand::{Rng, SeedableRng};
+
+use crate::{activity_block::ActivityBlockFile, analog::{AnalogIndex, AnalogOptions, Neighbour}, day_forecaster::{DayForecaster, DistributionForecast, DistributionForecaster, Forecast, Reseed}, encodings::ActivityCategory, error::Result, higher_order, markov_chain::{self, MissingDataPolicy, TrainingOptions}};
+
+/// a distribution placing all of its probability on a single activity
+fn certain(activity: ActivityCategory) -> [f64; ActivityCategory::COUNT] {
+    let mut distribution = [0.0; ActivityCategory::COUNT];
+    distribution[activity.into_code() as usize] = 1.0;
+    distribution
+}
+
+/// the probability of each activity, indexed by code, in every block of the days of a file
+/// missing data is only counted under MissingDataPolicy::OwnState, and blocks with nothing counted
+/// give every activity other than missing data the same probability
+/// path is only used for error reporting
+fn block_marginals(file: &ActivityBlockFile, path: &str, options: &TrainingOptions) -> Result<Vec<[f64; ActivityCategory::COUNT]>> {
+    let mut counts = vec![[0.0; ActivityCategory::COUNT]; file.blocks_per_day()];
+    markov_chain::for_each_training_day(file, path, options, |activities, weight| {
+        for (block_counts, &activity) in counts.iter_mut().zip(activities) {
+            if activity != ActivityCategory::MissingData || options.missing_data == MissingDataPolicy::OwnState {
+                block_counts[activity.into_code() as usize] += weight;
+            }
+        }
+    })?;
+
+    Ok(counts.into_iter()
+        .map(|block_counts| {
+            let total: f64 = block_counts.iter().sum();
+            if total > 0.0 {
+                block_counts.map(|count| count / total)
+            } else {
+                let mut uniform = [0.0; ActivityCategory::COUNT];
+                uniform[..ActivityCategory::VALID_COUNT].fill(1.0 / ActivityCategory::VALID_COUNT as f64);
+                uniform
+            }
+        })
+        .collect())
+}
+
+/// forecasts that whatever was being done in the last initial block carries on for the rest of the day
+#[derive(Default)]
+pub struct PersistenceForecaster<const BLOCK_DURATION: u32> {
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+}
+
+impl<const BLOCK_DURATION: u32> PersistenceForecaster<BLOCK_DURATION> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// the activity repeated after initial_conditions, which is the last one recorded
+    fn repeated(initial_conditions: &[ActivityCategory]) -> ActivityCategory {
+        initial_conditions.iter()
+            .rev()
+            .find(|&&activity| activity != ActivityCategory::MissingData)
+            .copied()
+            .unwrap_or(ActivityCategory::MissingData)
+    }
+}
+
+impl<const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for PersistenceForecaster<BLOCK_DURATION> {
+    /// forecasts a single day, as every forecast would be the same
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        if forecast_count == 0 {
+            return Vec::new();
+        }
+
+        let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_conditions.len();
+        let forecast_data = vec![Self::repeated(&initial_conditions); additional_block_count];
+        vec![Box::new(Forecast::new(initial_conditions, forecast_data, 1.0))]
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+}
+
+impl<const BLOCK_DURATION: u32> Reseed for PersistenceForecaster<BLOCK_DURATION> {
+    /// does nothing, as forecasts are made without randomness
+    fn reseed(&self, _seed: u64) {}
+}
+
+impl<const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for PersistenceForecaster<BLOCK_DURATION> {
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_conditions.len();
+        let distribution = certain(Self::repeated(&initial_conditions));
+        DistributionForecast::new(initial_conditions, vec![distribution; additional_block_count])
+    }
+}
+
+/// forecasts the activity most commonly performed in each block of the day, ignoring what has been
+/// done so far
+pub struct ModeForecaster<const BLOCK_DURATION: u32> {
+    /// the most common activity of each block of the day
+    modes: Vec<ActivityCategory>,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+}
+
+impl<const BLOCK_DURATION: u32> ModeForecaster<BLOCK_DURATION> {
+    /// creates a new ModeForecaster from the most common activity of each block of the day
+    pub fn new(modes: Vec<ActivityCategory>) -> Self {
+        assert!(
+            modes.len() == Forecast::<BLOCK_DURATION>::block_count(),
+            "a forecaster with block_duration {} requires {} modes",
+            BLOCK_DURATION, Forecast::<BLOCK_DURATION>::block_count()
+        );
+
+        Self {
+            modes,
+            day_start: 0,
+        }
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// creates a new ModeForecaster trained on the activity block file at the given path
+    pub fn from_block_file(path: &str, options: &TrainingOptions) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options)
+    }
+
+    /// creates a new ModeForecaster trained on a loaded activity block file
+    /// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions) -> Result<Self> {
+        markov_chain::check_block_count::<BLOCK_DURATION>(file.blocks_per_day(), path)?;
+
+        let modes = block_marginals(file, path, options)?
+            .iter()
+            .map(|distribution| {
+                // the first of the most likely activities, so ties are broken the same way every time
+                let code = distribution.iter()
+                    .enumerate()
+                    .fold(0, |best, (code, &probability)| if probability > distribution[best] { code } else { best });
+                ActivityCategory::from_code(code as u8).unwrap()
+            })
+            .collect();
+
+        Ok(Self::new(modes).with_day_start(file.header().day_start))
+    }
+
+    /// the most common activity of each block of the day
+    pub fn modes(&self) -> &[ActivityCategory] {
+        &self.modes
+    }
+}
+
+impl<const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for ModeForecaster<BLOCK_DURATION> {
+    /// forecasts a single day, as every forecast would be the same
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        if forecast_count == 0 {
+            return Vec::new();
+        }
+
+        let forecast_data = self.modes[initial_conditions.len()..].to_vec();
+        vec![Box::new(Forecast::new(initial_conditions, forecast_data, 1.0))]
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+}
+
+impl<const BLOCK_DURATION: u32> Reseed for ModeForecaster<BLOCK_DURATION> {
+    /// does nothing, as forecasts are made without randomness
+    fn reseed(&self, _seed: u64) {}
+}
+
+impl<const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for ModeForecaster<BLOCK_DURATION> {
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        let distributions = self.modes[initial_conditions.len()..].iter().map(|&mode| certain(mode)).collect();
+        DistributionForecast::new(initial_conditions, distributions)
+    }
+}
+
+/// forecasts days by sampling every block independently from how often each activity is performed
+/// at that time of day, ignoring what has been done so far
+pub struct MarginalForecaster<R: Rng, const BLOCK_DURATION: u32> {
+    /// the probability of each activity, indexed by code, in each block of the day
+    marginals: Vec<[f64; ActivityCategory::COUNT]>,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+
+    /// the rng used to sample activities
+    rng_cell: RefCell<R>,
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> MarginalForecaster<R, BLOCK_DURATION> {
+    /// creates a new MarginalForecaster from the probability of each activity in each block of the
+    /// day, consuming the rng used to sample activities
+    pub fn new(marginals: Vec<[f64; ActivityCategory::COUNT]>, rng: R) -> Self {
+        assert!(
+            marginals.len() == Forecast::<BLOCK_DURATION>::block_count(),
+            "a forecaster with block_duration {} requires {} distributions",
+            BLOCK_DURATION, Forecast::<BLOCK_DURATION>::block_count()
+        );
+
+        Self {
+            marginals,
+            day_start: 0,
+            rng_cell: RefCell::new(rng),
+        }
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// creates a new MarginalForecaster trained on the activity block file at the given path
+    pub fn from_block_file(path: &str, options: &TrainingOptions, rng: R) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options, rng)
+    }
+
+    /// creates a new MarginalForecaster trained on a loaded activity block file
+    /// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions, rng: R) -> Result<Self> {
+        markov_chain::check_block_count::<BLOCK_DURATION>(file.blocks_per_day(), path)?;
+        Ok(Self::new(block_marginals(file, path, options)?, rng).with_day_start(file.header().day_start))
+    }
+
+    /// the probability of each activity, indexed by code, in each block of the day
+    pub fn marginals(&self) -> &[[f64; ActivityCategory::COUNT]] {
+        &self.marginals
+    }
+
+    /// gets the rng from the forecaster, destroying it
+    pub fn rng(self) -> R {
+        self.rng_cell.into_inner()
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for MarginalForecaster<R, BLOCK_DURATION> {
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        let mut rng = self.rng_cell.borrow_mut();
+        let remaining = &self.marginals[initial_conditions.len()..];
+
+        // the generated paths, alongside the probability of each path occurring
+        let mut paths = Vec::with_capacity(forecast_count);
+        let mut total_probability = 0.0;
+
+        for _ in 0..forecast_count {
+            let mut probability = 1.0;
+            let forecast_data: Vec<ActivityCategory> = remaining.iter()
+                .map(|distribution| {
+                    let code = higher_order::sample_distribution(distribution, &mut *rng);
+                    probability *= distribution[code as usize];
+                    ActivityCategory::from_code(code).unwrap()
+                })
+                .collect();
+
+            total_probability += probability;
+            paths.push((forecast_data, probability));
+        }
+
+        paths.into_iter()
+            .map(|(forecast_data, probability)| {
+                // normalize so that the certainties of all forecasts sum to 1
+                let certainty = if total_probability > 0.0 {
+                    probability / total_probability
+                } else {
+                    1.0 / forecast_count as f64
+                };
+
+                Box::new(Forecast::new(
+                    initial_conditions.clone(),
+                    forecast_data,
+                    certainty.clamp(0.0, 1.0),
+                ))
+            })
+            .collect()
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+
+    fn log_likelihood(
+        &self,
+        initial_conditions: &[ActivityCategory],
+        continuation: &[ActivityCategory],
+    ) -> Option<f64> {
+        Some(self.marginals[initial_conditions.len()..].iter()
+            .zip(continuation)
+            .map(|(distribution, &activity)| distribution[activity.into_code() as usize].ln())
+            .sum())
+    }
+}
+
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for MarginalForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for MarginalForecaster<R, BLOCK_DURATION> {
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        let distributions = self.marginals[initial_conditions.len()..].to_vec();
+        DistributionForecast::new(initial_conditions, distributions)
+    }
+}
+
+/// forecasts days by copying the rest of the historical days whose beginnings most closely match
+/// what has been done so far, choosing between equally close days at random
+pub struct PrefixCopyForecaster<R: Rng, const BLOCK_DURATION: u32> {
+    /// the historical days that can be copied
+    index: AnalogIndex,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+
+    /// the rng used to choose between equally close days
+    rng_cell: RefCell<R>,
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> PrefixCopyForecaster<R, BLOCK_DURATION> {
+    /// creates a new PrefixCopyForecaster copying the days of an index, consuming the rng used to
+    /// choose between equally close days
+    pub fn new(index: AnalogIndex, rng: R) -> Self {
+        assert!(
+            index.blocks_per_day() == Forecast::<BLOCK_DURATION>::block_count(),
+            "a forecaster with block_duration {} requires an index with {} blocks per day",
+            BLOCK_DURATION, Forecast::<BLOCK_DURATION>::block_count()
+        );
+
+        Self {
+            index,
+            day_start: 0,
+            rng_cell: RefCell::new(rng),
+        }
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// creates a new PrefixCopyForecaster copying the days of the activity block file at the given path
+    pub fn from_block_file(path: &str, options: &TrainingOptions, rng: R) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options, rng)
+    }
+
+    /// creates a new PrefixCopyForecaster copying the days of a loaded activity block file selected
+    /// by the training options
+    /// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions, rng: R) -> Result<Self> {
+        markov_chain::check_block_count::<BLOCK_DURATION>(file.blocks_per_day(), path)?;
+
+        let index = AnalogIndex::train(file, path, options)?;
+        Ok(Self::new(index, rng).with_day_start(file.header().day_start))
+    }
+
+    /// the historical days that can be copied
+    pub fn index(&self) -> &AnalogIndex {
+        &self.index
+    }
+
+    /// gets the rng from the forecaster, destroying it
+    pub fn rng(self) -> R {
+        self.rng_cell.into_inner()
+    }
+
+    /// up to k distinct historical days whose beginnings differ from initial_conditions in the
+    /// fewest blocks, all equally close
+    /// blocks either day is missing data for are not compared
+    fn closest_days(&self, initial_conditions: &[ActivityCategory], k: usize) -> Vec<Neighbour> {
+        let mut neighbours = self.index.nearest(initial_conditions, k, &AnalogOptions::default(), &mut *self.rng_cell.borrow_mut());
+        if let Some(closest) = neighbours.first().map(|neighbour| neighbour.distance) {
+            neighbours.retain(|neighbour| neighbour.distance == closest);
+        }
+        neighbours
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for PrefixCopyForecaster<R, BLOCK_DURATION> {
+    /// forecasts the rest of up to forecast_count distinct closest days, each equally certain
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        let closest = self.closest_days(&initial_conditions, forecast_count);
+        let certainty = 1.0 / closest.len() as f64;
+
+        closest.iter()
+            .map(|neighbour| Box::new(Forecast::new(
+                initial_conditions.clone(),
+                self.index.activities(neighbour.day, initial_conditions.len()).collect(),
+                certainty,
+            )))
+            .collect()
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+}
+
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for PrefixCopyForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for PrefixCopyForecaster<R, BLOCK_DURATION> {
+    /// the weighted proportion of the closest days performing each activity in every later block
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_conditions.len();
+        // every day is asked for, so none of the closest are left out at random
+        let closest = self.closest_days(&initial_conditions, self.index.day_count());
+        let total_weight: f64 = closest.iter().map(|neighbour| self.index.weight(neighbour.day)).sum();
+
+        let mut distributions = vec![[0.0; ActivityCategory::COUNT]; additional_block_count];
+        for neighbour in &closest {
+            let share = self.index.weight(neighbour.day) / total_weight;
+            let continuation = self.index.activities(neighbour.day, initial_conditions.len());
+            for (distribution, activity) in distributions.iter_mut().zip(continuation) {
+                distribution[activity.into_code() as usize] += share;
+            }
+        }
+
+        DistributionForecast::new(initial_conditions, distributions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity_block::test_block_file;
+    use rand::rngs::StdRng;
+
+    const TOLERANCE: f64 = 1e-12;
+
+    fn activities(codes: &[u8]) -> Rc<Vec<ActivityCategory>> {
+        Rc::new(codes.iter().map(|&code| ActivityCategory::from_code(code).unwrap()).collect())
+    }
+
+    fn codes(activities: &[ActivityCategory]) -> Vec<u8> {
+        activities.iter().map(ActivityCategory::into_code).collect()
+    }
+
+    #[test]
+    fn persistence_repeats_the_last_recorded_activity() {
+        let forecaster = PersistenceForecaster::<240>::new();
+
+        let forecasts = forecaster.forecast(activities(&[1, 2, 20]), 5);
+        assert_eq!(forecasts.len(), 1);
+        assert_eq!(codes(forecasts[0].forecast_data()), [2, 2, 2]);
+        assert_eq!(forecasts[0].certainty(), 1.0);
+
+        let distribution = forecaster.forecast_distribution(activities(&[1, 2, 20]));
+        assert_eq!(distribution.distributions(), [certain(ActivityCategory::from_code(2).unwrap()); 3]);
+
+        // with nothing recorded, the day is forecast as missing
+        let forecasts = forecaster.forecast(activities(&[20, 20]), 1);
+        assert_eq!(codes(forecasts[0].forecast_data()), [20; 4]);
+
+        assert!(forecaster.forecast(activities(&[1]), 0).is_empty());
+    }
+
+    #[test]
+    fn mode_forecasts_the_most_common_activity_of_each_block() {
+        let file = test_block_file(&[
+            [1, 2, 1, 0, 5, 4],
+            [3, 2, 1, 0, 5, 0],
+            [3, 6, 1, 20, 20, 20],
+        ]);
+        let forecaster = ModeForecaster::<240>::train(&file, "test", &TrainingOptions::default()).unwrap();
+
+        // missing data is skipped, so blocks 4 and 5 only count the first two days, and the tie in
+        // block 5 goes to the lowest code
+        assert_eq!(codes(forecaster.modes()), [3, 2, 1, 0, 5, 0]);
+
+        let forecasts = forecaster.forecast(activities(&[7, 7]), 3);
+        assert_eq!(forecasts.len(), 1);
+        assert_eq!(codes(forecasts[0].forecast_data()), [1, 0, 5, 0]);
+        assert_eq!(forecasts[0].certainty(), 1.0);
+
+        let distribution = forecaster.forecast_distribution(activities(&[7, 7, 7, 7]));
+        assert_eq!(distribution.probability(0, ActivityCategory::from_code(5).unwrap()), 1.0);
+        assert_eq!(distribution.probability(1, ActivityCategory::from_code(0).unwrap()), 1.0);
+    }
+
+    #[test]
+    fn marginal_samples_each_block_from_its_time_of_day() {
+        let file = test_block_file(&[
+            [1, 2, 3, 3, 3, 3],
+            [1, 4, 3, 3, 3, 3],
+            [1, 4, 3, 3, 3, 3],
+            [1, 4, 3, 3, 3, 20],
+        ]);
+        let forecaster = MarginalForecaster::<_, 240>::train(&file, "test", &TrainingOptions::default(), StdRng::seed_from_u64(3)).unwrap();
+
+        assert_eq!(forecaster.marginals()[1][2], 0.25);
+        assert_eq!(forecaster.marginals()[1][4], 0.75);
+        // the missing block is skipped, leaving only activity 3
+        assert_eq!(forecaster.marginals()[5][3], 1.0);
+
+        let forecasts = forecaster.forecast(activities(&[9]), 20);
+        assert_eq!(forecasts.len(), 20);
+        for forecast in &forecasts {
+            let data = codes(forecast.forecast_data());
+            assert!(data[0] == 2 || data[0] == 4);
+            assert_eq!(data[1..], [3; 4]);
+        }
+        let total: f64 = forecasts.iter().map(|forecast| forecast.certainty()).sum();
+        assert!((total - 1.0).abs() < TOLERANCE);
+
+        let likelihood = forecaster.log_likelihood(&activities(&[9]), &activities(&[4, 3, 3, 3, 3])).unwrap();
+        assert!((likelihood - 0.75f64.ln()).abs() < TOLERANCE);
+
+        // the same seed samples the same days
+        let sample = |forecaster: &MarginalForecaster<StdRng, 240>| forecaster.forecast(activities(&[9]), 20)
+            .iter()
+            .map(|forecast| codes(forecast.forecast_data()))
+            .collect::<Vec<_>>();
+        forecaster.reseed(11);
+        let first = sample(&forecaster);
+        forecaster.reseed(11);
+        assert_eq!(first, sample(&forecaster));
+    }
+
+    /// two days beginning the same way, and a third that differs in its second block
+    fn prefix_copy_forecaster() -> PrefixCopyForecaster<StdRng, 240> {
+        let file = test_block_file(&[
+            [1, 1, 2, 2, 3, 3],
+            [1, 1, 4, 4, 5, 5],
+            [1, 2, 6, 6, 6, 6],
+        ]);
+        PrefixCopyForecaster::train(&file, "test", &TrainingOptions::default(), StdRng::seed_from_u64(5)).unwrap()
+    }
+
+    #[test]
+    fn prefix_copy_forecasts_each_equally_close_day_once() {
+        let forecaster = prefix_copy_forecaster();
+
+        let mut forecasts: Vec<(Vec<u8>, f64)> = forecaster.forecast(activities(&[1, 1]), 10)
+            .iter()
+            .map(|forecast| (codes(forecast.forecast_data()), forecast.certainty()))
+            .collect();
+        forecasts.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(forecasts, [(vec![2, 2, 3, 3], 0.5), (vec![4, 4, 5, 5], 0.5)]);
+
+        // only one of the equally close days is wanted
+        let forecasts = forecaster.forecast(activities(&[1, 1]), 1);
+        assert_eq!(forecasts.len(), 1);
+        assert_eq!(forecasts[0].certainty(), 1.0);
+        let data = codes(forecasts[0].forecast_data());
+        assert!(data == [2, 2, 3, 3] || data == [4, 4, 5, 5]);
+
+        // blocks missing from the beginning are not compared
+        let forecasts = forecaster.forecast(activities(&[20, 2]), 10);
+        assert_eq!(forecasts.len(), 1);
+        assert_eq!(codes(forecasts[0].forecast_data()), [6, 6, 6, 6]);
+    }
+
+    #[test]
+    fn prefix_copy_distributions_weigh_every_equally_close_day() {
+        let forecaster = prefix_copy_forecaster();
+
+        let distribution = forecaster.forecast_distribution(activities(&[1, 1]));
+        assert_eq!(distribution.probability(0, ActivityCategory::from_code(2).unwrap()), 0.5);
+        assert_eq!(distribution.probability(0, ActivityCategory::from_code(4).unwrap()), 0.5);
+        assert_eq!(distribution.probability(3, ActivityCategory::from_code(5).unwrap()), 0.5);
+        assert_eq!(distribution.probability(0, ActivityCategory::from_code(6).unwrap()), 0.0);
+    }
+}
@@ -0,0 +1,266 @@
+//! Checks whether the certainty a DayForecaster gives each forecast means anything, by comparing
+//! stated certainties with how often forecasts of held-out days actually came true.
+
+use std::rc::Rc;
+
+use crate::{activity_block::ActivityBlockFile, day_forecaster::{DayForecaster, Reseed}, encodings::ActivityCategory, error::{Error, Result}, evaluation::{self, EvaluationOptions}};
+
+/// how far the sum of the certainties of a set of forecasts may stray from 1 before the set is
+/// counted as unnormalized
+const NORMALIZATION_TOLERANCE: f64 = 1e-6;
+
+/// options controlling how forecasts are judged and bucketed
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationOptions {
+    /// the number of equally wide certainty ranges forecasts are bucketed into
+    pub bin_count: usize,
+
+    /// the number of blocks after the cut-off a forecast must get right to come true, or None if it
+    /// must get the whole rest of the day right
+    pub horizon: Option<usize>,
+}
+
+impl Default for CalibrationOptions {
+    /// buckets forecasts into 10 bins, judging them on the block after the cut-off
+    fn default() -> Self {
+        Self {
+            bin_count: 10,
+            horizon: Some(1),
+        }
+    }
+}
+
+impl CalibrationOptions {
+    pub fn with_bin_count(mut self, bin_count: usize) -> Self {
+        self.bin_count = bin_count;
+        self
+    }
+
+    pub fn with_horizon(mut self, horizon: Option<usize>) -> Self {
+        self.horizon = horizon;
+        self
+    }
+}
+
+/// the forecasts whose certainty fell within a range
+#[derive(Debug, Clone, Default)]
+pub struct CalibrationBin {
+    /// the smallest certainty of the range
+    pub lower: f64,
+
+    /// the largest certainty of the range, which is only included in the last bin
+    pub upper: f64,
+
+    /// the number of forecasts in the range
+    pub forecasts: usize,
+
+    /// the sum of the certainties of the forecasts in the range
+    pub total_certainty: f64,
+
+    /// the number of forecasts in the range that came true
+    pub hits: usize,
+}
+
+impl CalibrationBin {
+    /// the mean stated certainty of the forecasts in the range
+    pub fn mean_certainty(&self) -> Option<f64> {
+        (self.forecasts > 0).then(|| self.total_certainty / self.forecasts as f64)
+    }
+
+    /// the proportion of the forecasts in the range that came true
+    pub fn hit_rate(&self) -> Option<f64> {
+        (self.forecasts > 0).then(|| self.hits as f64 / self.forecasts as f64)
+    }
+}
+
+/// how well the certainties of a forecaster matched how often its forecasts came true
+#[derive(Debug, Clone)]
+pub struct CalibrationReport {
+    /// the reliability diagram, plotting the hit rate of each bin against its mean certainty
+    pub bins: Vec<CalibrationBin>,
+
+    /// the total squared difference between the certainty of every forecast and whether it came true
+    pub squared_error: f64,
+
+    /// the number of sets of forecasts whose certainties did not sum to 1
+    pub unnormalized_sets: usize,
+
+    /// the number of sets of forecasts judged
+    pub sets: usize,
+
+    /// the number of cut-off days not judged because the blocks forecasts are judged on were
+    /// missing data
+    pub skipped_sets: usize,
+}
+
+impl CalibrationReport {
+    /// creates an empty report with bin_count equally wide bins covering [0, 1]
+    fn new(bin_count: usize) -> Self {
+        Self {
+            bins: (0..bin_count)
+                .map(|i| CalibrationBin {
+                    lower: i as f64 / bin_count as f64,
+                    upper: (i + 1) as f64 / bin_count as f64,
+                    ..Default::default()
+                })
+                .collect(),
+            squared_error: 0.0,
+            unnormalized_sets: 0,
+            sets: 0,
+            skipped_sets: 0,
+        }
+    }
+
+    /// the total number of forecasts judged
+    pub fn forecasts(&self) -> usize {
+        self.bins.iter().map(|bin| bin.forecasts).sum()
+    }
+
+    /// the mean squared difference between the certainty of each forecast and whether it came true
+    pub fn brier_score(&self) -> Option<f64> {
+        let forecasts = self.forecasts();
+        (forecasts > 0).then(|| self.squared_error / forecasts as f64)
+    }
+
+    /// the mean difference between the certainty and hit rate of each bin, weighted by the number of
+    /// forecasts in the bin
+    pub fn expected_calibration_error(&self) -> Option<f64> {
+        let forecasts = self.forecasts();
+        (forecasts > 0).then(|| {
+            self.bins.iter()
+                .filter(|bin| bin.forecasts > 0)
+                .map(|bin| (bin.total_certainty - bin.hits as f64).abs())
+                .sum::<f64>() / forecasts as f64
+        })
+    }
+
+    /// adds a forecast of the given certainty to the report
+    fn add_forecast(&mut self, certainty: f64, hit: bool) {
+        let bin_count = self.bins.len();
+        let bin = &mut self.bins[((certainty * bin_count as f64) as usize).min(bin_count - 1)];
+        bin.forecasts += 1;
+        bin.total_certainty += certainty;
+        bin.hits += hit as usize;
+
+        self.squared_error += (certainty - hit as u8 as f64).powi(2);
+    }
+
+    /// writes the reliability diagram to a csv file, with one row per bin
+    pub fn write_csv(&self, path: &str) -> Result<()> {
+        let mut writer = csv::Writer::from_path(path).map_err(Error::csv(path, None))?;
+        writer.write_record(["lower", "upper", "forecasts", "mean_certainty", "hit_rate"])
+            .map_err(Error::csv(path, Some(0)))?;
+
+        for (row, bin) in self.bins.iter().enumerate() {
+            writer.write_record([
+                bin.lower.to_string(),
+                bin.upper.to_string(),
+                bin.forecasts.to_string(),
+                bin.mean_certainty().map(|value| value.to_string()).unwrap_or_default(),
+                bin.hit_rate().map(|value| value.to_string()).unwrap_or_default(),
+            ]).map_err(Error::csv(path, Some(row as u64 + 1)))?;
+        }
+
+        writer.flush().map_err(Error::io(path))
+    }
+}
+
+/// compares the certainty of every forecast of a held-out activity block file with whether the
+/// forecast came true
+/// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day and
+/// begin its days at the same time as the forecaster
+/// path is only used for error reporting
+pub fn calibrate<F: DayForecaster<BLOCK_DURATION> + Reseed + ?Sized, const BLOCK_DURATION: u32>(
+    forecaster: &F,
+    test: &ActivityBlockFile,
+    path: &str,
+    options: &EvaluationOptions,
+    calibration: &CalibrationOptions,
+) -> Result<CalibrationReport> {
+    assert!(calibration.bin_count > 0, "calibration requires at least one bin");
+
+    let cut_blocks = evaluation::check_test_file::<BLOCK_DURATION>(test, path, forecaster.day_start(), options)?;
+    forecaster.reseed(options.seed);
+
+    let mut report = CalibrationReport::new(calibration.bin_count);
+    for day in test.days() {
+        for &(blocks, _) in &cut_blocks {
+            let (initial_conditions, truth) = day.split_at(blocks);
+            let judged = &truth[..calibration.horizon.map_or(truth.len(), |horizon| horizon.min(truth.len()))];
+
+            // whether a forecast came true is unknown when the day is missing data
+            if judged.contains(&ActivityCategory::MissingData) {
+                report.skipped_sets += 1;
+                continue;
+            }
+
+            let forecasts = forecaster.forecast(Rc::new(initial_conditions.to_vec()), options.forecast_count);
+            let total_certainty: f64 = forecasts.iter().map(|forecast| forecast.certainty()).sum();
+            if (total_certainty - 1.0).abs() > NORMALIZATION_TOLERANCE {
+                report.unnormalized_sets += 1;
+            }
+            report.sets += 1;
+
+            for forecast in &forecasts {
+                let hit = forecast.forecast_data().starts_with(judged);
+                report.add_forecast(forecast.certainty(), hit);
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{activity_block::test_block_file, baselines::PersistenceForecaster};
+
+    const TOLERANCE: f64 = 1e-12;
+
+    #[test]
+    fn metrics_match_hand_computed_values() {
+        let mut report = CalibrationReport::new(4);
+        for (certainty, hit) in [(0.1, false), (0.2, true), (0.9, true), (0.8, false), (1.0, true), (0.6, true)] {
+            report.add_forecast(certainty, hit);
+        }
+
+        let counts: Vec<(usize, usize)> = report.bins.iter().map(|bin| (bin.forecasts, bin.hits)).collect();
+        assert_eq!(counts, [(2, 1), (0, 0), (1, 1), (3, 2)]);
+        assert_eq!(report.forecasts(), 6);
+
+        // the empty bin has nothing to plot
+        assert_eq!(report.bins[1].mean_certainty(), None);
+        assert_eq!(report.bins[1].hit_rate(), None);
+        assert!((report.bins[3].mean_certainty().unwrap() - 0.9).abs() < TOLERANCE);
+        assert!((report.bins[3].hit_rate().unwrap() - 2.0 / 3.0).abs() < TOLERANCE);
+
+        let squared_errors = 0.01 + 0.64 + 0.01 + 0.64 + 0.0 + 0.16;
+        assert!((report.brier_score().unwrap() - squared_errors / 6.0).abs() < TOLERANCE);
+
+        // |0.3 - 1| + |0.6 - 1| + |2.7 - 2| over 6 forecasts
+        assert!((report.expected_calibration_error().unwrap() - 0.3).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn empty_reports_have_no_metrics() {
+        let report = CalibrationReport::new(10);
+        assert_eq!(report.brier_score(), None);
+        assert_eq!(report.expected_calibration_error(), None);
+    }
+
+    #[test]
+    fn calibrate_judges_forecasts_on_the_horizon() {
+        let file = test_block_file(&[[0, 0, 0, 1, 1, 1], [2, 2, 2, 2, 2, 20]]);
+        let options = EvaluationOptions::default().with_cutoffs(vec![240, 480, 720, 960, 1200]);
+        let report = calibrate(&PersistenceForecaster::<240>::new(), &file, "test", &options, &CalibrationOptions::default()).unwrap();
+
+        // persistence is certain of every forecast, and only misses the change from 0 to 1, while
+        // the last cut-off of the second day is judged on missing data
+        assert_eq!(report.sets, 9);
+        assert_eq!(report.skipped_sets, 1);
+        assert_eq!(report.unnormalized_sets, 0);
+        assert_eq!((report.bins[9].forecasts, report.bins[9].hits), (9, 8));
+        assert!((report.brier_score().unwrap() - 1.0 / 9.0).abs() < TOLERANCE);
+    }
+}
@@ -0,0 +1,296 @@
+//! k-fold cross-validation comparing forecasters on equal footing: every forecaster is trained on
+//! the same folds and evaluated on the same held-out days.
+
+use std::fmt::Write;
+
+use crate::{activity_block::ActivityBlockFile, day_forecaster::ReseedableForecaster, encodings::ActivityCategory, error::{Error, Result}, evaluation::{self, EvaluationOptions, EvaluationReport, Metrics}, markov_chain, split::{self, SplitOptions}};
+
+/// the days a forecaster is trained on for one fold of cross-validation
+pub struct TrainingData {
+    /// every day outside the held-out fold
+    file: ActivityBlockFile,
+
+    /// describes the training days for error reporting
+    path: String,
+
+    /// the index of the fold held out for evaluation
+    held_out_fold: usize,
+}
+
+impl TrainingData {
+    /// the days to train on
+    pub fn file(&self) -> &ActivityBlockFile {
+        &self.file
+    }
+
+    /// describes the training days, for use wherever training requires a path for error reporting
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// the index of the fold held out for evaluation
+    pub fn held_out_fold(&self) -> usize {
+        self.held_out_fold
+    }
+}
+
+/// creates a forecaster trained on the given days, or fails if it cannot be trained on them
+pub type ForecasterFactory<'a, const BLOCK_DURATION: u32> =
+    Box<dyn Fn(&TrainingData) -> Result<Box<dyn ReseedableForecaster<BLOCK_DURATION>>> + 'a>;
+
+/// a metric compared between forecasters, alongside how to read it from the metrics of a fold
+struct MetricColumn {
+    name: &'static str,
+    value: fn(&Metrics) -> Option<f64>,
+}
+
+/// the metrics compared between forecasters, in the order they are reported
+const METRIC_COLUMNS: [MetricColumn; 6] = [
+    MetricColumn { name: "accuracy", value: Metrics::accuracy },
+    MetricColumn { name: "macro precision", value: macro_precision },
+    MetricColumn { name: "macro recall", value: macro_recall },
+    MetricColumn { name: "log-likelihood", value: Metrics::mean_log_likelihood },
+    MetricColumn { name: "hamming distance", value: Metrics::mean_hamming_distance },
+    MetricColumn { name: "edit distance", value: Metrics::mean_edit_distance },
+];
+
+/// the mean precision over every activity that was forecast at least once
+fn macro_precision(metrics: &Metrics) -> Option<f64> {
+    mean(&ActivityCategory::valid_iter().filter_map(|activity| metrics.precision(activity)).collect::<Vec<_>>())
+}
+
+/// the mean recall over every activity that was performed at least once
+fn macro_recall(metrics: &Metrics) -> Option<f64> {
+    mean(&ActivityCategory::valid_iter().filter_map(|activity| metrics.recall(activity)).collect::<Vec<_>>())
+}
+
+/// the mean of some values, or None if there are none
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// the sample standard deviation of some values, or None if there are fewer than two
+fn standard_deviation(values: &[f64]) -> Option<f64> {
+    let mean = mean(values)?;
+    (values.len() > 1).then(|| {
+        (values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64).sqrt()
+    })
+}
+
+/// compares forecasters by k-fold cross-validation
+pub struct CrossValidation<'a, const BLOCK_DURATION: u32> {
+    /// the name of each forecaster alongside how to train it
+    forecasters: Vec<(String, ForecasterFactory<'a, BLOCK_DURATION>)>,
+
+    /// how days are assigned to folds
+    split_options: SplitOptions,
+
+    /// the number of folds
+    fold_count: usize,
+
+    /// how each forecaster is evaluated on a held-out fold
+    evaluation_options: EvaluationOptions,
+}
+
+impl<'a, const BLOCK_DURATION: u32> CrossValidation<'a, BLOCK_DURATION> {
+    /// creates a cross-validation with the given number of folds and no forecasters
+    pub fn new(fold_count: usize) -> Self {
+        assert!(fold_count >= 2, "cross-validation requires at least two folds");
+
+        Self {
+            forecasters: Vec::new(),
+            split_options: SplitOptions::default(),
+            fold_count,
+            evaluation_options: EvaluationOptions::default(),
+        }
+    }
+
+    /// adds a forecaster to compare, created by factory for every fold
+    /// an error from the factory stops the cross-validation
+    pub fn with_forecaster(
+        mut self,
+        name: &str,
+        factory: impl Fn(&TrainingData) -> Result<Box<dyn ReseedableForecaster<BLOCK_DURATION>>> + 'a
+    ) -> Self {
+        self.forecasters.push((name.to_string(), Box::new(factory)));
+        self
+    }
+
+    /// sets how days are assigned to folds
+    pub fn with_split_options(mut self, split_options: SplitOptions) -> Self {
+        self.split_options = split_options;
+        self
+    }
+
+    /// sets how forecasters are evaluated on each held-out fold
+    pub fn with_evaluation_options(mut self, evaluation_options: EvaluationOptions) -> Self {
+        self.evaluation_options = evaluation_options;
+        self
+    }
+
+    /// trains every forecaster on all but one fold of a file and evaluates it on the held-out fold,
+    /// once for every fold
+    /// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    /// path is only used for error reporting
+    pub fn run(&self, file: &ActivityBlockFile, path: &str) -> Result<CrossValidationResults> {
+        // checked before training so factories never see days of the wrong length
+        markov_chain::check_block_count::<BLOCK_DURATION>(file.blocks_per_day(), path)?;
+
+        let folds = split::k_fold_indices(file, path, &self.split_options, self.fold_count)?;
+
+        let mut reports = vec![Vec::with_capacity(self.fold_count); self.forecasters.len()];
+        for (held_out_fold, test_days) in folds.iter().enumerate() {
+            let training_days = folds.iter()
+                .enumerate()
+                .filter(|&(fold, _)| fold != held_out_fold)
+                .flat_map(|(_, days)| days.iter().copied());
+
+            let training = TrainingData {
+                file: file.subset(training_days),
+                path: format!("{path} (without fold {held_out_fold})"),
+                held_out_fold,
+            };
+            let test = file.subset(test_days.iter().copied());
+            let test_path = format!("{path} (fold {held_out_fold})");
+
+            for ((_, factory), forecaster_reports) in self.forecasters.iter().zip(reports.iter_mut()) {
+                let forecaster = factory(&training)?;
+                forecaster_reports.push(evaluation::evaluate(&*forecaster, &test, &test_path, &self.evaluation_options)?);
+            }
+        }
+
+        Ok(CrossValidationResults {
+            forecasters: self.forecasters.iter()
+                .map(|(name, _)| name.clone())
+                .zip(reports)
+                .collect(),
+        })
+    }
+}
+
+/// the evaluation of every forecaster on every held-out fold
+pub struct CrossValidationResults {
+    /// the name of each forecaster alongside its report for each fold
+    pub forecasters: Vec<(String, Vec<EvaluationReport>)>,
+}
+
+impl CrossValidationResults {
+    /// the mean and standard deviation of a metric over the folds of a forecaster, ignoring folds
+    /// the metric could not be measured on
+    fn summarize(reports: &[EvaluationReport], column: &MetricColumn) -> (Option<f64>, Option<f64>) {
+        let values: Vec<f64> = reports.iter().filter_map(|report| (column.value)(&report.overall)).collect();
+        (mean(&values), standard_deviation(&values))
+    }
+
+    /// writes the mean and standard deviation of every metric of every forecaster to a csv file,
+    /// with one row per forecaster and metric
+    pub fn write_csv(&self, path: &str) -> Result<()> {
+        let mut writer = csv::Writer::from_path(path).map_err(Error::csv(path, None))?;
+        writer.write_record(["forecaster", "metric", "mean", "std"]).map_err(Error::csv(path, Some(0)))?;
+
+        let mut row = 1;
+        for (name, reports) in &self.forecasters {
+            for column in &METRIC_COLUMNS {
+                let (mean, std) = Self::summarize(reports, column);
+                writer.write_record([
+                    name.as_str(),
+                    column.name,
+                    &mean.map(|value| value.to_string()).unwrap_or_default(),
+                    &std.map(|value| value.to_string()).unwrap_or_default(),
+                ]).map_err(Error::csv(path, Some(row)))?;
+                row += 1;
+            }
+        }
+
+        writer.flush().map_err(Error::io(path))
+    }
+
+    /// a markdown table with one row per forecaster, giving the mean ± standard deviation of every metric
+    pub fn markdown(&self) -> String {
+        let mut table = String::from("| forecaster |");
+        for column in &METRIC_COLUMNS {
+            let _ = write!(table, " {} |", column.name);
+        }
+        table.push_str("\n| :---- |");
+        for _ in &METRIC_COLUMNS {
+            table.push_str(" ----: |");
+        }
+        table.push('\n');
+
+        for (name, reports) in &self.forecasters {
+            let _ = write!(table, "| {name} |");
+            for column in &METRIC_COLUMNS {
+                match Self::summarize(reports, column) {
+                    (Some(mean), Some(std)) => { let _ = write!(table, " {mean:.4} ± {std:.4} |"); }
+                    (Some(mean), None) => { let _ = write!(table, " {mean:.4} |"); }
+                    _ => table.push_str(" - |"),
+                }
+            }
+            table.push('\n');
+        }
+
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{activity_block::test_block_file, baselines::PersistenceForecaster};
+
+    #[test]
+    fn every_day_is_held_out_exactly_once() {
+        let days: Vec<[u8; 6]> = (0..30).map(|day| [(day % 20) as u8; 6]).collect();
+        let file = test_block_file(&days);
+
+        // the number of days each fold was trained on
+        let training_days = RefCell::new(Vec::new());
+        let results = CrossValidation::<240>::new(4)
+            .with_evaluation_options(EvaluationOptions::default().with_cutoffs(vec![4 * 60]))
+            .with_forecaster("persistence", |training: &TrainingData| {
+                training_days.borrow_mut().push((training.held_out_fold(), training.file().day_count()));
+                Ok(Box::new(PersistenceForecaster::<240>::new()))
+            })
+            .run(&file, "test")
+            .unwrap();
+
+        // each held-out day is cut off once, and trained on by every other fold
+        let reports = &results.forecasters[0].1;
+        let held_out: Vec<usize> = reports.iter().map(|report| report.overall.forecasts).collect();
+        assert_eq!(held_out.iter().sum::<usize>(), 30);
+        for (fold, &(held_out_fold, trained)) in training_days.borrow().iter().enumerate() {
+            assert_eq!(held_out_fold, fold);
+            assert_eq!(trained + held_out[fold], 30);
+        }
+    }
+
+    /// a report of a fold where the given number of 10 scored blocks were forecast correctly
+    fn report(correct_blocks: usize) -> EvaluationReport {
+        EvaluationReport {
+            overall: Metrics { forecasts: 1, scored_blocks: 10, correct_blocks, ..Metrics::default() },
+            ..EvaluationReport::default()
+        }
+    }
+
+    #[test]
+    fn markdown_gives_the_mean_and_standard_deviation_of_each_metric() {
+        let results = CrossValidationResults {
+            forecasters: vec![
+                ("two folds".to_string(), vec![report(5), report(10)]),
+                ("one fold".to_string(), vec![report(2)]),
+            ],
+        };
+
+        let markdown = results.markdown();
+        let lines: Vec<&str> = markdown.lines().collect();
+        assert_eq!(lines[0], "| forecaster | accuracy | macro precision | macro recall | log-likelihood | hamming distance | edit distance |");
+        assert_eq!(lines[1], "| :---- | ----: | ----: | ----: | ----: | ----: | ----: |");
+
+        // the standard deviation of 0.5 and 1.0 is sqrt(0.125)
+        assert_eq!(lines[2], "| two folds | 0.7500 ± 0.3536 | - | - | - | 0.0000 ± 0.0000 | 0.0000 ± 0.0000 |");
+        assert_eq!(lines[3], "| one fold | 0.2000 | - | - | - | 0.0000 | 0.0000 |");
+        assert_eq!(lines.len(), 4);
+    }
+}
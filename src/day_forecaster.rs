@@ -1,11 +1,11 @@
-//!	Defines the DayForecaster trait and associated types in order to allow generic implementations
-//!	for easier data visualization.
+//! Defines the DayForecaster trait and associated types in order to allow generic implementations
+//! for easier data visualization.
 //!
 //! Also defines a RandomForecaster for testing data visualizers.
 
 use std::{cell::RefCell, rc::Rc};
 
-use rand::{Rng, RngExt};
+use rand::{Rng, RngExt, SeedableRng};
 
 use crate::encodings::ActivityCategory;
 
@@ -27,6 +27,12 @@ impl<const BLOCK_DURATION: u32> Forecast<BLOCK_DURATION> {
         (24 * 60 / BLOCK_DURATION) as usize
     }
 
+    /// gets the time a block begins in minutes after midnight, given the time the first block
+    /// of the day begins in minutes after midnight
+    pub fn block_start_time(block_index: usize, day_start: u32) -> u32 {
+        (day_start + block_index as u32 * BLOCK_DURATION) % (24 * 60)
+    }
+
     /// creates a new forecast
     pub fn new(
         initial_conditions: Rc<Vec<ActivityCategory>>,
@@ -37,13 +43,13 @@ impl<const BLOCK_DURATION: u32> Forecast<BLOCK_DURATION> {
 
         let block_count = Self::block_count();
         assert!(
-            initial_conditions.len() + forecast_data.len() == block_count as usize,
+            initial_conditions.len() + forecast_data.len() == block_count,
             "for a forecast with block_duration {}, initial_conditions and forecast_data must contain {} blocks",
             BLOCK_DURATION, block_count
         );
 
         assert!(
-            0.0 <= certainty && certainty <= 1.0,
+            (0.0..=1.0).contains(&certainty),
             "certainty must be a number between 0.0 and 1.0"
         );
 
@@ -70,6 +76,50 @@ impl<const BLOCK_DURATION: u32> Forecast<BLOCK_DURATION> {
     }
 }
 
+/// the probability of each activity being performed in every remaining block of a day
+pub struct DistributionForecast<const BLOCK_DURATION: u32> {
+    /// the initial conditions that lead to the given forecast
+    initial_conditions: Rc<Vec<ActivityCategory>>,
+
+    /// distributions[i][a] is the probability that activity a is performed in the i-th forecast block
+    distributions: Vec<[f64; ActivityCategory::COUNT]>,
+}
+
+impl<const BLOCK_DURATION: u32> DistributionForecast<BLOCK_DURATION> {
+    /// creates a new distribution forecast
+    pub fn new(
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        distributions: Vec<[f64; ActivityCategory::COUNT]>,
+    ) -> Self {
+        let block_count = Forecast::<BLOCK_DURATION>::block_count();
+        assert!(
+            initial_conditions.len() + distributions.len() == block_count,
+            "for a forecast with block_duration {}, initial_conditions and distributions must contain {} blocks",
+            BLOCK_DURATION, block_count
+        );
+
+        Self {
+            initial_conditions,
+            distributions,
+        }
+    }
+
+    /// the initial blocks that led to the creation of a forecast
+    pub fn initial_conditions(&self) -> Rc<Vec<ActivityCategory>> {
+        self.initial_conditions.clone()
+    }
+
+    /// the probability of each activity, indexed by code, for every remaining block of the day
+    pub fn distributions(&self) -> &[[f64; ActivityCategory::COUNT]] {
+        &self.distributions
+    }
+
+    /// the probability that an activity is performed in the i-th forecast block
+    pub fn probability(&self, forecast_block: usize, activity: ActivityCategory) -> f64 {
+        self.distributions[forecast_block][activity.into_code() as usize]
+    }
+}
+
 /// forecasts the activities performed later in the day based on activities formed during the day
 pub trait DayForecaster<const BLOCK_DURATION: u32>{
     /// generate a forecast for the day based on the activities already performed in the day
@@ -79,6 +129,45 @@ pub trait DayForecaster<const BLOCK_DURATION: u32>{
         initial_conditions: Rc<Vec<ActivityCategory>>,
         forecast_count: usize,
     ) -> Vec<Box<Forecast<BLOCK_DURATION>>>;
+
+    /// the time the first block of each forecast day begins, in minutes after midnight
+    fn day_start(&self) -> u32 {
+        0
+    }
+
+    /// the natural log of the probability the forecaster gives to the day continuing as continuation
+    /// after initial_conditions, or None if the forecaster cannot compute it
+    fn log_likelihood(
+        &self,
+        _initial_conditions: &[ActivityCategory],
+        _continuation: &[ActivityCategory],
+    ) -> Option<f64> {
+        None
+    }
+}
+
+/// restarts the randomness used by a forecaster, kept apart from DayForecaster so forecasters can
+/// be built on rngs that cannot be seeded
+pub trait Reseed {
+    /// restarts any randomness used by the forecaster from the given seed, so that the same
+    /// sequence of calls to forecast produces the same forecasts
+    /// forecasters that do not use randomness may ignore this
+    fn reseed(&self, seed: u64);
+}
+
+/// a DayForecaster that can be reseeded, usable as a trait object
+pub trait ReseedableForecaster<const BLOCK_DURATION: u32>: DayForecaster<BLOCK_DURATION> + Reseed {}
+
+impl<F: DayForecaster<BLOCK_DURATION> + Reseed + ?Sized, const BLOCK_DURATION: u32> ReseedableForecaster<BLOCK_DURATION> for F {}
+
+/// forecasts the probability of each activity in every later block of the day, rather than
+/// individual days
+pub trait DistributionForecaster<const BLOCK_DURATION: u32> {
+    /// generate the distribution of activities in every block after the initial conditions
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION>;
 }
 
 /// forecasts days randomly, used for testing purposes
@@ -121,7 +210,7 @@ impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for Random
 
             for _ in 0..additional_block_count {
                 forecast_data.push(ActivityCategory::from_code(
-                    rng.random_range(0..ActivityCategory::MAX_CODE as u8)
+                    rng.random_range(0..ActivityCategory::VALID_COUNT as u8)
                 ).unwrap());
             }
 
@@ -134,6 +223,39 @@ impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for Random
 
         forecasts
     }
+
+    fn log_likelihood(
+        &self,
+        _initial_conditions: &[ActivityCategory],
+        continuation: &[ActivityCategory],
+    ) -> Option<f64> {
+        Some(continuation.iter()
+            .map(|&activity| if activity == ActivityCategory::MissingData {
+                f64::NEG_INFINITY
+            } else {
+                -(ActivityCategory::VALID_COUNT as f64).ln()
+            })
+            .sum())
+    }
 }
 
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for RandomForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
 
+impl<R: Rng, const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for RandomForecaster<R, BLOCK_DURATION> {
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_conditions.len();
+
+        // every activity other than missing data is equally likely
+        let mut uniform = [0.0; ActivityCategory::COUNT];
+        uniform[..ActivityCategory::VALID_COUNT].fill(1.0 / ActivityCategory::VALID_COUNT as f64);
+
+        DistributionForecast::new(initial_conditions, vec![uniform; additional_block_count])
+    }
+}
@@ -0,0 +1,298 @@
+//! Decoding of partially observed days under a chain of per-block transition matrices: the most
+//! probable way of filling the blocks of missing data in a day (Viterbi), and the probability of
+//! every activity in every block given the blocks that were observed (forward-backward).
+
+use crate::{encodings::ActivityCategory, markov_chain::BlockStateChangeMatrix};
+
+/// whether a block of a day may be the activity with the given code
+/// blocks of missing data may be any activity that is not missing data, while observed blocks may
+/// only be the activity observed
+fn allowed(observed: ActivityCategory, code: usize) -> bool {
+    if observed == ActivityCategory::MissingData {
+        code < ActivityCategory::VALID_COUNT
+    } else {
+        code == observed.into_code() as usize
+    }
+}
+
+/// whether a day can be decoded under a chain of per-block transition matrices, which requires it
+/// to have at least one block and no more blocks than the chain
+fn decodable(matrices: &[Box<BlockStateChangeMatrix>], day: &[ActivityCategory]) -> bool {
+    !day.is_empty() && day.len() <= matrices.len()
+}
+
+/// the most probable activities of a partially observed day
+#[derive(Debug, Clone)]
+pub struct Decoding {
+    /// the day, with every block of missing data filled with an activity
+    pub activities: Vec<ActivityCategory>,
+
+    /// the natural log of the probability of the filled day
+    pub log_probability: f64,
+}
+
+/// finds the single most probable filling of the blocks of missing data in a day, starting from the
+/// given distribution of activities, indexed by code, in the first block of the day
+/// a day made only of missing data gives the most probable complete day under the chain
+/// returns None if no filling is possible, such as when observed blocks cannot follow one another,
+/// or if the day is empty or has more blocks than the chain
+pub fn viterbi(
+    matrices: &[Box<BlockStateChangeMatrix>],
+    initial: &[f64; ActivityCategory::COUNT],
+    day: &[ActivityCategory],
+) -> Option<Decoding> {
+    if !decodable(matrices, day) {
+        return None;
+    }
+
+    // log_probabilities[code] is the log probability of the most probable path ending in that
+    // activity at the current block, and previous[i][code] is the activity that path was in at block i
+    let mut log_probabilities = [f64::NEG_INFINITY; ActivityCategory::COUNT];
+    for (code, log_probability) in log_probabilities.iter_mut().enumerate() {
+        if allowed(day[0], code) {
+            *log_probability = initial[code].ln();
+        }
+    }
+
+    let mut previous = Vec::with_capacity(day.len() - 1);
+    for (matrix, &observed) in matrices.iter().zip(&day[1..]) {
+        let mut next = [f64::NEG_INFINITY; ActivityCategory::COUNT];
+        let mut best_from = [0u8; ActivityCategory::COUNT];
+
+        for (to, (next, best_from)) in next.iter_mut().zip(&mut best_from).enumerate() {
+            if !allowed(observed, to) {
+                continue;
+            }
+            for (from, &log_probability) in log_probabilities.iter().enumerate() {
+                let candidate = log_probability + matrix.transition_probability(from as u8, to as u8).ln();
+                if candidate > *next {
+                    *next = candidate;
+                    *best_from = from as u8;
+                }
+            }
+        }
+
+        log_probabilities = next;
+        previous.push(best_from);
+    }
+
+    let (last, &log_probability) = log_probabilities.iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap();
+    if log_probability == f64::NEG_INFINITY {
+        return None;
+    }
+
+    // trace the most probable path back from its last block
+    let mut codes = vec![last as u8; day.len()];
+    for (i, best_from) in previous.iter().enumerate().rev() {
+        codes[i] = best_from[codes[i + 1] as usize];
+    }
+
+    Some(Decoding {
+        activities: codes.into_iter().map(|code| ActivityCategory::from_code(code).unwrap()).collect(),
+        log_probability,
+    })
+}
+
+/// finds the single most probable complete day under a chain of per-block transition matrices,
+/// starting from the given distribution of activities, indexed by code, in the first block
+/// returns None if the chain has no blocks
+pub fn most_probable_day(
+    matrices: &[Box<BlockStateChangeMatrix>],
+    initial: &[f64; ActivityCategory::COUNT],
+) -> Option<Decoding> {
+    viterbi(matrices, initial, &vec![ActivityCategory::MissingData; matrices.len()])
+}
+
+/// the probability of every activity in every block of a partially observed day, given the blocks
+/// that were observed
+#[derive(Debug, Clone)]
+pub struct Posterior {
+    /// distributions[i][code] is the probability that block i was the activity with that code
+    pub distributions: Vec<[f64; ActivityCategory::COUNT]>,
+
+    /// the natural log of the probability of the observed blocks
+    pub log_likelihood: f64,
+}
+
+impl Posterior {
+    /// the most probable activity of each block on its own, which unlike the Viterbi filling need
+    /// not form a possible day as a whole
+    pub fn most_probable_activities(&self) -> Vec<ActivityCategory> {
+        self.distributions.iter()
+            .map(|distribution| {
+                let (code, _) = distribution.iter()
+                    .enumerate()
+                    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .unwrap();
+                ActivityCategory::from_code(code as u8).unwrap()
+            })
+            .collect()
+    }
+}
+
+/// finds the probability of every activity in every block of a day given the blocks that were
+/// observed, starting from the given distribution of activities, indexed by code, in the first
+/// block of the day
+/// returns None if the observed blocks are impossible under the chain, or if the day is empty or
+/// has more blocks than the chain
+pub fn forward_backward(
+    matrices: &[Box<BlockStateChangeMatrix>],
+    initial: &[f64; ActivityCategory::COUNT],
+    day: &[ActivityCategory],
+) -> Option<Posterior> {
+    if !decodable(matrices, day) {
+        return None;
+    }
+
+    // forward[i][code] is the probability of the activity at block i given the blocks observed up
+    // to and including block i, and scales[i] is the probability of block i given the blocks before
+    // it, so the forward probabilities never underflow
+    let mut forward = Vec::with_capacity(day.len());
+    let mut scales = Vec::with_capacity(day.len());
+
+    let mut distribution = [0.0; ActivityCategory::COUNT];
+    for (code, probability) in distribution.iter_mut().enumerate() {
+        if allowed(day[0], code) {
+            *probability = initial[code];
+        }
+    }
+
+    for i in 0..day.len() {
+        if i > 0 {
+            distribution = matrices[i - 1].propagate(&forward[i - 1]);
+            for (code, probability) in distribution.iter_mut().enumerate() {
+                if !allowed(day[i], code) {
+                    *probability = 0.0;
+                }
+            }
+        }
+
+        let scale: f64 = distribution.iter().sum();
+        if scale <= 0.0 {
+            return None;
+        }
+        forward.push(distribution.map(|probability| probability / scale));
+        scales.push(scale);
+    }
+
+    // backward holds the probability of the blocks observed after block i given the activity at
+    // block i, divided by the scales of those blocks
+    let mut backward = [1.0; ActivityCategory::COUNT];
+    let mut distributions = vec![[0.0; ActivityCategory::COUNT]; day.len()];
+    for i in (0..day.len()).rev() {
+        if i + 1 < day.len() {
+            let mut previous = [0.0; ActivityCategory::COUNT];
+            for (from, previous) in previous.iter_mut().enumerate() {
+                for (to, &later) in backward.iter().enumerate() {
+                    if later > 0.0 && allowed(day[i + 1], to) {
+                        *previous += matrices[i].transition_probability(from as u8, to as u8) * later;
+                    }
+                }
+                *previous /= scales[i + 1];
+            }
+            backward = previous;
+        }
+
+        let mut total = 0.0;
+        for ((probability, &forward), &backward) in distributions[i].iter_mut().zip(&forward[i]).zip(&backward) {
+            *probability = forward * backward;
+            total += *probability;
+        }
+        for probability in &mut distributions[i] {
+            *probability /= total;
+        }
+    }
+
+    Some(Posterior {
+        distributions,
+        log_likelihood: scales.iter().map(|scale| scale.ln()).sum(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markov_chain::{BlockStateChangeMatrixPrecursor, Smoothing};
+
+    use ActivityCategory::MissingData;
+
+    const TOLERANCE: f64 = 1e-12;
+
+    /// a chain of three blocks between the activities with codes 0 and 1, where
+    ///     block 0 to 1: 0 stays with probability 0.7 and 1 stays with probability 0.8
+    ///     block 1 to 2: 0 stays with probability 0.9 and 1 stays with probability 0.5
+    /// and the first block is 0 with probability 0.6
+    #[allow(clippy::vec_box)]
+    fn chain() -> (Vec<Box<BlockStateChangeMatrix>>, [f64; ActivityCategory::COUNT]) {
+        let mut precursors = BlockStateChangeMatrixPrecursor::for_blocks(3);
+        for (from, to, count) in [(0, 0, 7.0), (0, 1, 3.0), (1, 0, 2.0), (1, 1, 8.0)] {
+            precursors[0].add_weighted_change(from, to, count);
+        }
+        for (from, to, count) in [(0, 0, 9.0), (0, 1, 1.0), (1, 0, 1.0), (1, 1, 1.0)] {
+            precursors[1].add_weighted_change(from, to, count);
+        }
+
+        let mut initial = [0.0; ActivityCategory::COUNT];
+        initial[0] = 0.6;
+        initial[1] = 0.4;
+        (BlockStateChangeMatrix::from_precursors(&precursors, Smoothing::Unsmoothed), initial)
+    }
+
+    fn activity(code: u8) -> ActivityCategory {
+        ActivityCategory::from_code(code).unwrap()
+    }
+
+    #[test]
+    fn viterbi_fills_the_most_probable_activity() {
+        let (matrices, initial) = chain();
+
+        // filling the middle block with 0 has probability 0.6 * 0.7 * 0.1, and with 1 has 0.6 * 0.3 * 0.5
+        let decoded = viterbi(&matrices, &initial, &[activity(0), MissingData, activity(1)]).unwrap();
+        assert_eq!(decoded.activities, [activity(0), activity(1), activity(1)]);
+        assert!((decoded.log_probability - (0.6f64 * 0.3 * 0.5).ln()).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn most_probable_day_is_found() {
+        let (matrices, initial) = chain();
+
+        let decoded = most_probable_day(&matrices, &initial).unwrap();
+        assert_eq!(decoded.activities, [activity(0); 3]);
+        assert!((decoded.log_probability - (0.6f64 * 0.7 * 0.9).ln()).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn forward_backward_gives_posterior_probabilities() {
+        let (matrices, initial) = chain();
+
+        let posterior = forward_backward(&matrices, &initial, &[activity(0), MissingData, activity(1)]).unwrap();
+        let (stays, changes) = (0.7 * 0.1, 0.3 * 0.5);
+        assert!((posterior.distributions[1][0] - stays / (stays + changes)).abs() < TOLERANCE);
+        assert!((posterior.distributions[1][1] - changes / (stays + changes)).abs() < TOLERANCE);
+        assert!((posterior.distributions[0][0] - 1.0).abs() < TOLERANCE);
+        assert!((posterior.distributions[2][1] - 1.0).abs() < TOLERANCE);
+        assert!((posterior.log_likelihood - (0.6 * (stays + changes)).ln()).abs() < TOLERANCE);
+        assert_eq!(posterior.most_probable_activities(), [activity(0), activity(1), activity(1)]);
+
+        // with nothing observed, every block is distributed as the chain propagates the first block
+        let posterior = forward_backward(&matrices, &initial, &[MissingData; 3]).unwrap();
+        assert!((posterior.distributions[1][0] - (0.6 * 0.7 + 0.4 * 0.2)).abs() < TOLERANCE);
+        assert!(posterior.log_likelihood.abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn impossible_or_invalid_days_are_not_decoded() {
+        let (matrices, initial) = chain();
+
+        // no activity follows 0 and leads to 2, which nothing transitions into
+        let impossible = [activity(0), MissingData, activity(2)];
+        assert!(viterbi(&matrices, &initial, &impossible).is_none());
+        assert!(forward_backward(&matrices, &initial, &impossible).is_none());
+
+        assert!(viterbi(&matrices, &initial, &[]).is_none());
+        assert!(forward_backward(&matrices, &initial, &[MissingData; 4]).is_none());
+    }
+}
@@ -0,0 +1,155 @@
+//! Demographic characteristics of survey respondents, used to train models on groups of days.
+
+/// the demographic characteristics of a survey respondent, using the original ATUS codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Demographics {
+    /// the FAMINCOME code of the respondent's family
+    pub family_income: u16,
+
+    /// the HHTENURE code of the respondent's living quarters
+    pub tenure: u16,
+
+    /// the HOUSETYPE code of the respondent's housing unit
+    pub housetype: u16,
+
+    /// the SCHLCOLL code of the respondent's school enrollment
+    pub schooling: u16,
+}
+
+impl Demographics {
+    /// the income band of the respondent's family, or None if the income is unknown
+    pub fn income_band(&self) -> Option<IncomeBand> {
+        match self.family_income {
+            1..=7 => Some(IncomeBand::Low),
+            8..=13 => Some(IncomeBand::Middle),
+            14..=16 => Some(IncomeBand::High),
+            _ => None,
+        }
+    }
+
+    /// whether the respondent is enrolled in high school or college, or None if unknown
+    pub fn is_student(&self) -> Option<bool> {
+        match self.schooling {
+            1 => Some(false),
+            2..=5 => Some(true),
+            _ => None,
+        }
+    }
+
+    /// whether the respondent's living quarters are owned by a household member, or None if unknown
+    pub fn owns_home(&self) -> Option<bool> {
+        match self.tenure {
+            1 => Some(true),
+            2 | 3 => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// a coarse grouping of family incomes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IncomeBand {
+    /// less than $25,000 a year
+    Low,
+
+    /// $25,000 to $74,999 a year
+    Middle,
+
+    /// $75,000 or more a year
+    High,
+}
+
+/// selects the days of respondents belonging to a demographic group
+/// a field of None places no constraint on that characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct DemographicFilter {
+    pub student: Option<bool>,
+    pub income_band: Option<IncomeBand>,
+    pub owns_home: Option<bool>,
+}
+
+impl DemographicFilter {
+    /// a filter accepting every respondent
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn with_student(mut self, student: bool) -> Self {
+        self.student = Some(student);
+        self
+    }
+
+    pub fn with_income_band(mut self, income_band: IncomeBand) -> Self {
+        self.income_band = Some(income_band);
+        self
+    }
+
+    pub fn with_owns_home(mut self, owns_home: bool) -> Self {
+        self.owns_home = Some(owns_home);
+        self
+    }
+
+    /// whether the filter accepts every respondent
+    pub fn is_all(&self) -> bool {
+        *self == Self::all()
+    }
+
+    /// the number of characteristics constrained by the filter
+    pub fn specificity(&self) -> usize {
+        self.student.is_some() as usize
+            + self.income_band.is_some() as usize
+            + self.owns_home.is_some() as usize
+    }
+
+    /// a filter accepting only the respondents accepted by both filters, or None if the filters
+    /// constrain a characteristic to different values, so no respondent is accepted
+    pub fn intersect(&self, other: &Self) -> Option<Self> {
+        fn combine<T: PartialEq + Copy>(a: Option<T>, b: Option<T>) -> Option<Option<T>> {
+            match (a, b) {
+                (Some(a), Some(b)) if a != b => None,
+                _ => Some(a.or(b)),
+            }
+        }
+
+        Some(Self {
+            student: combine(self.student, other.student)?,
+            income_band: combine(self.income_band, other.income_band)?,
+            owns_home: combine(self.owns_home, other.owns_home)?,
+        })
+    }
+
+    /// whether a respondent belongs to the group
+    /// respondents with unknown characteristics never match a constraint on that characteristic
+    pub fn matches(&self, demographics: &Demographics) -> bool {
+        fn check<T: PartialEq>(constraint: Option<T>, value: Option<T>) -> bool {
+            constraint.is_none() || constraint == value
+        }
+
+        check(self.student, demographics.is_student())
+            && check(self.income_band, demographics.income_band())
+            && check(self.owns_home, demographics.owns_home())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intersect_combines_constraints() {
+        let students = DemographicFilter::all().with_student(true);
+        let owners = DemographicFilter::all().with_owns_home(true);
+
+        assert_eq!(students.intersect(&owners), Some(students.with_owns_home(true)));
+        assert_eq!(students.intersect(&DemographicFilter::all()), Some(students));
+        assert_eq!(students.intersect(&students), Some(students));
+    }
+
+    #[test]
+    fn intersect_of_contradicting_filters_is_none() {
+        let students = DemographicFilter::all().with_student(true);
+        let non_students = DemographicFilter::all().with_student(false);
+
+        assert_eq!(students.intersect(&non_students), None);
+    }
+}
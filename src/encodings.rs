@@ -1,7 +1,9 @@
 //! Defines encodings and transformations of data used by the project. Useful for decreasing
 //! loading or processing times.
 
-use std::{collections::BTreeMap, io::Write};
+use std::collections::BTreeMap;
+
+use crate::{activity_block::{ActivityBlockHeader, ActivityBlockWriter, DayMetadata}, decoding, demographics::Demographics, error::{Error, Result}, markov_chain::BlockStateChangeMatrix};
 
 #[derive(Debug, serde::Deserialize)]
 #[allow(dead_code)]
@@ -16,13 +18,13 @@ struct OriginalRecord {
     serial: u64,
 
     #[serde(rename = "FAMINCOME")]
-    family_income: u32,
+    family_income: u16,
 
     #[serde(rename = "HHTENURE")]
-    tenure: u32,
+    tenure: u16,
 
     #[serde(rename = "HOUSETYPE")]
-    housetype: u32,
+    housetype: u16,
 
     #[serde(rename = "PERNUM")]
     person_number: u32,
@@ -30,11 +32,11 @@ struct OriginalRecord {
     #[serde(rename = "LINENO")]
     line_number: u32,
 
-   #[serde(rename = "WT06")]
+    #[serde(rename = "WT06")]
     weight: f64,
 
     #[serde(rename = "SCHLCOLL")]
-    schooling: u32,
+    schooling: u16,
 
     #[serde(rename = "ACTIVITY")]
     activity: u64,
@@ -47,20 +49,18 @@ struct OriginalRecord {
 }
 
 /// converts time in the form "hours:minutes:seconds" to seconds after midnight
-fn time_to_secs_after_midnight(time_str: &str) -> i32 {
+/// returns None if the time is malformed or out of range
+fn time_to_secs_after_midnight(time_str: &str) -> Option<i32> {
     let parts: Vec<&str> = time_str.split(':').collect();
     if parts.len() != 3 {
-        panic!("Invalid time format: {}", time_str);
+        return None;
     }
 
-    let hours = parts[0].parse::<u32>().unwrap() as i32;
-    assert!(hours >= 0 && hours < 24, "Hours must be between 0 and 23");
-    let minutes = parts[1].parse::<u32>().unwrap() as i32;
-    assert!(minutes >= 0 && minutes < 60, "Minutes must be between 0 and 59");
-    let seconds = parts[2].parse::<u32>().unwrap() as i32;
-    assert!(seconds >= 0 && seconds < 60, "Seconds must be between 0 and 59");
+    let hours = parts[0].parse::<i32>().ok().filter(|h| (0..24).contains(h))?;
+    let minutes = parts[1].parse::<i32>().ok().filter(|m| (0..60).contains(m))?;
+    let seconds = parts[2].parse::<i32>().ok().filter(|s| (0..60).contains(s))?;
 
-    (hours * 60 + minutes) * 60 + seconds
+    Some((hours * 60 + minutes) * 60 + seconds)
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -71,6 +71,24 @@ struct RemappedRecord {
     /// the id of the person surveyed
     serial: u64,
 
+    /// the ATUS case id of the person surveyed
+    case_id: u64,
+
+    /// the survey weight of the person surveyed
+    weight: f64,
+
+    /// the FAMINCOME code of the person surveyed
+    family_income: u16,
+
+    /// the HHTENURE code of the person surveyed
+    tenure: u16,
+
+    /// the HOUSETYPE code of the person surveyed
+    housetype: u16,
+
+    /// the SCHLCOLL code of the person surveyed
+    schooling: u16,
+
     /// the activity completed, remapped to a smaller set of categories
     activity: u8,
 
@@ -81,17 +99,29 @@ struct RemappedRecord {
     stop: i32
 }
 
-impl From<OriginalRecord> for Option<RemappedRecord> {
-    fn from(record: OriginalRecord) -> Self {
-        let start_parsed = time_to_secs_after_midnight(&record.start);
-        let stop_parsed = time_to_secs_after_midnight(&record.stop);
+impl RemappedRecord {
+    /// remaps an original record, given the file and row it was read from for error reporting
+    fn from_original(record: OriginalRecord, path: &str, row: u64) -> Result<Self> {
+        let parse_time = |time: &str| time_to_secs_after_midnight(time)
+            .ok_or_else(|| Error::MalformedTime { path: path.to_string(), row, time: time.to_string() });
+
+        let activity = u32::try_from(record.activity)
+            .ok()
+            .and_then(ActivityCategory::from_original_code)
+            .ok_or_else(|| Error::UnknownActivityCode { path: path.to_string(), row, code: record.activity })?;
 
-        Some(RemappedRecord {
+        Ok(RemappedRecord {
             year: record.year,
             serial: record.serial,
-            activity: ActivityCategory::from_original_code(record.activity as u32)?.into_code(),
-            start: start_parsed,
-            stop: stop_parsed
+            case_id: record.caseid,
+            weight: record.weight,
+            family_income: record.family_income,
+            tenure: record.tenure,
+            housetype: record.housetype,
+            schooling: record.schooling,
+            activity: activity.into_code(),
+            start: parse_time(&record.start)?,
+            stop: parse_time(&record.stop)?
         })
     }
 }
@@ -100,24 +130,79 @@ impl From<OriginalRecord> for Option<RemappedRecord> {
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct ActivityRecord {
     day_id: u32,
+    year: u32,
+    case_id: u64,
+    weight: f64,
+    family_income: u16,
+    tenure: u16,
+    housetype: u16,
+    schooling: u16,
     start: i32,
     stop: i32,
     activity: u8,
 }
 
-#[derive(Debug, Hash)]
-struct DayId {
-    year: u32,
-    case_id: u64,
+impl ActivityRecord {
+    /// the id of the day the activity was performed on
+    pub fn day_id(&self) -> u32 {
+        self.day_id
+    }
+
+    /// the survey weight of the respondent
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    /// the demographics of the respondent
+    pub fn demographics(&self) -> Demographics {
+        Demographics {
+            family_income: self.family_income,
+            tenure: self.tenure,
+            housetype: self.housetype,
+            schooling: self.schooling,
+        }
+    }
+
+    /// the time the activity starts in seconds after midnight
+    pub fn start(&self) -> i32 {
+        self.start
+    }
+
+    /// the time the activity stops in seconds after midnight
+    pub fn stop(&self) -> i32 {
+        self.stop
+    }
+
+    /// the category of the activity, or None if the record holds an unknown code
+    pub fn activity(&self) -> Option<ActivityCategory> {
+        ActivityCategory::from_code(self.activity)
+    }
 }
 
-impl PartialEq for DayId {
-    fn eq(&self, other: &Self) -> bool {
-        self.year == other.year && self.case_id == other.case_id
+/// a record of an activity with the given code from start to stop, in seconds after midnight, on
+/// an unweighted day with no demographics
+#[cfg(test)]
+pub(crate) fn test_record(start: i32, stop: i32, activity: u8) -> ActivityRecord {
+    ActivityRecord {
+        day_id: 0,
+        year: 2022,
+        case_id: 0,
+        weight: 1.0,
+        family_income: 0,
+        tenure: 0,
+        housetype: 0,
+        schooling: 0,
+        start,
+        stop,
+        activity,
     }
 }
 
-impl Eq for DayId {}
+#[derive(Debug, Hash, PartialEq, Eq)]
+struct DayId {
+    year: u32,
+    case_id: u64,
+}
 
 impl PartialOrd for DayId {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
@@ -135,6 +220,7 @@ impl Ord for DayId {
 }
 
 /// the category of an activity performed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ActivityCategory {
     Sleeping,
     PersonalCare,
@@ -160,9 +246,18 @@ pub enum ActivityCategory {
 }
 
 impl ActivityCategory {
-    pub const MAX_CODE: usize = 20;
+    /// the number of categories, including MissingData
+    pub const COUNT: usize = 21;
+
+    /// the number of categories excluding MissingData, which always has the greatest code
+    pub const VALID_COUNT: usize = Self::COUNT - 1;
+
+    /// identifies the current mapping from original codes to categories in activity block files
+    /// must be changed whenever from_original_code or the internal codes change
+    pub const CATEGORY_SCHEME: u16 = 1;
 
     /// gets an instance of self based on the original encoding for activities
+    #[allow(clippy::zero_prefixed_literal)]
     pub fn from_original_code(code: u32) -> Option<Self> {
         match code {
             010100..=010199 => Some(Self::Sleeping),
@@ -269,9 +364,9 @@ impl ActivityCategory {
 
     /// converts into the code used to internally represent the data
     /// we guarantee:
-    /// 	1) codes are positive
-    /// 	2) codes are consecutive starting from 0
-    /// 	3) the greatest value code corresponds to Self::MissingData
+    ///     1) codes are positive
+    ///     2) codes are consecutive starting from 0
+    ///     3) the greatest value code corresponds to Self::MissingData
     pub fn into_code(&self) -> u8 {
         match self {
             Self::Sleeping => 0,
@@ -300,81 +395,295 @@ impl ActivityCategory {
 
     /// returns an iterator over all categories excluding MissingData
     pub fn valid_iter() -> impl Iterator<Item = Self> {
-        (0..Self::MAX_CODE).into_iter()
+        (0..Self::VALID_COUNT)
+        .map(|c| Self::from_code(c as u8).unwrap())
+    }
+
+    /// returns an iterator over all categories, including MissingData
+    pub fn iter() -> impl Iterator<Item = Self> {
+        (0..Self::COUNT)
         .map(|c| Self::from_code(c as u8).unwrap())
     }
 }
 
-pub fn block_remap(block_duration: usize, input: &str, output: &str) {
-    debug_assert!(60*24 % block_duration == 0, "block duration must divide evenly into a day");
+/// the time ATUS diaries begin, in minutes after midnight
+pub const ATUS_DAY_START: u32 = 4 * 60;
 
-    let mut reader = csv::Reader::from_path(input).unwrap();
-    let mut output_file = std::fs::File::create(format!("{output}.ablk")).expect("failed to create output file");
+/// reads the records of a day-id remapped csv file, grouped by day id
+pub fn read_day_records(input: &str) -> Result<BTreeMap<u32, Vec<ActivityRecord>>> {
+    let mut reader = csv::Reader::from_path(input).map_err(Error::csv(input, None))?;
 
     let mut map = BTreeMap::<u32, Vec<ActivityRecord>>::new();
-    for result in reader.deserialize() {
-        let record: ActivityRecord = result.expect("failed to deserialize record");
-        map.entry(record.day_id).or_insert_with(Vec::new).push(record);
+    for (row, result) in reader.deserialize().enumerate() {
+        let record: ActivityRecord = result.map_err(Error::csv(input, Some(row as u64)))?;
+        map.entry(record.day_id).or_default().push(record);
+    }
+
+    Ok(map)
+}
+
+/// how blocks of missing data are filled in when building an activity block file
+#[derive(Debug, Default)]
+pub enum Imputation {
+    /// blocks of missing data are written as they are
+    #[default]
+    None,
+
+    /// each run of missing blocks is filled with the recorded activity before it, or after it at
+    /// the start of the day
+    CarryForward,
+
+    /// each run of missing blocks is filled with the nearest recorded activity in time, as by
+    /// interpolate_missing
+    Nearest,
+
+    /// missing blocks are filled with their most probable activities under a trained chain, given
+    /// the blocks that were recorded, so a run is filled the way the chain expects the day to go
+    Model {
+        /// matrices[i] gives the transitions from block i to block i + 1
+        #[allow(clippy::vec_box)]
+        matrices: Vec<Box<BlockStateChangeMatrix>>,
+
+        /// the probability of each activity, indexed by code, in the first block of the day
+        initial: [f64; ActivityCategory::COUNT],
+    },
+}
+
+/// options controlling how missing data is handled when building an activity block file
+#[derive(Debug)]
+pub struct ImputationOptions {
+    pub imputation: Imputation,
+
+    /// days whose proportion of missing blocks, before any are filled in, exceeds this are not written
+    pub max_missing_fraction: f64,
+}
+
+impl Default for ImputationOptions {
+    /// writes every day with its missing blocks as they are
+    fn default() -> Self {
+        Self {
+            imputation: Imputation::None,
+            max_missing_fraction: 1.0,
+        }
+    }
+}
+
+impl ImputationOptions {
+    pub fn with_imputation(mut self, imputation: Imputation) -> Self {
+        self.imputation = imputation;
+        self
+    }
+
+    pub fn with_max_missing_fraction(mut self, max_missing_fraction: f64) -> Self {
+        self.max_missing_fraction = max_missing_fraction;
+        self
     }
+}
+
+/// how the missing blocks of a single day were handled
+#[derive(Debug, Clone, Copy)]
+pub struct DayImputation {
+    pub day_id: u32,
 
-    let blocks_per_day: [u8; 4] = ((60 * 24 / block_duration) as u32).to_le_bytes();
-    let day_count: [u8; 8] = (map.len() as u64).to_le_bytes();
+    /// the number of blocks of the day with no covering record
+    pub missing: usize,
 
-    output_file.write(&blocks_per_day).expect("failed to write blocks per day to file");
-    output_file.write(&day_count).expect("failed to write day count to file");
+    /// the number of missing blocks that were filled in
+    pub imputed: usize,
+
+    /// whether the day was left out of the file for missing too many blocks
+    pub dropped: bool,
+}
 
-    for records in map.values() {
-        let blocks = get_day_blocks(block_duration, records);
+/// how the missing blocks of every day were handled when building an activity block file
+#[derive(Debug, Clone, Default)]
+pub struct ImputationReport {
+    /// the report of each day, in order of day id
+    pub days: Vec<DayImputation>,
+}
 
-        /*
-        let text = blocks.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(", ");
-        output_file.write_all(text.as_bytes()).expect("failed to write text to file");
-        output_file.write_all(b"\n").expect("failed to write newline to file");
-        */
+impl ImputationReport {
+    /// the total number of blocks filled in
+    pub fn imputed_blocks(&self) -> usize {
+        self.days.iter().map(|day| day.imputed).sum()
+    }
 
-        output_file.write_all(&blocks).expect("failed to write block to file");
+    /// the number of days left out of the file
+    pub fn dropped_days(&self) -> usize {
+        self.days.iter().filter(|day| day.dropped).count()
     }
 
-    output_file.flush().expect("failed to flush output file");
+    /// writes the report to a csv file, with one row per day
+    pub fn write_csv(&self, path: &str) -> Result<()> {
+        let mut writer = csv::Writer::from_path(path).map_err(Error::csv(path, None))?;
+        writer.write_record(["day_id", "missing", "imputed", "dropped"])
+            .map_err(Error::csv(path, Some(0)))?;
+
+        for (row, day) in self.days.iter().enumerate() {
+            writer.write_record([
+                day.day_id.to_string(),
+                day.missing.to_string(),
+                day.imputed.to_string(),
+                day.dropped.to_string(),
+            ]).map_err(Error::csv(path, Some(row as u64 + 1)))?;
+        }
+
+        writer.flush().map_err(Error::io(path))
+    }
+}
+
+/// fills the missing blocks of a day as the imputation describes, returning the number of blocks
+/// filled
+/// days with no recorded activity are left unchanged, as are days the chain of Imputation::Model
+/// considers impossible
+fn impute(imputation: &Imputation, day: &mut [ActivityCategory]) -> usize {
+    let missing = day.iter().filter(|&&a| a == ActivityCategory::MissingData).count();
+    if missing == 0 || missing == day.len() {
+        return 0;
+    }
+
+    let filled = match imputation {
+        Imputation::None => false,
+        Imputation::CarryForward => carry_forward_missing(day),
+        Imputation::Nearest => interpolate_missing(day),
+        Imputation::Model { matrices, initial } => match decoding::viterbi(matrices, initial, day) {
+            Some(decoded) => {
+                day.copy_from_slice(&decoded.activities);
+                true
+            }
+            None => false,
+        },
+    };
+
+    if filled { missing } else { 0 }
+}
+
+/// groups the records of a day-id remapped csv file into blocks of block_duration minutes,
+/// writing the result to "{output}.ablk"
+/// day_start is the time the first block of each day begins in minutes after midnight, which
+/// should be ATUS_DAY_START for days to match the survey's diary days
+/// blocks with no covering record are filled in or have their days dropped as the imputation
+/// options describe, and the returned report gives what was done to each day
+/// the chain of Imputation::Model must have a transition matrix for every block of the day
+pub fn block_remap(
+    block_duration: usize,
+    day_start: u32,
+    input: &str,
+    output: &str,
+    imputation: &ImputationOptions,
+) -> Result<ImputationReport> {
+    if day_start >= 24 * 60 {
+        return Err(Error::InvalidDayStart(day_start));
+    }
+
+    let output = format!("{output}.ablk");
+    let mut header = ActivityBlockHeader {
+        day_start,
+        has_metadata: true,
+        ..ActivityBlockHeader::with_block_duration(block_duration as u32, 0)?
+    };
+
+    if let Imputation::Model { matrices, .. } = &imputation.imputation
+        && matrices.len() != header.blocks_per_day as usize {
+        return Err(Error::HeaderMismatch {
+            path: output,
+            field: "blocks per day",
+            expected: header.blocks_per_day as u64,
+            found: matrices.len() as u64,
+        });
+    }
+
+    let map = read_day_records(input)?;
+
+    // the days to write must be known before the header, which holds their count
+    let mut report = ImputationReport::default();
+    let mut days = Vec::with_capacity(map.len());
+    for (&day_id, records) in &map {
+        let mut day: Vec<ActivityCategory> = get_day_blocks(block_duration, day_start, records)
+            .into_iter()
+            .map(|code| ActivityCategory::from_code(code).unwrap())
+            .collect();
+
+        let missing = day.iter().filter(|&&a| a == ActivityCategory::MissingData).count();
+        let dropped = missing as f64 / day.len() as f64 > imputation.max_missing_fraction;
+        let imputed = if dropped { 0 } else { impute(&imputation.imputation, &mut day) };
+
+        report.days.push(DayImputation { day_id, missing, imputed, dropped });
+        if !dropped {
+            days.push((day, records));
+        }
+    }
+
+    header.day_count = days.len() as u64;
+    let mut writer = ActivityBlockWriter::create(&output, header)?;
+
+    for (day, records) in days {
+        writer.write_day(&day)?;
+
+        // every day has at least one record, as days are only created when a record is found
+        let first = &records[0];
+        writer.write_metadata(DayMetadata {
+            year: first.year,
+            case_id: first.case_id,
+            weight: first.weight,
+            demographics: first.demographics(),
+        })?;
+    }
+
+    writer.finish()?;
+    Ok(report)
 }
 
 /// gets the entire activity record for a given day, given as a list of activity codes for the day
-fn get_day_blocks(block_duration: usize, records: &[ActivityRecord]) -> Vec<u8> {
+fn get_day_blocks(block_duration: usize, day_start: u32, records: &[ActivityRecord]) -> Vec<u8> {
     let num_blocks = 60 * 24 / block_duration;
     let mut blocks = Vec::with_capacity(num_blocks);
 
     for block_index in 0..num_blocks {
-        blocks.push(get_block(block_duration, block_index, records));
+        blocks.push(get_block(block_duration, day_start, block_index, records));
     }
 
     blocks
 }
 
+/// the number of seconds in a day
+const SECONDS_PER_DAY: i32 = 24 * 60 * 60;
+
+/// gets the number of seconds the interval [start, stop) overlaps the interval [block_start, block_end)
+fn overlap(start: i32, stop: i32, block_start: i32, block_end: i32) -> i32 {
+    stop.clamp(block_start, block_end) - start.clamp(block_start, block_end)
+}
+
 /// gets the activity of a given block, given a list of records for the day
-fn get_block(block_duration: usize, block_index: usize, records: &[ActivityRecord]) -> u8 {
-    let block_start = block_index * block_duration * 60;
-    let block_end = (block_index + 1) * block_duration * 60;
+/// day_start is the time the first block begins in minutes after midnight
+fn get_block(block_duration: usize, day_start: u32, block_index: usize, records: &[ActivityRecord]) -> u8 {
+    let block_start = (block_index * block_duration * 60) as i32;
+    let block_end = ((block_index + 1) * block_duration * 60) as i32;
+    let day_start = day_start as i32 * 60;
 
-    let mut seconds_per_code = [0; ActivityCategory::MAX_CODE];
+    let mut seconds_per_code = [0; ActivityCategory::VALID_COUNT];
     for record in records {
-        let time: i32 = if record.start < record.stop {
-            record.stop.clamp(block_start as i32, block_end as i32)
-            - record.start.clamp(block_start as i32, block_end as i32)
-        } else {
-            // sometimes the activity goes past midnight, in which case the above method won't work
-            // for determining time spent in an activity during this block
-            block_end as i32 - record.start.clamp(block_start as i32, block_end as i32)
-        };
+        // the times of the record relative to the start of the day, such that it stops after it starts
+        let start = (record.start - day_start).rem_euclid(SECONDS_PER_DAY);
+        let mut stop = (record.stop - day_start).rem_euclid(SECONDS_PER_DAY);
+        if stop < start {
+            stop += SECONDS_PER_DAY;
+        }
+
+        // a day spans exactly 24 hours, so any part of the activity past the end of the day
+        // wraps around to the start of the day
+        let time = overlap(start, stop, block_start, block_end)
+            + overlap(start - SECONDS_PER_DAY, stop - SECONDS_PER_DAY, block_start, block_end);
 
         // ignore missing data
-        if record.activity != ActivityCategory::MAX_CODE as u8 {
+        if record.activity != ActivityCategory::MissingData.into_code() {
             seconds_per_code[record.activity as usize] += time;
         }
     }
 
     // determine the most performed activity during the block
     let mut max_seconds = 0;
-    let mut max_code = ActivityCategory::MAX_CODE as u8;
+    let mut max_code = ActivityCategory::MissingData.into_code();
     for (i, &seconds) in seconds_per_code.iter().enumerate() {
         // total_seconds += seconds;
         if seconds > max_seconds {
@@ -385,20 +694,78 @@ fn get_block(block_duration: usize, block_index: usize, records: &[ActivityRecor
 
     // if no activity is found, return missing data
     if max_seconds == 0 {
-        ActivityCategory::MAX_CODE as u8
+        ActivityCategory::MissingData.into_code()
     } else {
         max_code
     }
 }
 
-pub fn day_id_remap(input: &str, output: &str) {
-    let mut reader = csv::Reader::from_path(input).unwrap();
-    let mut writer = csv::Writer::from_path(output).unwrap();
+/// fills each run of missing blocks in a day with the recorded activity before it, filling any
+/// run at the start of the day with the first recorded activity
+/// returns false, leaving the day unchanged, if every block is missing
+pub fn carry_forward_missing(day: &mut [ActivityCategory]) -> bool {
+    let Some(mut last) = day.iter().copied().find(|&a| a != ActivityCategory::MissingData) else {
+        return false;
+    };
+
+    for activity in day.iter_mut() {
+        if *activity == ActivityCategory::MissingData {
+            *activity = last;
+        } else {
+            last = *activity;
+        }
+    }
+    true
+}
+
+/// fills each run of missing blocks in a day with the nearest recorded activity in time, so a run
+/// between two activities is split between them, with ties going to the earlier activity
+/// returns false, leaving the day unchanged, if every block is missing
+pub fn interpolate_missing(day: &mut [ActivityCategory]) -> bool {
+    let recorded = |a: &ActivityCategory| *a != ActivityCategory::MissingData;
+    if !day.iter().any(recorded) {
+        return false;
+    }
+
+    // the index of the nearest recorded block at or before each block
+    let mut previous = vec![None; day.len()];
+    let mut last = None;
+    for (i, activity) in day.iter().enumerate() {
+        if recorded(activity) {
+            last = Some(i);
+        }
+        previous[i] = last;
+    }
+
+    // walk backwards, filling each missing block from whichever recorded block is nearer
+    let mut next: Option<usize> = None;
+    for i in (0..day.len()).rev() {
+        if recorded(&day[i]) {
+            next = Some(i);
+            continue;
+        }
+
+        let source = match (previous[i], next) {
+            (Some(p), Some(n)) => if i - p <= n - i { p } else { n },
+            (Some(p), None) => p,
+            (None, Some(n)) => n,
+            (None, None) => unreachable!("a day with a recorded block has a nearest recorded block"),
+        };
+        day[i] = day[source];
+    }
+
+    true
+}
+
+/// assigns each (year, case id) pair of a remapped csv file a consecutive day id
+pub fn day_id_remap(input: &str, output: &str) -> Result<()> {
+    let mut reader = csv::Reader::from_path(input).map_err(Error::csv(input, None))?;
+    let mut writer = csv::Writer::from_path(output).map_err(Error::csv(output, None))?;
 
     let mut map = BTreeMap::<DayId, u32>::new();
     let mut id_counter = 0;
-    for result in reader.deserialize() {
-        let record: RemappedRecord = result.unwrap();
+    for (row, result) in reader.deserialize().enumerate() {
+        let record: RemappedRecord = result.map_err(Error::csv(input, Some(row as u64)))?;
         let day_id = DayId {
             year: record.year,
             case_id: record.serial
@@ -415,22 +782,94 @@ pub fn day_id_remap(input: &str, output: &str) {
         };
         let activity_record = ActivityRecord {
             day_id: this_id,
+            year: record.year,
+            case_id: record.case_id,
+            weight: record.weight,
+            family_income: record.family_income,
+            tenure: record.tenure,
+            housetype: record.housetype,
+            schooling: record.schooling,
             start: record.start,
             stop: record.stop,
             activity: record.activity
         };
-        let _ = writer.serialize(activity_record).expect("Failed to write record");
+        writer.serialize(activity_record).map_err(Error::csv(output, Some(row as u64)))?;
     }
+    writer.flush().map_err(Error::io(output))
 }
 
-pub fn remap_original(input: &str, output: &str) {
-    let mut reader = csv::Reader::from_path(input).unwrap();
-    let mut writer = csv::Writer::from_path(output).unwrap();
-    for result in reader.deserialize() {
-        let record: OriginalRecord = result.unwrap();
-        let remapped_record = Option::<RemappedRecord>::from(record)
-            .expect("unable to remap activity code");
-        let _ = writer.serialize(remapped_record).expect("Failed to write record");
+/// remaps the activity codes and times of an original ATUS extract into a smaller csv file
+pub fn remap_original(input: &str, output: &str) -> Result<()> {
+    let mut reader = csv::Reader::from_path(input).map_err(Error::csv(input, None))?;
+    let mut writer = csv::Writer::from_path(output).map_err(Error::csv(output, None))?;
+    for (row, result) in reader.deserialize().enumerate() {
+        let row = row as u64;
+        let record: OriginalRecord = result.map_err(Error::csv(input, Some(row)))?;
+        let remapped_record = RemappedRecord::from_original(record, input, row)?;
+        writer.serialize(remapped_record).map_err(Error::csv(output, Some(row)))?;
+    }
+    writer.flush().map_err(Error::io(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip_for_every_category() {
+        for code in 0..ActivityCategory::COUNT as u8 {
+            let category = ActivityCategory::from_code(code).expect("every code below COUNT is a category");
+            assert_eq!(category.into_code(), code);
+        }
+        assert!(ActivityCategory::from_code(ActivityCategory::COUNT as u8).is_none());
+    }
+
+    #[test]
+    fn missing_data_has_the_greatest_code() {
+        assert_eq!(ActivityCategory::MissingData.into_code() as usize, ActivityCategory::COUNT - 1);
+        assert_eq!(ActivityCategory::iter().count(), ActivityCategory::COUNT);
+        assert!(ActivityCategory::valid_iter().all(|c| c != ActivityCategory::MissingData));
+        assert_eq!(ActivityCategory::valid_iter().count(), ActivityCategory::VALID_COUNT);
+    }
+
+    #[test]
+    fn interpolation_fills_gaps_from_nearest_activity() {
+        use ActivityCategory::*;
+
+        let mut day = [MissingData, Sleeping, MissingData, MissingData, MissingData, Work, MissingData];
+        assert!(interpolate_missing(&mut day));
+        assert_eq!(day, [Sleeping, Sleeping, Sleeping, Sleeping, Work, Work, Work]);
+
+        let mut empty = [MissingData; 4];
+        assert!(!interpolate_missing(&mut empty));
+        assert_eq!(empty, [MissingData; 4]);
+    }
+
+    #[test]
+    fn model_imputation_rejects_matrices_of_another_block_duration() {
+        use crate::markov_chain::{BlockStateChangeMatrixPrecursor, Smoothing};
+
+        // matrices for 30 minute blocks, used to fill a file of 15 minute blocks
+        let matrices = BlockStateChangeMatrix::from_precursors(&BlockStateChangeMatrixPrecursor::for_blocks(48), Smoothing::Unsmoothed);
+        let options = ImputationOptions::default()
+            .with_imputation(Imputation::Model { matrices, initial: [0.0; ActivityCategory::COUNT] });
+
+        let result = block_remap(15, 0, "unused.csv", "unused", &options);
+        assert!(matches!(
+            result,
+            Err(Error::HeaderMismatch { field: "blocks per day", expected: 96, found: 48, .. })
+        ));
+    }
+
+    #[test]
+    fn carry_forward_fills_gaps_from_previous_activity() {
+        use ActivityCategory::*;
+
+        let mut day = [MissingData, Sleeping, MissingData, MissingData, Work, MissingData];
+        assert!(carry_forward_missing(&mut day));
+        assert_eq!(day, [Sleeping, Sleeping, Sleeping, Sleeping, Work, Work]);
+
+        let mut empty = [MissingData; 3];
+        assert!(!carry_forward_missing(&mut empty));
     }
-    writer.flush().expect("Failed to flush writer");
 }
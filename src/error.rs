@@ -0,0 +1,202 @@
+//! Defines the error type shared by the data pipeline and model training.
+
+use std::fmt;
+
+/// an error encountered while reading, transforming, or writing activity data
+#[derive(Debug)]
+pub enum Error {
+    /// a file could not be opened, read, or written
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+
+    /// a csv file could not be read or written
+    Csv {
+        path: String,
+        /// the index of the row (excluding the header) being processed, if known
+        row: Option<u64>,
+        source: csv::Error,
+    },
+
+    /// a time was not of the form "hours:minutes:seconds"
+    MalformedTime {
+        path: String,
+        row: u64,
+        time: String,
+    },
+
+    /// an activity code did not correspond to any activity category
+    UnknownActivityCode {
+        path: String,
+        row: u64,
+        code: u64,
+    },
+
+    /// an activity block file ended before all of its days could be read
+    TruncatedBlockFile {
+        path: String,
+        /// the index of the day being read, or None if the header itself was truncated
+        day: Option<u64>,
+    },
+
+    /// an activity block file contained a code with no matching activity category
+    InvalidBlockCode {
+        path: String,
+        day: u64,
+        block: u32,
+        code: u8,
+    },
+
+    /// a value in the header of an activity block file did not match what was expected
+    HeaderMismatch {
+        path: String,
+        field: &'static str,
+        expected: u64,
+        found: u64,
+    },
+
+    /// an activity block file had no metadata section, but one was required
+    MissingMetadata {
+        path: String,
+    },
+
+    /// a model file ended before it could be fully read
+    TruncatedModelFile {
+        path: String,
+    },
+
+    /// a model file contained data that does not describe a model
+    InvalidModelFile {
+        path: String,
+        reason: &'static str,
+    },
+
+    /// two models could not be combined, as they describe days in different ways
+    IncompatibleModels {
+        field: &'static str,
+        expected: u64,
+        found: u64,
+    },
+
+    /// two models could not be combined, as their counts were trained in different ways
+    IncompatibleTrainingOptions {
+        field: &'static str,
+    },
+
+    /// no days of an activity block file were selected for training
+    NoTrainingDays {
+        path: String,
+    },
+
+    /// a mixture was given no components to train
+    InvalidComponentCount(usize),
+
+    /// the proportions of days assigned to the training and validation sets were not between 0 and
+    /// 1, or summed to more than 1
+    InvalidSplitFractions {
+        train_fraction: f64,
+        validation_fraction: f64,
+    },
+
+    /// days were to be split into no folds
+    InvalidFoldCount(usize),
+
+    /// a block duration did not divide evenly into a day
+    InvalidBlockDuration(u32),
+
+    /// a number of blocks per day did not divide evenly into a day
+    InvalidBlocksPerDay(u32),
+
+    /// a day-start offset was not within a single day
+    InvalidDayStart(u32),
+}
+
+impl Error {
+    /// creates a closure converting an io error on the given file into an Error
+    pub(crate) fn io(path: &str) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| Self::Io { path: path.to_string(), source }
+    }
+
+    /// creates a closure converting a csv error on the given file and row into an Error
+    pub(crate) fn csv(path: &str, row: Option<u64>) -> impl FnOnce(csv::Error) -> Self + '_ {
+        move |source| Self::Csv { path: path.to_string(), row, source }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "{path}: {source}"),
+            Self::Csv { path, row: Some(row), source } => write!(f, "{path}, row {row}: {source}"),
+            Self::Csv { path, row: None, source } => write!(f, "{path}: {source}"),
+            Self::MalformedTime { path, row, time } => {
+                write!(f, "{path}, row {row}: malformed time '{time}', expected 'hours:minutes:seconds'")
+            }
+            Self::UnknownActivityCode { path, row, code } => {
+                write!(f, "{path}, row {row}: unknown activity code {code}")
+            }
+            Self::TruncatedBlockFile { path, day: Some(day) } => {
+                write!(f, "{path}: activity block file ended while reading day {day}")
+            }
+            Self::TruncatedBlockFile { path, day: None } => {
+                write!(f, "{path}: activity block file ended while reading its header")
+            }
+            Self::InvalidBlockCode { path, day, block, code } => {
+                write!(f, "{path}: invalid activity code {code} in block {block} of day {day}")
+            }
+            Self::HeaderMismatch { path, field, expected, found } => {
+                write!(f, "{path}: expected header field '{field}' to be {expected}, found {found}")
+            }
+            Self::MissingMetadata { path } => {
+                write!(f, "{path}: activity block file has no per-day metadata")
+            }
+            Self::TruncatedModelFile { path } => {
+                write!(f, "{path}: model file ended before it could be fully read")
+            }
+            Self::InvalidModelFile { path, reason } => {
+                write!(f, "{path}: invalid model file, {reason}")
+            }
+            Self::IncompatibleModels { field, expected, found } => {
+                write!(f, "cannot combine models with different {field}, expected {expected}, found {found}")
+            }
+            Self::IncompatibleTrainingOptions { field } => {
+                write!(f, "cannot combine models trained with different {field}")
+            }
+            Self::NoTrainingDays { path } => {
+                write!(f, "{path}: no days were selected for training")
+            }
+            Self::InvalidComponentCount(count) => {
+                write!(f, "a mixture requires at least one component, found {count}")
+            }
+            Self::InvalidSplitFractions { train_fraction, validation_fraction } => {
+                write!(f, "cannot split {train_fraction} of days for training and {validation_fraction} for validation, \
+                    expected fractions between 0 and 1 summing to at most 1")
+            }
+            Self::InvalidFoldCount(count) => {
+                write!(f, "days must be split into at least one fold, found {count}")
+            }
+            Self::InvalidBlockDuration(duration) => {
+                write!(f, "block duration {duration} does not divide evenly into a day")
+            }
+            Self::InvalidBlocksPerDay(blocks_per_day) => {
+                write!(f, "{blocks_per_day} blocks per day do not divide evenly into a day")
+            }
+            Self::InvalidDayStart(day_start) => {
+                write!(f, "day start {day_start} is not between 0 and 1439 minutes after midnight")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Csv { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
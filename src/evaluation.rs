@@ -0,0 +1,338 @@
+//! Measures how well a DayForecaster predicts held-out days, by cutting each day off part way
+//! through and comparing forecasts of the rest of the day with what was actually done.
+
+use std::{collections::BTreeMap, rc::Rc};
+
+use crate::{activity_block::ActivityBlockFile, day_forecaster::{DayForecaster, Forecast, Reseed}, encodings::ActivityCategory, error::{Error, Result}, markov_chain};
+
+/// options controlling how forecasters are evaluated
+#[derive(Debug, Clone)]
+pub struct EvaluationOptions {
+    /// the times each test day is cut off at, in minutes after the start of the day
+    /// times are rounded down to the start of a block, and times leaving no initial block or no
+    /// block to forecast are ignored
+    pub cutoffs: Vec<u32>,
+
+    /// the number of forecasts requested for each cut-off day
+    pub forecast_count: usize,
+
+    /// the seed the forecaster is reseeded with before evaluation, so results can be reproduced
+    pub seed: u64,
+}
+
+impl Default for EvaluationOptions {
+    /// cuts days off every hour, requesting 5 forecasts each time
+    fn default() -> Self {
+        Self {
+            cutoffs: (1..24).map(|hour| hour * 60).collect(),
+            forecast_count: 5,
+            seed: 0,
+        }
+    }
+}
+
+impl EvaluationOptions {
+    pub fn with_cutoffs(mut self, cutoffs: Vec<u32>) -> Self {
+        self.cutoffs = cutoffs;
+        self
+    }
+
+    pub fn with_forecast_count(mut self, forecast_count: usize) -> Self {
+        self.forecast_count = forecast_count;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// the accumulated measures of how well forecasts matched the days they forecast
+/// blocks the test day is missing data for are not counted towards accuracy, precision or recall
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// the number of cut-off days forecast
+    pub forecasts: usize,
+
+    /// the number of cut-off days the forecaster returned no forecasts for, which are measured as
+    /// a forecast getting every block wrong
+    pub missed_forecasts: usize,
+
+    /// the number of forecast blocks the test day has recorded activities for
+    pub scored_blocks: usize,
+
+    /// the number of scored blocks the best forecast got right
+    pub correct_blocks: usize,
+
+    /// true_positives[a] counts the scored blocks where both the best forecast and the test day had activity a
+    pub true_positives: [usize; ActivityCategory::COUNT],
+
+    /// false_positives[a] counts the scored blocks where the best forecast had activity a but the test day did not
+    pub false_positives: [usize; ActivityCategory::COUNT],
+
+    /// false_negatives[a] counts the scored blocks where the test day had activity a but the best forecast did not
+    pub false_negatives: [usize; ActivityCategory::COUNT],
+
+    /// the total log-likelihood of every true continuation with a non-zero likelihood
+    /// continuations with missing blocks and forecasters that cannot compute likelihoods are not included
+    pub log_likelihood: f64,
+
+    /// the number of true continuations included in log_likelihood
+    pub likelihood_forecasts: usize,
+
+    /// the number of true continuations the forecaster gave a likelihood of zero
+    pub impossible_forecasts: usize,
+
+    /// the total number of blocks the best forecast differed from the test day in
+    pub hamming_distance: usize,
+
+    /// the total edit distance between the best forecast and the test day
+    pub edit_distance: usize,
+}
+
+impl Metrics {
+    /// the proportion of scored blocks the best forecast got right
+    pub fn accuracy(&self) -> Option<f64> {
+        ratio(self.correct_blocks, self.scored_blocks)
+    }
+
+    /// the proportion of blocks forecast as an activity that really were that activity
+    pub fn precision(&self, activity: ActivityCategory) -> Option<f64> {
+        let code = activity.into_code() as usize;
+        ratio(self.true_positives[code], self.true_positives[code] + self.false_positives[code])
+    }
+
+    /// the proportion of blocks of an activity that were forecast as that activity
+    pub fn recall(&self, activity: ActivityCategory) -> Option<f64> {
+        let code = activity.into_code() as usize;
+        ratio(self.true_positives[code], self.true_positives[code] + self.false_negatives[code])
+    }
+
+    /// the mean log-likelihood of the true continuations the forecaster gave a non-zero likelihood
+    pub fn mean_log_likelihood(&self) -> Option<f64> {
+        (self.likelihood_forecasts > 0).then(|| self.log_likelihood / self.likelihood_forecasts as f64)
+    }
+
+    /// the mean number of blocks the best forecast differed from the test day in
+    pub fn mean_hamming_distance(&self) -> Option<f64> {
+        ratio(self.hamming_distance, self.forecasts)
+    }
+
+    /// the mean edit distance between the best forecast and the test day
+    pub fn mean_edit_distance(&self) -> Option<f64> {
+        ratio(self.edit_distance, self.forecasts)
+    }
+
+    /// adds the measures of another set of forecasts to these
+    pub fn merge(&mut self, other: &Self) {
+        self.forecasts += other.forecasts;
+        self.missed_forecasts += other.missed_forecasts;
+        self.scored_blocks += other.scored_blocks;
+        self.correct_blocks += other.correct_blocks;
+        for code in 0..ActivityCategory::COUNT {
+            self.true_positives[code] += other.true_positives[code];
+            self.false_positives[code] += other.false_positives[code];
+            self.false_negatives[code] += other.false_negatives[code];
+        }
+        self.log_likelihood += other.log_likelihood;
+        self.likelihood_forecasts += other.likelihood_forecasts;
+        self.impossible_forecasts += other.impossible_forecasts;
+        self.hamming_distance += other.hamming_distance;
+        self.edit_distance += other.edit_distance;
+    }
+
+    /// measures a single forecast against the true continuation of the day, or a missed forecast
+    /// if the forecaster returned none
+    /// blocks past the end of a forecast are counted as wrong
+    fn add_forecast(&mut self, best: Option<&[ActivityCategory]>, truth: &[ActivityCategory], log_likelihood: Option<f64>) {
+        self.forecasts += 1;
+        if best.is_none() {
+            self.missed_forecasts += 1;
+        }
+        let best = best.unwrap_or_default();
+
+        for (i, &actual) in truth.iter().enumerate() {
+            if actual == ActivityCategory::MissingData {
+                continue;
+            }
+
+            self.scored_blocks += 1;
+            match best.get(i) {
+                Some(&forecast) if forecast == actual => {
+                    self.correct_blocks += 1;
+                    self.true_positives[actual.into_code() as usize] += 1;
+                }
+                Some(&forecast) => {
+                    self.false_positives[forecast.into_code() as usize] += 1;
+                    self.false_negatives[actual.into_code() as usize] += 1;
+                }
+                None => self.false_negatives[actual.into_code() as usize] += 1,
+            }
+        }
+
+        match log_likelihood {
+            Some(log_likelihood) if log_likelihood.is_finite() => {
+                self.log_likelihood += log_likelihood;
+                self.likelihood_forecasts += 1;
+            }
+            Some(_) => self.impossible_forecasts += 1,
+            None => {}
+        }
+
+        self.hamming_distance += hamming_distance(best, truth);
+        self.edit_distance += edit_distance(best, truth);
+    }
+}
+
+/// the measures of a forecaster over every cut-off of every test day
+#[derive(Debug, Clone, Default)]
+pub struct EvaluationReport {
+    pub overall: Metrics,
+
+    /// the measures of days cut off within each hour of the day, keyed by the hour after midnight
+    pub by_cutoff_hour: BTreeMap<u32, Metrics>,
+}
+
+impl EvaluationReport {
+    /// adds the measures of another report to these
+    pub fn merge(&mut self, other: &Self) {
+        self.overall.merge(&other.overall);
+        for (hour, metrics) in &other.by_cutoff_hour {
+            self.by_cutoff_hour.entry(*hour).or_default().merge(metrics);
+        }
+    }
+}
+
+/// numerator / denominator, or None if the denominator is zero
+fn ratio(numerator: usize, denominator: usize) -> Option<f64> {
+    (denominator > 0).then(|| numerator as f64 / denominator as f64)
+}
+
+/// the number of positions two sequences differ in, counting every position past the end of the
+/// shorter sequence as different
+pub fn hamming_distance(a: &[ActivityCategory], b: &[ActivityCategory]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| x != y).count() + a.len().abs_diff(b.len())
+}
+
+/// the smallest number of insertions, deletions and substitutions turning one sequence into another,
+/// so a forecast that is right but shifted in time scores better than under the hamming distance
+pub fn edit_distance(a: &[ActivityCategory], b: &[ActivityCategory]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, x) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = previous[j] + (x != y) as usize;
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+/// checks a held-out file can be forecast by a forecaster beginning its days at day_start, returning
+/// the number of initial blocks given for each usable cut-off alongside the hour of the cut-off
+/// path is only used for error reporting
+pub(crate) fn check_test_file<const BLOCK_DURATION: u32>(
+    test: &ActivityBlockFile,
+    path: &str,
+    day_start: u32,
+    options: &EvaluationOptions,
+) -> Result<Vec<(usize, u32)>> {
+    markov_chain::check_block_count::<BLOCK_DURATION>(test.blocks_per_day(), path)?;
+
+    if test.header().day_start != day_start {
+        return Err(Error::HeaderMismatch {
+            path: path.to_string(),
+            field: "day start",
+            expected: day_start as u64,
+            found: test.header().day_start as u64,
+        });
+    }
+
+    Ok(options.cutoffs.iter()
+        .map(|&cutoff| (cutoff / BLOCK_DURATION) as usize)
+        .filter(|&blocks| blocks > 0 && blocks < Forecast::<BLOCK_DURATION>::block_count())
+        .map(|blocks| (blocks, Forecast::<BLOCK_DURATION>::block_start_time(blocks, day_start) / 60))
+        .collect())
+}
+
+/// evaluates a forecaster on every day of a held-out activity block file
+/// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day and
+/// begin its days at the same time as the forecaster
+/// path is only used for error reporting
+pub fn evaluate<F: DayForecaster<BLOCK_DURATION> + Reseed + ?Sized, const BLOCK_DURATION: u32>(
+    forecaster: &F,
+    test: &ActivityBlockFile,
+    path: &str,
+    options: &EvaluationOptions,
+) -> Result<EvaluationReport> {
+    let cut_blocks = check_test_file::<BLOCK_DURATION>(test, path, forecaster.day_start(), options)?;
+    forecaster.reseed(options.seed);
+
+    let mut report = EvaluationReport::default();
+    for day in test.days() {
+        for &(blocks, hour) in &cut_blocks {
+            let (initial_conditions, truth) = day.split_at(blocks);
+
+            let forecasts = forecaster.forecast(Rc::new(initial_conditions.to_vec()), options.forecast_count);
+            // a cut-off the forecaster returned no forecasts for is measured as missed, rather than
+            // left out where it would flatter the forecaster
+            let best = forecasts.iter().max_by(|a, b| a.certainty().total_cmp(&b.certainty()));
+            // a continuation with missing blocks has no single likelihood, so it is not scored
+            let log_likelihood = if truth.contains(&ActivityCategory::MissingData) {
+                None
+            } else {
+                forecaster.log_likelihood(initial_conditions, truth)
+            };
+
+            let mut metrics = Metrics::default();
+            metrics.add_forecast(best.map(|best| best.forecast_data()), truth, log_likelihood);
+            report.overall.merge(&metrics);
+            report.by_cutoff_hour.entry(hour).or_default().merge(&metrics);
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activities(codes: &[u8]) -> Vec<ActivityCategory> {
+        codes.iter().map(|&code| ActivityCategory::from_code(code).unwrap()).collect()
+    }
+
+    #[test]
+    fn hamming_distance_counts_unmatched_positions() {
+        assert_eq!(hamming_distance(&activities(&[0, 1, 2]), &activities(&[0, 2, 2])), 1);
+        assert_eq!(hamming_distance(&activities(&[0, 1]), &activities(&[0, 1, 2, 3])), 2);
+        assert_eq!(hamming_distance(&[], &activities(&[0, 1])), 2);
+    }
+
+    #[test]
+    fn missing_forecasts_are_scored_as_wrong() {
+        let truth = activities(&[0, 1, 2]);
+
+        let mut metrics = Metrics::default();
+        metrics.add_forecast(None, &truth, None);
+        assert_eq!(metrics.forecasts, 1);
+        assert_eq!(metrics.missed_forecasts, 1);
+        assert_eq!(metrics.scored_blocks, 3);
+        assert_eq!(metrics.correct_blocks, 0);
+        assert_eq!(metrics.hamming_distance, 3);
+        assert_eq!(metrics.edit_distance, 3);
+        assert_eq!(metrics.false_negatives[1], 1);
+
+        // blocks past the end of a short forecast are wrong too
+        metrics.add_forecast(Some(&activities(&[0])), &truth, None);
+        assert_eq!(metrics.missed_forecasts, 1);
+        assert_eq!(metrics.correct_blocks, 1);
+        assert_eq!(metrics.accuracy(), Some(1.0 / 6.0));
+        assert_eq!(metrics.mean_hamming_distance(), Some(2.5));
+    }
+}
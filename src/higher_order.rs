@@ -0,0 +1,369 @@
+//! Implementation of a DayForecaster conditioned on the activities of the last several blocks,
+//! rather than only the previous block
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use rand::{Rng, RngExt, SeedableRng};
+
+use crate::{activity_block::ActivityBlockFile, day_forecaster::{DayForecaster, Forecast, Reseed}, encodings::ActivityCategory, error::Result, markov_chain::{self, MissingDataPolicy, TrainingOptions}};
+
+/// the greatest number of previous blocks a chain can be conditioned on
+pub const MAX_ORDER: usize = 12;
+
+/// the number of bits used to store each activity of a context
+const CONTEXT_BITS: usize = 5;
+
+/// the activities of up to MAX_ORDER consecutive blocks, packed CONTEXT_BITS per activity with
+/// the most recent activity in the lowest bits
+type Context = u64;
+
+/// the (possibly weighted) number of times each activity follows a context
+type ContextCounts = [f64; ActivityCategory::COUNT];
+
+/// a chain of per-block transition counts conditioned on the last `order` activities, backing off
+/// to shorter contexts when a context was not seen often enough during training
+pub struct HigherOrderChain {
+    /// the number of previous blocks each transition is conditioned on
+    order: usize,
+
+    /// counts[i][k] maps the last k activities up to and including block i to the counts of the
+    /// activity performed in block i + 1
+    /// (the last block is unused, as there is no block following it)
+    counts: Vec<Vec<HashMap<Context, ContextCounts>>>,
+
+    /// the smallest total count a context must have to be used rather than a shorter context
+    min_context_count: f64,
+}
+
+impl HigherOrderChain {
+    /// allocates an empty chain of the given order for each block of the day
+    pub fn new(order: usize, blocks_per_day: usize) -> Self {
+        assert!(
+            (1..=MAX_ORDER).contains(&order),
+            "the order of a chain must be between 1 and {MAX_ORDER}"
+        );
+
+        Self {
+            order,
+            counts: (0..blocks_per_day).map(|_| vec![HashMap::new(); order + 1]).collect(),
+            min_context_count: f64::MIN_POSITIVE,
+        }
+    }
+
+    /// creates a chain of the given order trained on a loaded activity block file
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions, order: usize) -> Result<Self> {
+        let mut chain = Self::new(order, file.blocks_per_day());
+        markov_chain::for_each_training_day(file, path, options, |activities, weight| {
+            chain.add_day(activities, weight, options.missing_data);
+        })?;
+        Ok(chain)
+    }
+
+    /// sets the smallest total count a context must have to be used rather than backing off to
+    /// a shorter context
+    pub fn with_min_context_count(mut self, min_context_count: f64) -> Self {
+        self.min_context_count = min_context_count.max(f64::MIN_POSITIVE);
+        self
+    }
+
+    /// counts the activity of every block of a day following each of its contexts
+    /// days are expected to have already been interpolated under MissingDataPolicy::Interpolate
+    pub fn add_day(&mut self, activities: &[ActivityCategory], weight: f64, missing_data: MissingDataPolicy) {
+        let skip_missing = missing_data != MissingDataPolicy::OwnState;
+
+        for (block_idx, &next) in activities.iter().enumerate().skip(1) {
+            if skip_missing && next == ActivityCategory::MissingData {
+                continue;
+            }
+
+            let block_counts = &mut self.counts[block_idx - 1];
+            let mut context: Context = 0;
+            for (length, contexts) in block_counts.iter_mut().enumerate().take(block_idx + 1) {
+                if length > 0 {
+                    let previous = activities[block_idx - length];
+                    // any longer context contains the same missing block
+                    if skip_missing && previous == ActivityCategory::MissingData {
+                        break;
+                    }
+                    context |= (previous.into_code() as Context) << (CONTEXT_BITS * (length - 1));
+                }
+
+                contexts.entry(context).or_insert([0.0; ActivityCategory::COUNT])[next.into_code() as usize] += weight;
+            }
+        }
+    }
+
+    /// the number of previous blocks each transition is conditioned on
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// the number of blocks in each day of the chain
+    pub fn blocks_per_day(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// the number of distinct contexts of the full order seen during training
+    pub fn context_count(&self) -> usize {
+        self.counts.iter().map(|block| block[self.order].len()).sum()
+    }
+
+    /// the probability of each activity, indexed by code, being performed in the block following
+    /// history, using the longest context of at most `order` blocks that was seen often enough
+    /// if no context was seen, every activity other than missing data is equally likely
+    /// panics if history is empty or leaves no block of the day to follow it
+    pub fn next_distribution(&self, history: &[ActivityCategory]) -> [f64; ActivityCategory::COUNT] {
+        assert!(!history.is_empty(), "a higher order chain requires at least one previous block");
+        assert!(history.len() < self.blocks_per_day(), "history must leave at least one block of the day");
+
+        let block_counts = &self.counts[history.len() - 1];
+        let longest = self.order.min(history.len());
+
+        // the contexts of every length up to the longest available
+        let mut contexts = [0 as Context; MAX_ORDER + 1];
+        for length in 1..=longest {
+            let previous = history[history.len() - length].into_code() as Context;
+            contexts[length] = contexts[length - 1] | previous << (CONTEXT_BITS * (length - 1));
+        }
+
+        for length in (0..=longest).rev() {
+            if let Some(counts) = block_counts[length].get(&contexts[length]) {
+                let total: f64 = counts.iter().sum();
+                if total >= self.min_context_count {
+                    return counts.map(|count| count / total);
+                }
+            }
+        }
+
+        let mut uniform = [0.0; ActivityCategory::COUNT];
+        uniform[..ActivityCategory::VALID_COUNT].fill(1.0 / ActivityCategory::VALID_COUNT as f64);
+        uniform
+    }
+}
+
+/// picks an activity code at random according to a distribution indexed by code
+pub(crate) fn sample_distribution<R: Rng + ?Sized>(distribution: &[f64; ActivityCategory::COUNT], rng: &mut R) -> u8 {
+    let mut remaining = rng.random::<f64>();
+    for (code, &probability) in distribution.iter().enumerate() {
+        if remaining < probability {
+            return code as u8;
+        }
+        remaining -= probability;
+    }
+
+    // rounding left the sample just past the end, so use the last possible activity
+    distribution.iter().rposition(|&probability| probability > 0.0).unwrap_or(0) as u8
+}
+
+/// forecasts days by sampling paths from a chain conditioned on the last several blocks
+pub struct HigherOrderForecaster<R: Rng, const BLOCK_DURATION: u32> {
+    chain: HigherOrderChain,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+
+    /// the rng used to sample transitions
+    rng_cell: RefCell<R>,
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> HigherOrderForecaster<R, BLOCK_DURATION> {
+    /// creates a new HigherOrderForecaster from a trained chain, consuming the rng used to sample
+    /// transitions
+    pub fn new(chain: HigherOrderChain, rng: R) -> Self {
+        assert!(
+            chain.blocks_per_day() == Forecast::<BLOCK_DURATION>::block_count(),
+            "a forecaster with block_duration {} requires a chain with {} blocks per day",
+            BLOCK_DURATION, Forecast::<BLOCK_DURATION>::block_count()
+        );
+
+        Self {
+            chain,
+            day_start: 0,
+            rng_cell: RefCell::new(rng),
+        }
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// creates a new HigherOrderForecaster of the given order trained on the activity block file
+    /// at the given path
+    pub fn from_block_file(path: &str, options: &TrainingOptions, order: usize, rng: R) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options, order, rng)
+    }
+
+    /// creates a new HigherOrderForecaster of the given order trained on a loaded activity block file
+    /// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions, order: usize, rng: R) -> Result<Self> {
+        markov_chain::check_block_count::<BLOCK_DURATION>(file.blocks_per_day(), path)?;
+
+        let chain = HigherOrderChain::train(file, path, options, order)?;
+        Ok(Self::new(chain, rng).with_day_start(file.header().day_start))
+    }
+
+    /// the chain forecasts are sampled from
+    pub fn chain(&self) -> &HigherOrderChain {
+        &self.chain
+    }
+
+    /// gets the rng from the forecaster, destroying it
+    pub fn rng(self) -> R {
+        self.rng_cell.into_inner()
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for HigherOrderForecaster<R, BLOCK_DURATION> {
+    /// panics if initial_conditions is empty, as there is no previous block to condition on
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        let initial_block_count = initial_conditions.len();
+        assert!(initial_block_count > 0, "a markov forecast requires at least one initial block");
+
+        let block_count = Forecast::<BLOCK_DURATION>::block_count();
+        let mut rng = self.rng_cell.borrow_mut();
+
+        // the generated paths, alongside the probability of each path occurring
+        let mut paths = Vec::with_capacity(forecast_count);
+        let mut total_probability = 0.0;
+
+        for _ in 0..forecast_count {
+            let mut day = Vec::with_capacity(block_count);
+            day.extend_from_slice(&initial_conditions);
+            let mut probability = 1.0;
+
+            while day.len() < block_count {
+                let distribution = self.chain.next_distribution(&day);
+                let next = sample_distribution(&distribution, &mut *rng);
+                probability *= distribution[next as usize];
+                day.push(ActivityCategory::from_code(next).unwrap());
+            }
+
+            total_probability += probability;
+            paths.push((day.split_off(initial_block_count), probability));
+        }
+
+        paths.into_iter()
+            .map(|(forecast_data, probability)| {
+                // normalize so that the certainties of all forecasts sum to 1
+                let certainty = if total_probability > 0.0 {
+                    probability / total_probability
+                } else {
+                    1.0 / forecast_count as f64
+                };
+
+                Box::new(Forecast::new(
+                    initial_conditions.clone(),
+                    forecast_data,
+                    certainty.clamp(0.0, 1.0),
+                ))
+            })
+            .collect()
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+
+    fn log_likelihood(
+        &self,
+        initial_conditions: &[ActivityCategory],
+        continuation: &[ActivityCategory],
+    ) -> Option<f64> {
+        let mut day = initial_conditions.to_vec();
+        let mut log_likelihood = 0.0;
+        for &activity in continuation {
+            log_likelihood += self.chain.next_distribution(&day)[activity.into_code() as usize].ln();
+            day.push(activity);
+        }
+        Some(log_likelihood)
+    }
+}
+
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for HigherOrderForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{activity_block::test_block_file, markov_chain::BlockStateChangeMatrix};
+    use rand::rngs::StdRng;
+
+    const TOLERANCE: f64 = 1e-12;
+
+    fn activities(codes: &[u8]) -> Vec<ActivityCategory> {
+        codes.iter().map(|&code| ActivityCategory::from_code(code).unwrap()).collect()
+    }
+
+    fn chain(days: &[[u8; 6]], order: usize) -> HigherOrderChain {
+        HigherOrderChain::train(&test_block_file(days), "test", &TrainingOptions::default(), order).unwrap()
+    }
+
+    #[test]
+    fn unseen_contexts_back_off_to_shorter_ones() {
+        let chain = chain(&[[0, 1, 2, 0, 0, 0], [3, 1, 3, 0, 0, 0]], 2);
+
+        // 0 then 1 was only ever followed by 2
+        let distribution = chain.next_distribution(&activities(&[0, 1]));
+        assert_eq!(distribution[2], 1.0);
+
+        // 4 then 1 was never seen, so only the last block is conditioned on
+        let distribution = chain.next_distribution(&activities(&[4, 1]));
+        assert_eq!(distribution[2], 0.5);
+        assert_eq!(distribution[3], 0.5);
+
+        // contexts seen fewer times than the minimum are backed off from too
+        let chain = chain.with_min_context_count(2.0);
+        let distribution = chain.next_distribution(&activities(&[0, 1]));
+        assert_eq!(distribution[2], 0.5);
+        assert_eq!(distribution[3], 0.5);
+    }
+
+    #[test]
+    fn long_contexts_are_told_apart_by_their_oldest_block() {
+        let chain = chain(&[[1, 0, 0, 0, 0, 2], [3, 0, 0, 0, 0, 4]], MAX_ORDER);
+
+        assert_eq!(chain.next_distribution(&activities(&[1, 0, 0, 0, 0]))[2], 1.0);
+        assert_eq!(chain.next_distribution(&activities(&[3, 0, 0, 0, 0]))[4], 1.0);
+    }
+
+    #[test]
+    fn first_order_chains_match_transition_matrices() {
+        let days = [[0, 0, 1, 1, 2, 0], [0, 1, 1, 2, 2, 0], [0, 0, 2, 1, 0, 0], [1, 0, 1, 2, 0, 1]];
+        let chain = chain(&days, 1);
+        let matrices = BlockStateChangeMatrix::from_block_file(&test_block_file(&days), "test", &TrainingOptions::default()).unwrap();
+
+        for day in &days {
+            for prefix in 1..6 {
+                let history = activities(&day[..prefix]);
+                let distribution = chain.next_distribution(&history);
+                let from = history[prefix - 1].into_code();
+                for (to, &probability) in distribution.iter().enumerate() {
+                    assert!((probability - matrices[prefix - 1].transition_probability(from, to as u8)).abs() < TOLERANCE);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn forecasts_complete_the_day() {
+        let file = test_block_file(&[[0, 0, 1, 1, 2, 0], [0, 1, 1, 2, 2, 0]]);
+        let forecaster = HigherOrderForecaster::<_, 240>::train(&file, "test", &TrainingOptions::default(), 2, StdRng::seed_from_u64(0)).unwrap();
+
+        let forecasts = forecaster.forecast(Rc::new(activities(&[0, 0])), 5);
+        assert_eq!(forecasts.len(), 5);
+        for forecast in &forecasts {
+            assert_eq!(forecast.forecast_data(), activities(&[1, 1, 2, 0]));
+        }
+    }
+}
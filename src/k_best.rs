@@ -0,0 +1,299 @@
+//! Deterministic forecasting of the most likely completions of a day under a Markov chain.
+
+use std::rc::Rc;
+
+use crate::{
+    activity_block::ActivityBlockFile,
+    day_forecaster::{DayForecaster, DistributionForecast, DistributionForecaster, Forecast, Reseed},
+    encodings::ActivityCategory,
+    error::Result,
+    markov_chain::{self, BlockStateChangeMatrix, TrainingOptions},
+};
+
+/// a partial path ending in some activity at some block
+#[derive(Debug, Clone, Copy)]
+struct PathEntry {
+    /// the log probability of the path, given the initial conditions
+    log_probability: f64,
+
+    /// the activity the path was in at the previous block
+    previous_activity: usize,
+
+    /// the rank of the path's prefix among the paths ending in previous_activity at the previous block
+    previous_rank: usize,
+}
+
+/// forecasts the k most likely completions of a day under a chain of per-block transition matrices
+///
+/// for each block and activity, the k most likely paths ending in that activity are kept, which is
+/// enough to recover the k most likely paths overall, so the result is exact rather than approximate
+pub struct KBestForecaster<const BLOCK_DURATION: u32> {
+    /// matrices[i] gives the transitions from block i to block i + 1
+    #[allow(clippy::vec_box)]
+    matrices: Vec<Box<BlockStateChangeMatrix>>,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+}
+
+impl<const BLOCK_DURATION: u32> KBestForecaster<BLOCK_DURATION> {
+    /// creates a new KBestForecaster from the transition matrices of each block of the day
+    /// panics if there are not as many matrices as there are blocks of BLOCK_DURATION in a day
+    pub fn new(matrices: Vec<Box<BlockStateChangeMatrix>>) -> Self {
+        assert!(
+            matrices.len() == Forecast::<BLOCK_DURATION>::block_count(),
+            "a forecaster with block_duration {} requires {} transition matrices, one for each block of the day",
+            BLOCK_DURATION, Forecast::<BLOCK_DURATION>::block_count()
+        );
+
+        Self { matrices, day_start: 0 }
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// creates a new KBestForecaster trained on the activity block file at the given path
+    pub fn from_block_file(path: &str, options: &TrainingOptions) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options)
+    }
+
+    /// creates a new KBestForecaster trained on a loaded activity block file
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions) -> Result<Self> {
+        let matrices = markov_chain::train_matrices::<BLOCK_DURATION>(file, path, options)?;
+        Ok(Self::new(matrices).with_day_start(file.header().day_start))
+    }
+
+    /// the transition matrices of each block of the day
+    pub fn matrices(&self) -> &[Box<BlockStateChangeMatrix>] {
+        &self.matrices
+    }
+
+    /// gets the k most likely completions of a day, most likely first, alongside the probability
+    /// of each completion given the initial conditions
+    /// completions with a probability of zero are never returned, so fewer than k may be returned
+    pub fn most_likely(
+        &self,
+        initial_conditions: &[ActivityCategory],
+        k: usize
+    ) -> Vec<(Vec<ActivityCategory>, f64)> {
+        let initial_block_count = initial_conditions.len();
+        assert!(initial_block_count > 0, "a markov forecast requires at least one initial block");
+
+        let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_block_count;
+        if k == 0 {
+            return Vec::new();
+        }
+        if additional_block_count == 0 {
+            return vec![(Vec::new(), 1.0)];
+        }
+
+        let start = initial_conditions[initial_block_count - 1].into_code() as usize;
+
+        // layers[t][a] holds the k most likely paths ending in activity a at the t-th forecast block,
+        // most likely first
+        let mut layers: Vec<Vec<Vec<PathEntry>>> = Vec::with_capacity(additional_block_count);
+
+        for step in 0..additional_block_count {
+            let matrix = &self.matrices[initial_block_count - 1 + step];
+            let mut layer = vec![Vec::new(); ActivityCategory::COUNT];
+
+            for (to, paths) in layer.iter_mut().enumerate() {
+                let mut candidates = Vec::new();
+
+                if step == 0 {
+                    let probability = matrix.transition_probability(start as u8, to as u8);
+                    if probability > 0.0 {
+                        candidates.push(PathEntry {
+                            log_probability: probability.ln(),
+                            previous_activity: start,
+                            previous_rank: 0,
+                        });
+                    }
+                } else {
+                    for (from, previous_paths) in layers[step - 1].iter().enumerate() {
+                        let probability = matrix.transition_probability(from as u8, to as u8);
+                        if probability <= 0.0 {
+                            continue;
+                        }
+
+                        for (rank, previous) in previous_paths.iter().enumerate() {
+                            candidates.push(PathEntry {
+                                log_probability: previous.log_probability + probability.ln(),
+                                previous_activity: from,
+                                previous_rank: rank,
+                            });
+                        }
+                    }
+                }
+
+                candidates.sort_by(|a, b| b.log_probability.total_cmp(&a.log_probability));
+                candidates.truncate(k);
+                *paths = candidates;
+            }
+
+            layers.push(layer);
+        }
+
+        // the k most likely paths overall are among the k most likely paths ending in each activity
+        let mut endings: Vec<(f64, usize, usize)> = layers[additional_block_count - 1]
+            .iter()
+            .enumerate()
+            .flat_map(|(activity, paths)| paths.iter()
+                .enumerate()
+                .map(move |(rank, path)| (path.log_probability, activity, rank)))
+            .collect();
+        endings.sort_by(|a, b| b.0.total_cmp(&a.0));
+        endings.truncate(k);
+
+        endings.into_iter()
+            .map(|(log_probability, mut activity, mut rank)| {
+                let mut completion = Vec::with_capacity(additional_block_count);
+                for step in (0..additional_block_count).rev() {
+                    completion.push(ActivityCategory::from_code(activity as u8).unwrap());
+                    let entry = layers[step][activity][rank];
+                    activity = entry.previous_activity;
+                    rank = entry.previous_rank;
+                }
+                completion.reverse();
+
+                (completion, log_probability.exp())
+            })
+            .collect()
+    }
+}
+
+impl<const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for KBestForecaster<BLOCK_DURATION> {
+    /// forecasts the forecast_count most likely completions of the day, where the certainty of each
+    /// forecast is its probability normalized over every forecast returned
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        let completions = self.most_likely(&initial_conditions, forecast_count);
+        let total_probability: f64 = completions.iter().map(|(_, p)| p).sum();
+
+        completions.into_iter()
+            .map(|(forecast_data, probability)| {
+                let certainty = if total_probability > 0.0 {
+                    probability / total_probability
+                } else {
+                    0.0
+                };
+
+                Box::new(Forecast::new(
+                    initial_conditions.clone(),
+                    forecast_data,
+                    certainty.clamp(0.0, 1.0),
+                ))
+            })
+            .collect()
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+
+    fn log_likelihood(
+        &self,
+        initial_conditions: &[ActivityCategory],
+        continuation: &[ActivityCategory],
+    ) -> Option<f64> {
+        Some(markov_chain::path_log_likelihood(&self.matrices, initial_conditions, continuation))
+    }
+}
+
+impl<const BLOCK_DURATION: u32> Reseed for KBestForecaster<BLOCK_DURATION> {
+    /// does nothing, as forecasts are made without randomness
+    fn reseed(&self, _seed: u64) {}
+}
+
+impl<const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for KBestForecaster<BLOCK_DURATION> {
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        markov_chain::propagate_distribution(&self.matrices, initial_conditions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::markov_chain::{BlockStateChangeMatrixPrecursor, Smoothing};
+
+    /// a chain of 6 four hour blocks between the activities with codes 0, 1 and 2, with uneven
+    /// probabilities that differ from block to block
+    #[allow(clippy::vec_box)]
+    fn matrices() -> Vec<Box<BlockStateChangeMatrix>> {
+        let mut precursors = BlockStateChangeMatrixPrecursor::for_blocks(6);
+        for (block, precursor) in precursors.iter_mut().enumerate() {
+            for from in 0..3 {
+                for to in 0..3 {
+                    precursor.add_weighted_change(from, to, (1 + (from as usize * 3 + to as usize * 5 + block * 7) % 11) as f64);
+                }
+            }
+        }
+        BlockStateChangeMatrix::from_precursors(&precursors, Smoothing::Unsmoothed)
+    }
+
+    fn probability(matrices: &[Box<BlockStateChangeMatrix>], start: u8, path: &[u8], first_block: usize) -> f64 {
+        let mut previous = start;
+        let mut probability = 1.0;
+        for (matrix, &next) in matrices[first_block..].iter().zip(path) {
+            probability *= matrix.transition_probability(previous, next);
+            previous = next;
+        }
+        probability
+    }
+
+    /// the probability of every completion of a day of 6 blocks after prefix blocks, most likely first
+    fn enumerate(matrices: &[Box<BlockStateChangeMatrix>], start: u8, prefix: usize) -> Vec<f64> {
+        let length = 6 - prefix;
+        let mut probabilities: Vec<f64> = (0..3usize.pow(length as u32))
+            .map(|index| {
+                let path: Vec<u8> = (0..length).map(|block| (index / 3usize.pow(block as u32) % 3) as u8).collect();
+                probability(matrices, start, &path, prefix - 1)
+            })
+            .collect();
+        probabilities.sort_by(|a, b| b.total_cmp(a));
+        probabilities
+    }
+
+    #[test]
+    fn most_likely_matches_brute_force_enumeration() {
+        let forecaster = KBestForecaster::<240>::new(matrices());
+
+        for (prefix, k) in [(1, 10), (2, 81), (3, 5), (5, 3)] {
+            let initial_conditions = vec![ActivityCategory::from_code(1).unwrap(); prefix];
+            let expected = enumerate(forecaster.matrices(), 1, prefix);
+            let completions = forecaster.most_likely(&initial_conditions, k);
+            assert_eq!(completions.len(), k.min(expected.len()));
+
+            for ((completion, found), expected) in completions.iter().zip(&expected) {
+                // ties may be broken either way, so the probabilities are compared in order and
+                // each path is checked to have the probability given
+                let path: Vec<u8> = completion.iter().map(|activity| activity.into_code()).collect();
+                assert!((found - expected).abs() < 1e-12);
+                assert!((probability(forecaster.matrices(), 1, &path, prefix - 1) - found).abs() < 1e-12);
+            }
+
+            let distinct: HashSet<_> = completions.iter().map(|(completion, _)| completion.clone()).collect();
+            assert_eq!(distinct.len(), completions.len());
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_rejects_the_wrong_number_of_matrices() {
+        let mut matrices = matrices();
+        matrices.pop();
+        KBestForecaster::<240>::new(matrices);
+    }
+}
@@ -1,7 +1,37 @@
+pub mod error;
+
 pub mod encodings;
 
+pub mod activity_block;
+
+pub mod demographics;
+
 pub mod markov_chain;
 
+pub mod markov_model;
+
+pub mod k_best;
+
+pub mod higher_order;
+
+pub mod semi_markov;
+
+pub mod mixture;
+
+pub mod decoding;
+
+pub mod baselines;
+
+pub mod analog;
+
+pub mod split;
+
+pub mod evaluation;
+
+pub mod calibration;
+
+pub mod cross_validation;
+
 pub mod day_forecaster;
 
 pub mod app;
@@ -1,32 +1,172 @@
-use modeling_daily_activity::{app::ActivityForecastApp, day_forecaster::RandomForecaster, encodings, markov_chain::BlockStateChangeMatrix};
+use std::path::Path;
+
+use modeling_daily_activity::{activity_block::ActivityBlockFile, analog::AnalogForecaster, app::ActivityForecastApp, baselines::{MarginalForecaster, ModeForecaster, PersistenceForecaster, PrefixCopyForecaster}, cross_validation::CrossValidation, day_forecaster::{DayForecaster, DistributionForecaster, RandomForecaster, Reseed}, encodings::{self, ImputationOptions}, error::Result, k_best::KBestForecaster, markov_chain::{BlockStateChangeMatrix, MarkovForecaster, TrainingOptions}, markov_model::MarkovModel, mixture::{MixtureForecaster, MixtureOptions}};
+use rand::{SeedableRng, rngs::StdRng};
+
+/// the activity block file models are trained on
+const BLOCK_FILE: &str = "./data/15blocks.ablk";
+
+/// the pre-trained model shipped alongside the activity block file
+const MODEL_FILE: &str = "./data/15blocks.amkv";
+
+/// where the comparison table of a cross-validation is written
+const CROSS_VALIDATION_FILE: &str = "./data/cross_validation.csv";
+
+/// the options given on the command line
+#[derive(Default)]
+struct Args {
+    /// the seed used for random sampling
+    seed: Option<u64>,
+
+    /// whether to show the most likely completions of the day rather than sampled completions
+    most_likely: bool,
+
+    /// whether to show the rest of the most similar historical days rather than sampled completions
+    analog: bool,
+
+    /// the number of folds to compare forecasters over, rather than showing the app
+    cross_validate: Option<usize>,
+}
 
 fn main() {
-    run_app();
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("error: {message}");
+            eprintln!("usage: modeling_daily_activity [--seed <u64>] [--most-likely | --analog] [--cross-validate <folds>]");
+            std::process::exit(2);
+        }
+    };
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    println!("using seed {seed}");
+
+    let result = if let Some(fold_count) = args.cross_validate {
+        cross_validate(fold_count, seed)
+    } else if args.analog {
+        AnalogForecaster::<_, 15>::from_block_file(BLOCK_FILE, &TrainingOptions::default(), StdRng::seed_from_u64(seed))
+            .and_then(|forecaster| run_app(forecaster, seed))
+    } else {
+        load_model().and_then(|model| if args.most_likely {
+            let forecaster = KBestForecaster::<15>::new(model.matrices()).with_day_start(model.day_start());
+            run_app(forecaster, seed)
+        } else {
+            let rng = StdRng::seed_from_u64(seed);
+            run_app(MarkovForecaster::<_, 15>::from_model(&model, rng), seed)
+        })
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
 }
 
-#[allow(dead_code)]
-fn process_data() {
-    encodings::remap_original("./data/timedata.csv", "./data/timedata_remap.csv");
+/// reads the options from the command line arguments
+fn parse_args(mut args: impl Iterator<Item = String>) -> std::result::Result<Args, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let value = args.next().ok_or("--seed requires a value")?;
+                parsed.seed = Some(value.parse().map_err(|_| format!("invalid seed '{value}'"))?);
+            }
+            "--most-likely" => parsed.most_likely = true,
+            "--analog" => parsed.analog = true,
+            "--cross-validate" => {
+                let value = args.next().ok_or("--cross-validate requires a value")?;
+                parsed.cross_validate = Some(value.parse()
+                    .ok()
+                    .filter(|&folds| folds >= 2)
+                    .ok_or_else(|| format!("invalid fold count '{value}'"))?);
+            }
+            _ => return Err(format!("unknown argument '{arg}'")),
+        }
+    }
 
-    encodings::day_id_remap("./data/timedata_remap.csv", "./data/timedata_remap_dayid.csv");
+    if parsed.most_likely && parsed.analog {
+        return Err("--most-likely and --analog cannot be used together".to_string());
+    }
+    Ok(parsed)
+}
 
-    encodings::block_remap(15, "./data/timedata_remap_dayid.csv", "./data/15blocks");
+/// loads the pre-trained model, training it and saving it for next time if there is none or it
+/// was trained on a different version of the activity block file
+fn load_model() -> Result<MarkovModel> {
+    let file = ActivityBlockFile::open(BLOCK_FILE)?;
+    if Path::new(MODEL_FILE).exists() {
+        let model = MarkovModel::load(MODEL_FILE)?;
+        if model.source_hash() == file.source_hash() {
+            return Ok(model);
+        }
+        eprintln!("warning: the saved model was trained on different data, retraining");
+    }
 
-    let _ = BlockStateChangeMatrix::from_block_encoding("./data/15blocks.ablk");
+    let model = MarkovModel::train(&file, BLOCK_FILE, &TrainingOptions::default())?;
+    if let Err(e) = model.save(MODEL_FILE) {
+        eprintln!("warning: could not save the trained model: {e}");
+    }
+    Ok(model)
+}
+
+/// compares the forecasters by cross-validation on the activity block file, printing the
+/// comparison table and writing it to CROSS_VALIDATION_FILE
+fn cross_validate(fold_count: usize, seed: u64) -> Result<()> {
+    let file = ActivityBlockFile::open(BLOCK_FILE)?;
+
+    let results = CrossValidation::<15>::new(fold_count)
+        .with_forecaster("random", |_| Ok(Box::new(RandomForecaster::<_, 15>::new(StdRng::seed_from_u64(seed)))))
+        .with_forecaster("persistence", |training| Ok(Box::new(
+            PersistenceForecaster::<15>::new().with_day_start(training.file().header().day_start)
+        )))
+        .with_forecaster("mode", |training| Ok(Box::new(
+            ModeForecaster::<15>::train(training.file(), training.path(), &TrainingOptions::default())?
+        )))
+        .with_forecaster("marginal", |training| Ok(Box::new(
+            MarginalForecaster::<_, 15>::train(training.file(), training.path(), &TrainingOptions::default(), StdRng::seed_from_u64(seed))?
+        )))
+        .with_forecaster("prefix copy", |training| Ok(Box::new(
+            PrefixCopyForecaster::<_, 15>::train(training.file(), training.path(), &TrainingOptions::default(), StdRng::seed_from_u64(seed))?
+        )))
+        .with_forecaster("analog", |training| Ok(Box::new(
+            AnalogForecaster::<_, 15>::train(training.file(), training.path(), &TrainingOptions::default(), StdRng::seed_from_u64(seed))?
+        )))
+        .with_forecaster("markov", |training| Ok(Box::new(
+            MarkovForecaster::<_, 15>::train(training.file(), training.path(), &TrainingOptions::default(), StdRng::seed_from_u64(seed))?
+        )))
+        .with_forecaster("mixture", |training| Ok(Box::new(
+            MixtureForecaster::<_, 15>::train(training.file(), training.path(), &TrainingOptions::default(), &MixtureOptions::default().with_seed(seed), StdRng::seed_from_u64(seed))?
+        )))
+        .run(&file, BLOCK_FILE)?;
+
+    print!("{}", results.markdown());
+    results.write_csv(CROSS_VALIDATION_FILE)
 }
 
 #[allow(dead_code)]
-fn run_app() {
-    let rng = rand::rng();
-    let forecaster = RandomForecaster::<_, 15>::new(rng);
+fn process_data() -> Result<()> {
+    encodings::remap_original("./data/timedata.csv", "./data/timedata_remap.csv")?;
+
+    encodings::day_id_remap("./data/timedata_remap.csv", "./data/timedata_remap_dayid.csv")?;
+
+    encodings::block_remap(15, encodings::ATUS_DAY_START, "./data/timedata_remap_dayid.csv", "./data/15blocks", &ImputationOptions::default())?;
+
+    let _ = BlockStateChangeMatrix::from_block_encoding("./data/15blocks.ablk")?;
 
+    Ok(())
+}
+
+fn run_app<F>(forecaster: F, seed: u64) -> Result<()>
+where F: DayForecaster<15> + DistributionForecaster<15> + Reseed + 'static {
     let native_options = eframe::NativeOptions::default();
 
     let _ = eframe::run_native(
         "Daily Activity Model",
         native_options,
-        Box::new(|cc| Ok(Box::new(
-            ActivityForecastApp::new(cc, Box::new(forecaster))
+        Box::new(move |cc| Ok(Box::new(
+            ActivityForecastApp::new(cc, Box::new(forecaster), seed)
         )))
     );
+
+    Ok(())
 }
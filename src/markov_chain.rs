@@ -1,63 +1,299 @@
 //! Implementation of a Markovian DayForecaster
 
-use std::{fs::File, io::{BufReader, Read}};
+use std::{cell::RefCell, rc::Rc};
 
-use crate::encodings::ActivityCategory;
+use rand::{Rng, RngExt, SeedableRng};
 
-struct BlockStateChangeMatrixPrecursor {
-    /// the number of times a change from activity i to activity j occurs in the data
-    counts: [[u32; ActivityCategory::MAX_CODE]; ActivityCategory::MAX_CODE],
+use crate::{activity_block::{ActivityBlockFile, ActivityBlockReader}, day_forecaster::{DayForecaster, DistributionForecast, DistributionForecaster, Forecast, Reseed}, demographics::{DemographicFilter, Demographics}, encodings::{self, ActivityCategory}, error::{Error, Result}, markov_model::MarkovModel};
+
+/// how each day contributes to the transition counts of a model
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Weighting {
+    /// every day is counted once
+    #[default]
+    Unweighted,
+
+    /// every day is counted according to its ATUS survey weight, as stored in the metadata of the
+    /// activity block file, so that counts are representative of the population
+    SurveyWeights,
+}
+
+/// how blocks of MissingData are treated when counting transitions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingDataPolicy {
+    /// transitions into or out of a missing block are not counted
+    #[default]
+    Skip,
+
+    /// missing blocks are filled with the nearest recorded activity in time before counting,
+    /// and days with no recorded activity are skipped
+    Interpolate,
+
+    /// MissingData is treated as a state of its own, so transitions into and out of it are counted
+    OwnState,
+}
+
+/// how transition probabilities are estimated from the counted changes
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Smoothing {
+    /// probabilities are the observed proportions of each change, and rows with no observations
+    /// transition uniformly to any activity that is not missing data
+    #[default]
+    Unsmoothed,
+
+    /// alpha pseudo-observations of a change into every activity other than missing data are added
+    /// to each row (Laplace smoothing when alpha is 1)
+    Additive { alpha: f64 },
+
+    /// each row is shrunk toward the same row of the blocks up to `neighbours` blocks either side
+    /// and toward the same row of the pooled all-day counts, which contribute `neighbour_strength`
+    /// and `pooled_strength` pseudo-observations respectively
+    Hierarchical {
+        neighbours: usize,
+        neighbour_strength: f64,
+        pooled_strength: f64,
+    },
+}
+
+/// options controlling how transition matrices are trained
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrainingOptions {
+    pub weighting: Weighting,
+
+    pub missing_data: MissingDataPolicy,
+
+    /// only days of respondents matching the filter are counted
+    pub filter: DemographicFilter,
+
+    pub smoothing: Smoothing,
+}
+
+impl TrainingOptions {
+    pub fn with_weighting(mut self, weighting: Weighting) -> Self {
+        self.weighting = weighting;
+        self
+    }
+
+    pub fn with_missing_data(mut self, missing_data: MissingDataPolicy) -> Self {
+        self.missing_data = missing_data;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: DemographicFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_smoothing(mut self, smoothing: Smoothing) -> Self {
+        self.smoothing = smoothing;
+        self
+    }
+}
+
+/// calls on_day with every day of a file selected by the training options, alongside its weight
+/// under MissingDataPolicy::Interpolate, days are passed with their missing blocks filled in and
+/// days with no recorded activity are skipped
+pub(crate) fn for_each_training_day(
+    file: &ActivityBlockFile,
+    path: &str,
+    options: &TrainingOptions,
+    mut on_day: impl FnMut(&[ActivityCategory], f64)
+) -> Result<()> {
+    let needs_metadata = options.weighting == Weighting::SurveyWeights || !options.filter.is_all();
+
+    // reused between days to hold interpolated activities
+    let mut interpolated = Vec::with_capacity(file.blocks_per_day());
+
+    for (day_idx, activities) in file.days().enumerate() {
+        let metadata = file.day_metadata(day_idx);
+        if needs_metadata && metadata.is_none() {
+            return Err(Error::MissingMetadata { path: path.to_string() });
+        }
+
+        if let Some(metadata) = metadata
+            && !options.filter.matches(&metadata.demographics) {
+            continue;
+        }
+
+        let weight = match (options.weighting, metadata) {
+            (Weighting::SurveyWeights, Some(metadata)) => metadata.weight,
+            _ => 1.0,
+        };
+
+        if options.missing_data == MissingDataPolicy::Interpolate {
+            interpolated.clear();
+            interpolated.extend_from_slice(activities);
+            if encodings::interpolate_missing(&mut interpolated) {
+                on_day(&interpolated, weight);
+            }
+        } else {
+            on_day(activities, weight);
+        }
+    }
+
+    Ok(())
+}
+
+/// turns the (possibly weighted) counts of each activity in the first block of the day into a
+/// distribution, smoothed like a row of a transition matrix where additive smoothing applies
+/// with no counts, the first block is uniformly any activity that is not missing data
+pub(crate) fn first_block_distribution(
+    mut counts: [f64; ActivityCategory::COUNT],
+    smoothing: Smoothing,
+) -> [f64; ActivityCategory::COUNT] {
+    if let Smoothing::Additive { alpha } = smoothing {
+        for count in &mut counts[..ActivityCategory::VALID_COUNT] {
+            *count += alpha;
+        }
+    }
+
+    let total: f64 = counts.iter().sum();
+    if total > 0.0 {
+        counts.map(|count| count / total)
+    } else {
+        let mut uniform = [0.0; ActivityCategory::COUNT];
+        uniform[..ActivityCategory::VALID_COUNT].fill(1.0 / ActivityCategory::VALID_COUNT as f64);
+        uniform
+    }
+}
+
+/// estimates the distribution of activities in the first block of the day from the days of a
+/// loaded activity block file selected by the training options
+/// first blocks of missing data are only counted under MissingDataPolicy::OwnState
+/// path is only used for error reporting
+pub fn initial_distribution(
+    file: &ActivityBlockFile,
+    path: &str,
+    options: &TrainingOptions,
+) -> Result<[f64; ActivityCategory::COUNT]> {
+    let mut counts = [0.0; ActivityCategory::COUNT];
+    for_each_training_day(file, path, options, |activities, weight| {
+        if let Some(&first) = activities.first()
+            && (first != ActivityCategory::MissingData || options.missing_data == MissingDataPolicy::OwnState) {
+            counts[first.into_code() as usize] += weight;
+        }
+    })?;
+    Ok(first_block_distribution(counts, options.smoothing))
+}
+
+/// the counted changes between activities from one block of the day to the next, from which a
+/// BlockStateChangeMatrix is built
+/// precursors can be combined, so models from different years or files can be merged without retraining
+#[derive(Debug, Clone)]
+pub struct BlockStateChangeMatrixPrecursor {
+    /// the (possibly weighted) number of times a change from activity i to activity j occurs in the data
+    counts: [[f64; ActivityCategory::COUNT]; ActivityCategory::COUNT],
+}
+
+impl Default for BlockStateChangeMatrixPrecursor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BlockStateChangeMatrixPrecursor {
     pub fn new() -> Self {
         Self {
-            counts: [[0; ActivityCategory::MAX_CODE]; ActivityCategory::MAX_CODE]
+            counts: [[0.0; ActivityCategory::COUNT]; ActivityCategory::COUNT]
         }
     }
 
+    /// allocates one precursor for each block of the day
+    /// (the last precursor is unused, as there is no block following it)
+    pub fn for_blocks(blocks_per_day: usize) -> Vec<Self> {
+        let mut precursors = Vec::with_capacity(blocks_per_day);
+        for _ in 0..blocks_per_day {
+            precursors.push(Self::new());
+        }
+        precursors
+    }
+
+    /// counts the changes between consecutive blocks of a day
+    /// days are expected to have already been interpolated under MissingDataPolicy::Interpolate
+    pub(crate) fn add_day(
+        precursors: &mut [Self],
+        activities: &[ActivityCategory],
+        weight: f64,
+        missing_data: MissingDataPolicy
+    ) {
+        let mut previous = activities[0];
+        for (block_idx, &activity) in activities.iter().skip(1).enumerate() {
+            let involves_missing = previous == ActivityCategory::MissingData
+                || activity == ActivityCategory::MissingData;
+
+            if missing_data == MissingDataPolicy::OwnState || !involves_missing {
+                precursors[block_idx].add_weighted_change(previous.into_code(), activity.into_code(), weight);
+            }
+            previous = activity;
+        }
+    }
+
+    /// counts the changes of every block of the day in an activity block file, streaming its days
     pub fn from_block_encoding(
         filename: &str
-    ) -> Vec<Box<Self>> {
-        // get reader
-        let f = File::open(filename).expect("unable to open file");
-        let mut reader = BufReader::new(f);
+    ) -> Result<Vec<Self>> {
+        let mut reader = ActivityBlockReader::open(filename)?;
+        let mut precursors = Self::for_blocks(reader.header().blocks_per_day as usize);
 
-        // interpret header of block file
-        let mut blocks_per_day = [0; 4];
-        reader.read_exact(&mut blocks_per_day).expect("unable to read 'blocks per day' of activity block file");
-        let blocks_per_day = u32::from_le_bytes(blocks_per_day) as usize;
+        while let Some(activities) = reader.next_day()? {
+            Self::add_day(&mut precursors, activities, 1.0, MissingDataPolicy::Skip);
+        }
 
-        let mut day_count = [0; 8];
-        reader.read_exact(&mut day_count).expect("unable to read 'day count' of activity block file");
-        let day_count = u64::from_le_bytes(day_count) as usize;
+        Ok(precursors)
+    }
 
-        // allocate the necessary precursors (1 less than BLOCK_COUNT)
-        let mut precursors = Vec::with_capacity(blocks_per_day);
-        for _ in 0..blocks_per_day {
-            precursors.push(Box::new(Self::new()));
+    /// counts the changes of every block of the day in a loaded activity block file
+    /// path is only used for error reporting
+    pub fn from_block_file(
+        file: &ActivityBlockFile,
+        path: &str,
+        options: &TrainingOptions
+    ) -> Result<Vec<Self>> {
+        let mut precursors = Self::for_blocks(file.blocks_per_day());
+        for_each_training_day(file, path, options, |activities, weight| {
+            Self::add_day(&mut precursors, activities, weight, options.missing_data);
+        })?;
+        Ok(precursors)
+    }
+
+    /// adds the counts of another precursor to this one
+    pub fn merge(&mut self, other: &Self) {
+        for (row, other_row) in self.counts.iter_mut().zip(&other.counts) {
+            for (count, other_count) in row.iter_mut().zip(other_row) {
+                *count += other_count;
+            }
         }
+    }
 
-        let mut activities: Vec<u8> = vec![0; blocks_per_day];
-        for i in 0..day_count {
-            reader.read_exact(&mut activities).unwrap_or_else(|_| panic!("unable to read day index {}", i));
-            let mut previous = activities[0];
-            for (block_idx, activity) in activities.iter().skip(1).enumerate() {
-                precursors[block_idx].add_change(previous, *activity);
-                previous = *activity;
+    /// removes the counts of another precursor from this one, such as days that were previously
+    /// merged in, never leaving a count below zero
+    pub fn subtract(&mut self, other: &Self) {
+        for (row, other_row) in self.counts.iter_mut().zip(&other.counts) {
+            for (count, other_count) in row.iter_mut().zip(other_row) {
+                *count = (*count - other_count).max(0.0);
             }
         }
+    }
+
+    /// multiplies every count by a factor, such as to weight one year's data against another
+    pub fn scale(&mut self, factor: f64) {
+        for count in self.counts.iter_mut().flatten() {
+            *count *= factor;
+        }
+    }
 
-        precursors
+    /// the total (possibly weighted) number of changes counted
+    pub fn total_count(&self) -> f64 {
+        self.counts.iter().flatten().sum()
     }
 
-    pub fn add_change(&mut self, from: u8, to: u8) {
-        if from < ActivityCategory::MAX_CODE as u8 && to < ActivityCategory::MAX_CODE as u8 {
-            self.counts[from as usize][to as usize] += 1;
+    pub fn add_weighted_change(&mut self, from: u8, to: u8, weight: f64) {
+        if (from as usize) < ActivityCategory::COUNT && (to as usize) < ActivityCategory::COUNT {
+            self.counts[from as usize][to as usize] += weight;
         }
     }
 
-    pub fn get_change_count(&self, from: usize, to: usize) -> u32 {
+    pub fn get_change_count(&self, from: usize, to: usize) -> f64 {
         self.counts[from][to]
     }
 }
@@ -66,7 +302,7 @@ impl BlockStateChangeMatrixPrecursor {
 pub struct BlockStateChangeMatrix {
     /// probabilities[i][j] - probabilities[i][j - 1] (or 0 if j == 0) is the probability that
     /// a change from activity i to activity j occurs, given that we are initially in activity i
-    probabilities: [[f64; ActivityCategory::MAX_CODE]; ActivityCategory::MAX_CODE]
+    probabilities: [[f64; ActivityCategory::COUNT]; ActivityCategory::COUNT]
 }
 
 impl BlockStateChangeMatrix {
@@ -74,31 +310,98 @@ impl BlockStateChangeMatrix {
     /// provide the number of blocks in the day 
     pub fn from_block_encoding(
         filename: &str
-    ) -> Vec<Box<Self>> {
-        BlockStateChangeMatrixPrecursor::from_block_encoding(filename)
-            .iter()
-        	.map(|p| Box::new(Self::from_precursor(p)))
+    ) -> Result<Vec<Box<Self>>> {
+        let precursors = BlockStateChangeMatrixPrecursor::from_block_encoding(filename)?;
+        Ok(Self::from_precursors(&precursors, Smoothing::Unsmoothed))
+    }
+
+    /// creates the state change matrices for every block of the day from a loaded activity block file
+    /// path is only used for error reporting
+    pub fn from_block_file(
+        file: &ActivityBlockFile,
+        path: &str,
+        options: &TrainingOptions
+    ) -> Result<Vec<Box<Self>>> {
+        let precursors = BlockStateChangeMatrixPrecursor::from_block_file(file, path, options)?;
+        Ok(Self::from_precursors(&precursors, options.smoothing))
+    }
+
+    /// creates the state change matrices of every block of the day from their precursors, which
+    /// may have been merged from several files
+    #[allow(clippy::vec_box)]
+    pub fn from_precursors(precursors: &[BlockStateChangeMatrixPrecursor], smoothing: Smoothing) -> Vec<Box<Self>> {
+        // the counts of every block of the day, only needed for the hierarchical prior
+        let mut pooled = BlockStateChangeMatrixPrecursor::new();
+        if let Smoothing::Hierarchical { .. } = smoothing {
+            for precursor in precursors {
+                pooled.merge(precursor);
+            }
+        }
+
+        precursors.iter()
+            .enumerate()
+            .map(|(block_idx, precursor)| {
+                let mut pseudo_counts = [[0.0; ActivityCategory::COUNT]; ActivityCategory::COUNT];
+
+                match smoothing {
+                    Smoothing::Unsmoothed => {}
+                    Smoothing::Additive { alpha } => {
+                        for row in pseudo_counts.iter_mut() {
+                            row[..ActivityCategory::VALID_COUNT].fill(alpha);
+                        }
+                    }
+                    Smoothing::Hierarchical { neighbours, neighbour_strength, pooled_strength } => {
+                        let first = block_idx.saturating_sub(neighbours);
+                        let last = (block_idx + neighbours).min(precursors.len() - 1);
+
+                        let mut neighbouring = BlockStateChangeMatrixPrecursor::new();
+                        for (neighbour_idx, neighbour) in precursors.iter().enumerate().take(last + 1).skip(first) {
+                            if neighbour_idx != block_idx {
+                                neighbouring.merge(neighbour);
+                            }
+                        }
+
+                        for (i, row) in pseudo_counts.iter_mut().enumerate() {
+                            add_prior(row, &neighbouring.counts[i], neighbour_strength);
+                            add_prior(row, &pooled.counts[i], pooled_strength);
+                        }
+                    }
+                }
+
+                Box::new(Self::from_counts(precursor, &pseudo_counts))
+            })
             .collect()
     }
 
-    fn from_precursor(precursor: &BlockStateChangeMatrixPrecursor) -> Self {
-        let mut probabilities = [[0.0; ActivityCategory::MAX_CODE]; ActivityCategory::MAX_CODE];
-        for i in 0..ActivityCategory::MAX_CODE {
-            let mut total_changes_from_i: u32 = 0;
-            for j in 0..ActivityCategory::MAX_CODE {
-                total_changes_from_i += precursor.get_change_count(i, j);
+    /// creates a state change matrix from the counted changes of a block and the pseudo-counts of
+    /// any prior added to them
+    fn from_counts(
+        precursor: &BlockStateChangeMatrixPrecursor,
+        pseudo_counts: &[[f64; ActivityCategory::COUNT]; ActivityCategory::COUNT]
+    ) -> Self {
+        let count = |i: usize, j: usize| precursor.get_change_count(i, j) + pseudo_counts[i][j];
+
+        let mut probabilities = [[0.0; ActivityCategory::COUNT]; ActivityCategory::COUNT];
+        for (i, row) in probabilities.iter_mut().enumerate() {
+            let mut total_changes_from_i = 0.0;
+            for j in 0..ActivityCategory::COUNT {
+                total_changes_from_i += count(i, j);
             }
 
             let mut cumulative_probability = 0.0;
-            if total_changes_from_i == 0 {
-                for j in 0..ActivityCategory::MAX_CODE {
-                    cumulative_probability += 1.0 / (ActivityCategory::MAX_CODE) as f64;
-                    probabilities[i][j] = cumulative_probability;
+            if total_changes_from_i == 0.0 {
+                // with no observations, transition uniformly to any activity that is not missing data
+                for cell in row.iter_mut().take(ActivityCategory::VALID_COUNT) {
+                    cumulative_probability += 1.0 / ActivityCategory::VALID_COUNT as f64;
+                    *cell = cumulative_probability;
+                }
+                for cell in row.iter_mut().skip(ActivityCategory::VALID_COUNT) {
+                    *cell = cumulative_probability;
                 }
             } else {
-                for j in 0..ActivityCategory::MAX_CODE {
-                    cumulative_probability += precursor.get_change_count(i, j) as f64 / total_changes_from_i as f64;
-                    probabilities[i][j] = cumulative_probability;
+                for (j, cell) in row.iter_mut().enumerate() {
+                    cumulative_probability += count(i, j) / total_changes_from_i;
+                    *cell = cumulative_probability;
                 }
             }
         }
@@ -106,14 +409,693 @@ impl BlockStateChangeMatrix {
     }
 
     /// gets a random activity to transition to, given the current activity
-    pub fn get_random_transition(&self, from: u8) -> u8 {
-        let rand: f64 = rand::random();
+    pub fn get_random_transition<R: Rng + ?Sized>(&self, from: u8, rng: &mut R) -> u8 {
+        let rand: f64 = rng.random();
         for (to, &cumulative_probability) in self.probabilities[from as usize].iter().enumerate() {
             if rand <= cumulative_probability {
                 return to as u8;
             }
         }
-        return (ActivityCategory::MAX_CODE - 1) as u8;
+
+        // rounding may leave the final cumulative probability slightly below 1, in which case the
+        // last activity with a non-zero probability is chosen
+        (0..ActivityCategory::COUNT as u8).rev()
+            .find(|&to| self.transition_probability(from, to) > 0.0)
+            .unwrap_or(0)
+    }
+
+    /// gets the distribution of activities in the next block, given the distribution in this block
+    pub fn propagate(&self, distribution: &[f64; ActivityCategory::COUNT]) -> [f64; ActivityCategory::COUNT] {
+        let mut next = [0.0; ActivityCategory::COUNT];
+        for (from, &p_from) in distribution.iter().enumerate() {
+            if p_from == 0.0 {
+                continue;
+            }
+            for (to, p_next) in next.iter_mut().enumerate() {
+                *p_next += p_from * self.transition_probability(from as u8, to as u8);
+            }
+        }
+        next
+    }
+
+    /// gets the probability of transitioning to an activity, given the current activity
+    pub fn transition_probability(&self, from: u8, to: u8) -> f64 {
+        let row = &self.probabilities[from as usize];
+        if to == 0 {
+            row[0]
+        } else {
+            row[to as usize] - row[to as usize - 1]
+        }
+    }
+}
+
+/// adds `strength` pseudo-observations to a row, distributed in proportion to the counts of another row
+/// rows with no counts add nothing
+fn add_prior(
+    pseudo_counts: &mut [f64; ActivityCategory::COUNT],
+    counts: &[f64; ActivityCategory::COUNT],
+    strength: f64
+) {
+    let total: f64 = counts.iter().sum();
+    if total > 0.0 {
+        for (pseudo_count, count) in pseudo_counts.iter_mut().zip(counts) {
+            *pseudo_count += strength * count / total;
+        }
+    }
+}
+
+/// a row of a transition matrix that was estimated from too few observations
+#[derive(Debug, Clone, Copy)]
+pub struct SparseRow {
+    /// the block the changes are from
+    pub block: usize,
+
+    /// the activity the changes are from
+    pub from: ActivityCategory,
+
+    /// the (possibly weighted) number of changes observed
+    pub observations: f64,
+}
+
+/// finds every row of the transition matrices trained on a file that is backed by fewer than
+/// min_observations (possibly weighted) observed changes, in order of block then activity
+/// rows from missing data are only reported under MissingDataPolicy::OwnState, and the last block
+/// is never reported, as there is no block following it
+pub fn sparse_rows(
+    file: &ActivityBlockFile,
+    path: &str,
+    options: &TrainingOptions,
+    min_observations: f64
+) -> Result<Vec<SparseRow>> {
+    let precursors = BlockStateChangeMatrixPrecursor::from_block_file(file, path, options)?;
+    let activities: Vec<ActivityCategory> = if options.missing_data == MissingDataPolicy::OwnState {
+        ActivityCategory::iter().collect()
+    } else {
+        ActivityCategory::valid_iter().collect()
+    };
+
+    let mut rows = Vec::new();
+    for (block, precursor) in precursors.iter().enumerate().take(precursors.len().saturating_sub(1)) {
+        for &from in &activities {
+            let observations: f64 = precursor.counts[from.into_code() as usize].iter().sum();
+            if observations < min_observations {
+                rows.push(SparseRow { block, from, observations });
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// checks that a file or model has as many blocks per day as there are blocks of BLOCK_DURATION in a day
+/// path is only used for error reporting
+pub(crate) fn check_block_count<const BLOCK_DURATION: u32>(blocks_per_day: usize, path: &str) -> Result<()> {
+    let block_count = Forecast::<BLOCK_DURATION>::block_count();
+    if blocks_per_day != block_count {
+        return Err(Error::HeaderMismatch {
+            path: path.to_string(),
+            field: "blocks per day",
+            expected: block_count as u64,
+            found: blocks_per_day as u64,
+        });
+    }
+    Ok(())
+}
+
+/// trains the transition matrices of every block of the day, checking they match BLOCK_DURATION
+#[allow(clippy::vec_box)]
+pub(crate) fn train_matrices<const BLOCK_DURATION: u32>(
+    file: &ActivityBlockFile,
+    path: &str,
+    options: &TrainingOptions
+) -> Result<Vec<Box<BlockStateChangeMatrix>>> {
+    let matrices = BlockStateChangeMatrix::from_block_file(file, path, options)?;
+    check_block_count::<BLOCK_DURATION>(matrices.len(), path)?;
+    Ok(matrices)
+}
+
+/// samples forecast_count paths through a chain of per-block transition matrices, starting from
+/// the last block of initial_conditions
+/// the certainty of each forecast is its path probability, normalized over every forecast returned
+#[allow(clippy::vec_box)]
+fn sample_forecasts<R: Rng + ?Sized, const BLOCK_DURATION: u32>(
+    matrices: &[Box<BlockStateChangeMatrix>],
+    initial_conditions: Rc<Vec<ActivityCategory>>,
+    forecast_count: usize,
+    rng: &mut R,
+) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+    let initial_block_count = initial_conditions.len();
+    assert!(initial_block_count > 0, "a markov forecast requires at least one initial block");
+
+    // the additional number of blocks to generate
+    let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_block_count;
+
+    // the generated paths, alongside the probability of each path occurring
+    let mut paths = Vec::with_capacity(forecast_count);
+    let mut total_probability = 0.0;
+
+    for _ in 0..forecast_count {
+        let mut forecast_data = Vec::with_capacity(additional_block_count);
+        let mut probability = 1.0;
+        let mut previous = initial_conditions[initial_block_count - 1].into_code();
+
+        for matrix in &matrices[(initial_block_count - 1)..(initial_block_count - 1 + additional_block_count)] {
+            let next = matrix.get_random_transition(previous, rng);
+            probability *= matrix.transition_probability(previous, next);
+            forecast_data.push(ActivityCategory::from_code(next).unwrap());
+            previous = next;
+        }
+
+        total_probability += probability;
+        paths.push((forecast_data, probability));
+    }
+
+    paths.into_iter()
+        .map(|(forecast_data, probability)| {
+            // normalize so that the certainties of all forecasts sum to 1
+            let certainty = if total_probability > 0.0 {
+                probability / total_probability
+            } else {
+                1.0 / forecast_count as f64
+            };
+
+            Box::new(Forecast::new(
+                initial_conditions.clone(),
+                forecast_data,
+                certainty.clamp(0.0, 1.0),
+            ))
+        })
+        .collect()
+}
+
+/// the natural log of the probability of a chain of per-block transition matrices continuing from
+/// the last block of initial_conditions as continuation
+pub fn path_log_likelihood(
+    matrices: &[Box<BlockStateChangeMatrix>],
+    initial_conditions: &[ActivityCategory],
+    continuation: &[ActivityCategory],
+) -> f64 {
+    let initial_block_count = initial_conditions.len();
+    assert!(initial_block_count > 0, "a markov forecast requires at least one initial block");
+
+    let mut previous = initial_conditions[initial_block_count - 1].into_code();
+    let mut log_likelihood = 0.0;
+    for (matrix, &activity) in matrices[(initial_block_count - 1)..].iter().zip(continuation) {
+        log_likelihood += matrix.transition_probability(previous, activity.into_code()).ln();
+        previous = activity.into_code();
+    }
+    log_likelihood
+}
+
+/// propagates the last block of initial_conditions through a chain of per-block transition
+/// matrices, giving the distribution of activities in every remaining block of the day
+pub fn propagate_distribution<const BLOCK_DURATION: u32>(
+    matrices: &[Box<BlockStateChangeMatrix>],
+    initial_conditions: Rc<Vec<ActivityCategory>>,
+) -> DistributionForecast<BLOCK_DURATION> {
+    let initial_block_count = initial_conditions.len();
+    assert!(initial_block_count > 0, "a markov forecast requires at least one initial block");
+
+    let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_block_count;
+
+    let mut distribution = [0.0; ActivityCategory::COUNT];
+    distribution[initial_conditions[initial_block_count - 1].into_code() as usize] = 1.0;
+
+    let mut distributions = Vec::with_capacity(additional_block_count);
+    for matrix in &matrices[(initial_block_count - 1)..(initial_block_count - 1 + additional_block_count)] {
+        distribution = matrix.propagate(&distribution);
+        distributions.push(distribution);
+    }
+
+    DistributionForecast::new(initial_conditions, distributions)
+}
+
+/// forecasts days by sampling paths from a chain of per-block transition matrices
+pub struct MarkovForecaster<R: Rng, const BLOCK_DURATION: u32> {
+    /// matrices[i] gives the transitions from block i to block i + 1
+    #[allow(clippy::vec_box)]
+    matrices: Vec<Box<BlockStateChangeMatrix>>,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+
+    /// the rng used to sample transitions
+    rng_cell: RefCell<R>,
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> MarkovForecaster<R, BLOCK_DURATION> {
+    /// creates a new MarkovForecaster from the transition matrices of each block of the day,
+    /// consuming the rng used to sample transitions
+    pub fn new(matrices: Vec<Box<BlockStateChangeMatrix>>, rng: R) -> Self {
+        assert!(
+            matrices.len() + 1 >= Forecast::<BLOCK_DURATION>::block_count(),
+            "a forecaster with block_duration {} requires at least {} transition matrices",
+            BLOCK_DURATION, Forecast::<BLOCK_DURATION>::block_count() - 1
+        );
+
+        Self {
+            matrices,
+            day_start: 0,
+            rng_cell: RefCell::new(rng),
+        }
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// creates a new MarkovForecaster trained on an activity block file
+    /// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    pub fn from_block_encoding(filename: &str, rng: R) -> Result<Self> {
+        Self::from_block_file(filename, &TrainingOptions::default(), rng)
+    }
+
+    /// creates a new MarkovForecaster trained on the activity block file at the given path
+    pub fn from_block_file(path: &str, options: &TrainingOptions, rng: R) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options, rng)
+    }
+
+    /// creates a new MarkovForecaster trained on a loaded activity block file
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions, rng: R) -> Result<Self> {
+        let matrices = train_matrices::<BLOCK_DURATION>(file, path, options)?;
+        Ok(Self::new(matrices, rng).with_day_start(file.header().day_start))
+    }
+
+    /// creates a new MarkovForecaster from a trained model
+    /// the model must have as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    pub fn from_model(model: &MarkovModel, rng: R) -> Self {
+        Self::new(model.matrices(), rng).with_day_start(model.day_start())
+    }
+
+    /// creates a new MarkovForecaster from the model file at the given path
+    pub fn load_model(path: &str, rng: R) -> Result<Self> {
+        let model = MarkovModel::load(path)?;
+        let matrices = model.matrices();
+        check_block_count::<BLOCK_DURATION>(matrices.len(), path)?;
+        Ok(Self::new(matrices, rng).with_day_start(model.day_start()))
+    }
+
+    /// the transition matrices of each block of the day
+    pub fn matrices(&self) -> &[Box<BlockStateChangeMatrix>] {
+        &self.matrices
+    }
+
+    /// gets the rng from the forecaster, destroying it
+    pub fn rng(self) -> R {
+        self.rng_cell.into_inner()
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for MarkovForecaster<R, BLOCK_DURATION> {
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        sample_forecasts(&self.matrices, initial_conditions, forecast_count, &mut *self.rng_cell.borrow_mut())
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+
+    fn log_likelihood(
+        &self,
+        initial_conditions: &[ActivityCategory],
+        continuation: &[ActivityCategory],
+    ) -> Option<f64> {
+        Some(path_log_likelihood(&self.matrices, initial_conditions, continuation))
+    }
+}
+
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for MarkovForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for MarkovForecaster<R, BLOCK_DURATION> {
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        propagate_distribution(&self.matrices, initial_conditions)
+    }
+}
+
+/// a Markov model trained on the days of a single demographic group
+struct Stratum {
+    filter: DemographicFilter,
+
+    #[allow(clippy::vec_box)]
+    matrices: Vec<Box<BlockStateChangeMatrix>>,
+}
+
+/// forecasts days with the model of the most specific demographic group matching a profile,
+/// falling back to a model trained on every day when no group with enough days matches
+pub struct StratifiedMarkovForecaster<R: Rng, const BLOCK_DURATION: u32> {
+    /// the model trained on every day
+    #[allow(clippy::vec_box)]
+    pooled: Vec<Box<BlockStateChangeMatrix>>,
+
+    /// the models of every group with enough days to be used
+    strata: Vec<Stratum>,
+
+    /// the demographics of the person whose day is being forecast
+    profile: Option<Demographics>,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+
+    /// the rng used to sample transitions
+    rng_cell: RefCell<R>,
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> StratifiedMarkovForecaster<R, BLOCK_DURATION> {
+    /// trains a pooled model and a model for every given group on the activity block file at the given path
+    /// groups with fewer than min_days days are discarded in favor of the pooled model
+    pub fn from_block_file(
+        path: &str,
+        options: &TrainingOptions,
+        groups: &[DemographicFilter],
+        min_days: usize,
+        rng: R,
+    ) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options, groups, min_days, rng)
+    }
+
+    /// trains a pooled model and a model for every given group on a loaded activity block file
+    /// each group is trained on the days matching both the group and the filter of the options
+    /// groups with fewer than min_days days are discarded in favor of the pooled model
+    /// path is only used for error reporting
+    pub fn train(
+        file: &ActivityBlockFile,
+        path: &str,
+        options: &TrainingOptions,
+        groups: &[DemographicFilter],
+        min_days: usize,
+        rng: R,
+    ) -> Result<Self> {
+        let pooled = train_matrices::<BLOCK_DURATION>(file, path, options)?;
+
+        // a group contradicting the filter of the training options has no days to train on
+        let combined: Vec<_> = groups.iter()
+            .filter_map(|filter| Some((*filter, filter.intersect(&options.filter)?)))
+            .collect();
+
+        // the days of every group are counted in a single pass, which needs no metadata if there
+        // are no groups to count
+        let mut day_counts = vec![0; combined.len()];
+        if !combined.is_empty() {
+            for day_idx in 0..file.day_count() {
+                let metadata = file.day_metadata(day_idx)
+                    .ok_or_else(|| Error::MissingMetadata { path: path.to_string() })?;
+                for ((_, filter), day_count) in combined.iter().zip(&mut day_counts) {
+                    if filter.matches(&metadata.demographics) {
+                        *day_count += 1;
+                    }
+                }
+            }
+        }
+
+        let mut strata = Vec::with_capacity(combined.len());
+        for ((group, filter), day_count) in combined.into_iter().zip(day_counts) {
+            if day_count >= min_days {
+                strata.push(Stratum {
+                    filter: group,
+                    matrices: train_matrices::<BLOCK_DURATION>(file, path, &options.with_filter(filter))?,
+                });
+            }
+        }
+
+        Ok(Self {
+            pooled,
+            strata,
+            profile: None,
+            day_start: file.header().day_start,
+            rng_cell: RefCell::new(rng),
+        })
+    }
+
+    /// sets the demographics of the person whose day is being forecast
+    /// with no profile, the pooled model is always used
+    pub fn set_profile(&mut self, profile: Option<Demographics>) {
+        self.profile = profile;
+    }
+
+    /// the demographics of the person whose day is being forecast
+    pub fn profile(&self) -> Option<&Demographics> {
+        self.profile.as_ref()
+    }
+
+    /// the groups that had enough days to be trained
+    pub fn groups(&self) -> impl Iterator<Item = &DemographicFilter> {
+        self.strata.iter().map(|s| &s.filter)
+    }
+
+    /// the group whose model is used for the current profile, or None if the pooled model is used
+    pub fn selected_group(&self) -> Option<&DemographicFilter> {
+        self.selected_stratum().map(|s| &s.filter)
+    }
+
+    /// the most specific trained group matching the profile, using the earliest group to break ties
+    fn selected_stratum(&self) -> Option<&Stratum> {
+        let profile = self.profile.as_ref()?;
+
+        let mut selected: Option<&Stratum> = None;
+        for stratum in self.strata.iter().filter(|s| s.filter.matches(profile)) {
+            if selected.is_none_or(|s| stratum.filter.specificity() > s.filter.specificity()) {
+                selected = Some(stratum);
+            }
+        }
+        selected
+    }
+
+    /// the transition matrices used for the current profile
+    pub fn selected_matrices(&self) -> &[Box<BlockStateChangeMatrix>] {
+        match self.selected_stratum() {
+            Some(stratum) => &stratum.matrices,
+            None => &self.pooled,
+        }
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for StratifiedMarkovForecaster<R, BLOCK_DURATION> {
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        sample_forecasts(
+            self.selected_matrices(),
+            initial_conditions,
+            forecast_count,
+            &mut *self.rng_cell.borrow_mut()
+        )
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+
+    fn log_likelihood(
+        &self,
+        initial_conditions: &[ActivityCategory],
+        continuation: &[ActivityCategory],
+    ) -> Option<f64> {
+        Some(path_log_likelihood(self.selected_matrices(), initial_conditions, continuation))
+    }
+}
+
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for StratifiedMarkovForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for StratifiedMarkovForecaster<R, BLOCK_DURATION> {
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        propagate_distribution(self.selected_matrices(), initial_conditions)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity_block::test_block_file;
+    use rand::rngs::StdRng;
+
+    const TOLERANCE: f64 = 1e-12;
+
+    /// precursors of a chain with the given (block, from, to, count) changes
+    fn precursors(blocks: usize, changes: &[(usize, u8, u8, f64)]) -> Vec<BlockStateChangeMatrixPrecursor> {
+        let mut precursors = BlockStateChangeMatrixPrecursor::for_blocks(blocks);
+        for &(block, from, to, count) in changes {
+            precursors[block].add_weighted_change(from, to, count);
+        }
+        precursors
+    }
+
+    fn assert_probability(matrix: &BlockStateChangeMatrix, from: u8, to: u8, expected: f64) {
+        let probability = matrix.transition_probability(from, to);
+        assert!((probability - expected).abs() < TOLERANCE, "p({from} -> {to}) = {probability}, expected {expected}");
+    }
+
+    #[test]
+    fn additive_smoothing_adds_alpha_to_every_valid_activity() {
+        let precursors = precursors(2, &[(0, 0, 0, 3.0), (0, 0, 1, 1.0)]);
+        let matrices = BlockStateChangeMatrix::from_precursors(&precursors, Smoothing::Additive { alpha: 1.0 });
+
+        // 4 observations and 20 pseudo-observations
+        let total = 4.0 + ActivityCategory::VALID_COUNT as f64;
+        assert_probability(&matrices[0], 0, 0, 4.0 / total);
+        assert_probability(&matrices[0], 0, 1, 2.0 / total);
+        assert_probability(&matrices[0], 0, 5, 1.0 / total);
+        assert_probability(&matrices[0], 0, ActivityCategory::MissingData.into_code(), 0.0);
+    }
+
+    #[test]
+    fn hierarchical_smoothing_borrows_from_neighbouring_blocks() {
+        let precursors = precursors(3, &[(0, 0, 0, 1.0), (1, 0, 1, 1.0), (2, 0, 2, 1.0)]);
+        let smoothing = Smoothing::Hierarchical { neighbours: 1, neighbour_strength: 2.0, pooled_strength: 0.0 };
+        let matrices = BlockStateChangeMatrix::from_precursors(&precursors, smoothing);
+
+        // the first and last blocks only have a neighbour on one side
+        assert_probability(&matrices[0], 0, 0, 1.0 / 3.0);
+        assert_probability(&matrices[0], 0, 1, 2.0 / 3.0);
+        assert_probability(&matrices[2], 0, 2, 1.0 / 3.0);
+        assert_probability(&matrices[2], 0, 1, 2.0 / 3.0);
+
+        // the middle block splits its prior between the blocks either side
+        for to in 0..3 {
+            assert_probability(&matrices[1], 0, to, 1.0 / 3.0);
+        }
+    }
+
+    #[test]
+    fn hierarchical_smoothing_fills_unseen_rows_from_the_pooled_counts() {
+        let precursors = precursors(3, &[(0, 0, 0, 1.0), (1, 1, 3, 1.0)]);
+        let smoothing = Smoothing::Hierarchical { neighbours: 0, neighbour_strength: 0.0, pooled_strength: 1.0 };
+        let matrices = BlockStateChangeMatrix::from_precursors(&precursors, smoothing);
+
+        // activity 1 is never left in block 0, so unsmoothed it would transition uniformly
+        assert_probability(&matrices[0], 1, 3, 1.0);
+        assert_probability(&BlockStateChangeMatrix::from_precursors(&precursors, Smoothing::Unsmoothed)[0], 1, 3, 1.0 / 20.0);
+    }
+
+    #[test]
+    fn sparse_rows_are_exactly_those_under_the_threshold() {
+        let file = test_block_file(&[[0, 0, 1, 1, 2, 0], [0, 0, 1, 2, 2, 0]]);
+        let rows = sparse_rows(&file, "test", &TrainingOptions::default(), 2.0).unwrap();
+
+        // every block but the last has a row for each valid activity, of which the rows of one
+        // activity have two observations, except in block 3 where 1 and 2 are each left once
+        assert_eq!(rows.len(), 4 * (ActivityCategory::VALID_COUNT - 1) + ActivityCategory::VALID_COUNT);
+        let observed: Vec<_> = rows.iter()
+            .filter(|row| row.observations > 0.0)
+            .map(|row| (row.block, row.from.into_code(), row.observations))
+            .collect();
+        assert_eq!(observed, [(3, 1, 1.0), (3, 2, 1.0)]);
+        assert!(rows.iter().all(|row| row.block < 5 && row.from != ActivityCategory::MissingData));
+        assert!(rows.windows(2).all(|pair| (pair[0].block, pair[0].from.into_code()) < (pair[1].block, pair[1].from.into_code())));
+
+        assert!(sparse_rows(&file, "test", &TrainingOptions::default(), 0.0).unwrap().is_empty());
+    }
+
+    /// a day with a hole of missing data in blocks 1 and 2
+    const HOLE: [u8; 6] = [0, 20, 20, 1, 1, 1];
+
+    fn counted(missing_data: MissingDataPolicy) -> Vec<BlockStateChangeMatrixPrecursor> {
+        let options = TrainingOptions::default().with_missing_data(missing_data);
+        BlockStateChangeMatrixPrecursor::from_block_file(&test_block_file(&[HOLE]), "test", &options).unwrap()
+    }
+
+    #[test]
+    fn skip_does_not_count_changes_involving_missing_data() {
+        let precursors = counted(MissingDataPolicy::Skip);
+
+        assert_eq!(precursors[0].total_count(), 0.0);
+        assert_eq!(precursors[1].total_count(), 0.0);
+        assert_eq!(precursors[2].total_count(), 0.0);
+        assert_eq!(precursors[3].get_change_count(1, 1), 1.0);
+        assert_eq!(precursors[4].get_change_count(1, 1), 1.0);
+    }
+
+    #[test]
+    fn interpolate_counts_changes_through_the_filled_hole() {
+        let precursors = counted(MissingDataPolicy::Interpolate);
+
+        // the hole is filled with 0 then 1, the nearest recorded activity to each block
+        assert_eq!(precursors[0].get_change_count(0, 0), 1.0);
+        assert_eq!(precursors[1].get_change_count(0, 1), 1.0);
+        assert_eq!(precursors[2].get_change_count(1, 1), 1.0);
+        let total: f64 = precursors.iter().map(BlockStateChangeMatrixPrecursor::total_count).sum();
+        assert_eq!(total, 5.0);
+    }
+
+    #[test]
+    fn own_state_counts_changes_into_and_out_of_missing_data() {
+        let precursors = counted(MissingDataPolicy::OwnState);
+
+        assert_eq!(precursors[0].get_change_count(0, 20), 1.0);
+        assert_eq!(precursors[1].get_change_count(20, 20), 1.0);
+        assert_eq!(precursors[2].get_change_count(20, 1), 1.0);
+        assert_eq!(precursors[3].get_change_count(1, 1), 1.0);
+    }
+
+    /// days with several ways of continuing from each block
+    const VARIED: [[u8; 6]; 4] = [[0, 0, 1, 1, 2, 0], [0, 1, 1, 2, 2, 0], [0, 0, 2, 1, 0, 0], [1, 0, 1, 2, 0, 1]];
+
+    fn forecaster(days: &[[u8; 6]], seed: u64) -> MarkovForecaster<StdRng, 240> {
+        MarkovForecaster::train(&test_block_file(days), "test", &TrainingOptions::default(), StdRng::seed_from_u64(seed)).unwrap()
+    }
+
+    fn activities(codes: &[u8]) -> Rc<Vec<ActivityCategory>> {
+        Rc::new(codes.iter().map(|&code| ActivityCategory::from_code(code).unwrap()).collect())
+    }
+
+    #[test]
+    fn forecasts_complete_the_day_with_certainties_summing_to_one() {
+        let forecaster = forecaster(&VARIED, 0);
+
+        for prefix in [1, 2, 5] {
+            let forecasts = forecaster.forecast(activities(&VARIED[0][..prefix]), 10);
+            assert_eq!(forecasts.len(), 10);
+            assert!(forecasts.iter().all(|forecast| forecast.forecast_data().len() == 6 - prefix));
+
+            let total: f64 = forecasts.iter().map(|forecast| forecast.certainty()).sum();
+            assert!((total - 1.0).abs() < TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn a_deterministic_chain_reproduces_its_path() {
+        let forecaster = forecaster(&[[0, 0, 1, 1, 2, 0]], 0);
+
+        for forecast in forecaster.forecast(activities(&[0, 0]), 3) {
+            assert_eq!(forecast.forecast_data(), activities(&[1, 1, 2, 0]).as_slice());
+            assert!((forecast.certainty() - 1.0 / 3.0).abs() < TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn seeded_forecasts_are_reproducible() {
+        let forecast = |forecaster: &MarkovForecaster<StdRng, 240>| -> Vec<Vec<ActivityCategory>> {
+            forecaster.forecast(activities(&[0]), 20).iter().map(|forecast| forecast.forecast_data().to_vec()).collect()
+        };
+
+        let first = forecaster(&VARIED, 7);
+        let expected = forecast(&first);
+        assert_eq!(forecast(&forecaster(&VARIED, 7)), expected);
+
+        // reseeding restarts the sampling from the same point
+        first.reseed(7);
+        assert_eq!(forecast(&first), expected);
+        assert_ne!(forecast(&first), expected);
+    }
+}
@@ -0,0 +1,468 @@
+//! Reading and writing of trained Markov models in the model (.amkv) format described in
+//! data_formats.md, so models can be shipped and loaded without rescanning their training data.
+
+use std::{fs::File, io::{BufReader, BufWriter, Read, Write}};
+
+use crate::{activity_block::ActivityBlockFile, demographics::{DemographicFilter, IncomeBand}, encodings::ActivityCategory, error::{Error, Result}, markov_chain::{BlockStateChangeMatrix, BlockStateChangeMatrixPrecursor, MissingDataPolicy, Smoothing, TrainingOptions, Weighting}};
+
+/// the raw transition counts of every block of the day, alongside how they were trained
+pub struct MarkovModel {
+    /// the duration of each block in minutes
+    block_duration: u32,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+
+    /// the options the counts were trained with, including the smoothing applied to them
+    options: TrainingOptions,
+
+    /// the FNV-1a hash of the activity block file the counts were trained on
+    source_hash: u64,
+
+    /// the transition counts of each block of the day
+    precursors: Vec<BlockStateChangeMatrixPrecursor>,
+}
+
+impl MarkovModel {
+    /// the bytes identifying a model file
+    pub const MAGIC: [u8; 4] = *b"AMKV";
+
+    /// the latest version of the format
+    pub const VERSION: u16 = 1;
+
+    /// the size of the header in bytes
+    pub const HEADER_SIZE: usize = 52;
+
+    /// trains a model on a loaded activity block file
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, options: &TrainingOptions) -> Result<Self> {
+        Ok(Self {
+            block_duration: file.header().block_duration(),
+            day_start: file.header().day_start,
+            options: *options,
+            source_hash: file.source_hash(),
+            precursors: BlockStateChangeMatrixPrecursor::from_block_file(file, path, options)?,
+        })
+    }
+
+    /// trains a model on the activity block file at the given path
+    pub fn from_block_file(path: &str, options: &TrainingOptions) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, options)
+    }
+
+    /// the duration of each block in minutes
+    pub fn block_duration(&self) -> u32 {
+        self.block_duration
+    }
+
+    /// the number of blocks in each day
+    pub fn blocks_per_day(&self) -> usize {
+        self.precursors.len()
+    }
+
+    /// the time the first block of each day begins, in minutes after midnight
+    pub fn day_start(&self) -> u32 {
+        self.day_start
+    }
+
+    /// the options the model was trained with
+    pub fn options(&self) -> &TrainingOptions {
+        &self.options
+    }
+
+    /// the FNV-1a hash of the activity block file the model was trained on, which can be compared
+    /// with ActivityBlockFile::source_hash to check a model is up to date
+    pub fn source_hash(&self) -> u64 {
+        self.source_hash
+    }
+
+    /// the transition counts of each block of the day
+    pub fn precursors(&self) -> &[BlockStateChangeMatrixPrecursor] {
+        &self.precursors
+    }
+
+    /// adds the counts of another model to this one, such as a newly released survey year
+    /// the source hash of the result is the wrapping sum of the hashes of both models, so it does
+    /// not depend on the order models are merged in
+    /// the smoothing of this model is kept, but every other training option must match
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        self.check_compatible(other)?;
+        for (precursor, other_precursor) in self.precursors.iter_mut().zip(&other.precursors) {
+            precursor.merge(other_precursor);
+        }
+        self.source_hash = self.source_hash.wrapping_add(other.source_hash);
+        Ok(())
+    }
+
+    /// removes the counts of another model that were previously merged into this one
+    /// the hash of the other model is subtracted from the source hash, so subtracting a merged
+    /// model restores the source hash from before the merge
+    pub fn subtract(&mut self, other: &Self) -> Result<()> {
+        self.check_compatible(other)?;
+        for (precursor, other_precursor) in self.precursors.iter_mut().zip(&other.precursors) {
+            precursor.subtract(other_precursor);
+        }
+        self.source_hash = self.source_hash.wrapping_sub(other.source_hash);
+        Ok(())
+    }
+
+    /// multiplies every count by a factor, such as to weight this model against another before merging
+    pub fn scale(&mut self, factor: f64) {
+        for precursor in self.precursors.iter_mut() {
+            precursor.scale(factor);
+        }
+    }
+
+    /// checks another model's counts line up block for block with this model's and were trained
+    /// the same way, apart from the smoothing, which is only applied when building matrices
+    fn check_compatible(&self, other: &Self) -> Result<()> {
+        if self.block_duration != other.block_duration {
+            return Err(Error::IncompatibleModels {
+                field: "block durations",
+                expected: self.block_duration as u64,
+                found: other.block_duration as u64,
+            });
+        }
+
+        if self.day_start != other.day_start {
+            return Err(Error::IncompatibleModels {
+                field: "day starts",
+                expected: self.day_start as u64,
+                found: other.day_start as u64,
+            });
+        }
+
+        let field = if self.options.weighting != other.options.weighting {
+            "weightings"
+        } else if self.options.missing_data != other.options.missing_data {
+            "missing data policies"
+        } else if self.options.filter != other.options.filter {
+            "demographic filters"
+        } else {
+            return Ok(());
+        };
+        Err(Error::IncompatibleTrainingOptions { field })
+    }
+
+    /// changes the smoothing applied when building matrices, which does not require retraining
+    pub fn with_smoothing(mut self, smoothing: Smoothing) -> Self {
+        self.options.smoothing = smoothing;
+        self
+    }
+
+    /// builds the transition matrices of every block of the day from the counts
+    #[allow(clippy::vec_box)]
+    pub fn matrices(&self) -> Vec<Box<BlockStateChangeMatrix>> {
+        BlockStateChangeMatrix::from_precursors(&self.precursors, self.options.smoothing)
+    }
+
+    /// reads a model file
+    pub fn load(path: &str) -> Result<Self> {
+        let reader = BufReader::new(File::open(path).map_err(Error::io(path))?);
+        Self::read(reader, path)
+    }
+
+    /// reads a model from the start of a model file
+    /// path is only used for error reporting
+    pub fn read<R: Read>(mut reader: R, path: &str) -> Result<Self> {
+        let magic: [u8; 4] = read_bytes(&mut reader, path)?;
+        if magic != Self::MAGIC {
+            return Err(Error::InvalidModelFile { path: path.to_string(), reason: "missing magic bytes" });
+        }
+
+        let version = u16::from_le_bytes(read_bytes(&mut reader, path)?);
+        if version != Self::VERSION {
+            return Err(Error::HeaderMismatch {
+                path: path.to_string(),
+                field: "version",
+                expected: Self::VERSION as u64,
+                found: version as u64,
+            });
+        }
+
+        let category_scheme = u16::from_le_bytes(read_bytes(&mut reader, path)?);
+        if category_scheme != ActivityCategory::CATEGORY_SCHEME {
+            return Err(Error::HeaderMismatch {
+                path: path.to_string(),
+                field: "category scheme",
+                expected: ActivityCategory::CATEGORY_SCHEME as u64,
+                found: category_scheme as u64,
+            });
+        }
+
+        let block_duration = u32::from_le_bytes(read_bytes(&mut reader, path)?);
+        if block_duration == 0 || 24 * 60 % block_duration != 0 {
+            return Err(Error::InvalidBlockDuration(block_duration));
+        }
+
+        let day_start = u32::from_le_bytes(read_bytes(&mut reader, path)?);
+        if day_start >= 24 * 60 {
+            return Err(Error::InvalidDayStart(day_start));
+        }
+
+        let [weighting, missing_data, student, income_band, owns_home, smoothing, _, _] = read_bytes(&mut reader, path)?;
+        let neighbours = u32::from_le_bytes(read_bytes(&mut reader, path)?);
+        let first_parameter = f64::from_le_bytes(read_bytes(&mut reader, path)?);
+        let second_parameter = f64::from_le_bytes(read_bytes(&mut reader, path)?);
+        let source_hash = u64::from_le_bytes(read_bytes(&mut reader, path)?);
+
+        let invalid_option = || Error::InvalidModelFile { path: path.to_string(), reason: "unknown training option" };
+        let options = TrainingOptions {
+            weighting: match weighting {
+                0 => Weighting::Unweighted,
+                1 => Weighting::SurveyWeights,
+                _ => return Err(invalid_option()),
+            },
+            missing_data: match missing_data {
+                0 => MissingDataPolicy::Skip,
+                1 => MissingDataPolicy::Interpolate,
+                2 => MissingDataPolicy::OwnState,
+                _ => return Err(invalid_option()),
+            },
+            filter: DemographicFilter {
+                student: decode_flag(student).ok_or_else(invalid_option)?,
+                income_band: match income_band {
+                    0 => None,
+                    1 => Some(IncomeBand::Low),
+                    2 => Some(IncomeBand::Middle),
+                    3 => Some(IncomeBand::High),
+                    _ => return Err(invalid_option()),
+                },
+                owns_home: decode_flag(owns_home).ok_or_else(invalid_option)?,
+            },
+            smoothing: match smoothing {
+                0 => Smoothing::Unsmoothed,
+                1 => Smoothing::Additive { alpha: first_parameter },
+                2 => Smoothing::Hierarchical {
+                    neighbours: neighbours as usize,
+                    neighbour_strength: first_parameter,
+                    pooled_strength: second_parameter,
+                },
+                _ => return Err(invalid_option()),
+            },
+        };
+
+        let mut precursors = BlockStateChangeMatrixPrecursor::for_blocks((24 * 60 / block_duration) as usize);
+        for precursor in precursors.iter_mut() {
+            let entry_count = u16::from_le_bytes(read_bytes(&mut reader, path)?);
+            for _ in 0..entry_count {
+                let [from, to] = read_bytes(&mut reader, path)?;
+                let count = f64::from_le_bytes(read_bytes(&mut reader, path)?);
+                if from as usize >= ActivityCategory::COUNT || to as usize >= ActivityCategory::COUNT {
+                    return Err(Error::InvalidModelFile { path: path.to_string(), reason: "invalid activity code" });
+                }
+                precursor.add_weighted_change(from, to, count);
+            }
+        }
+
+        Ok(Self { block_duration, day_start, options, source_hash, precursors })
+    }
+
+    /// writes the model to the given path
+    pub fn save(&self, path: &str) -> Result<()> {
+        let writer = BufWriter::new(File::create(path).map_err(Error::io(path))?);
+        self.write(writer, path)?;
+        Ok(())
+    }
+
+    /// writes the model to a writer, returning the writer
+    /// path is only used for error reporting
+    pub fn write<W: Write>(&self, mut writer: W, path: &str) -> Result<W> {
+        let (smoothing, neighbours, first_parameter, second_parameter) = match self.options.smoothing {
+            Smoothing::Unsmoothed => (0, 0, 0.0, 0.0),
+            Smoothing::Additive { alpha } => (1, 0, alpha, 0.0),
+            Smoothing::Hierarchical { neighbours, neighbour_strength, pooled_strength } => {
+                (2, neighbours as u32, neighbour_strength, pooled_strength)
+            }
+        };
+        let filter = &self.options.filter;
+
+        let mut bytes = Vec::with_capacity(Self::HEADER_SIZE);
+        bytes.extend_from_slice(&Self::MAGIC);
+        bytes.extend_from_slice(&Self::VERSION.to_le_bytes());
+        bytes.extend_from_slice(&ActivityCategory::CATEGORY_SCHEME.to_le_bytes());
+        bytes.extend_from_slice(&self.block_duration.to_le_bytes());
+        bytes.extend_from_slice(&self.day_start.to_le_bytes());
+        bytes.extend_from_slice(&[
+            match self.options.weighting {
+                Weighting::Unweighted => 0,
+                Weighting::SurveyWeights => 1,
+            },
+            match self.options.missing_data {
+                MissingDataPolicy::Skip => 0,
+                MissingDataPolicy::Interpolate => 1,
+                MissingDataPolicy::OwnState => 2,
+            },
+            encode_flag(filter.student),
+            match filter.income_band {
+                None => 0,
+                Some(IncomeBand::Low) => 1,
+                Some(IncomeBand::Middle) => 2,
+                Some(IncomeBand::High) => 3,
+            },
+            encode_flag(filter.owns_home),
+            smoothing,
+            0,
+            0,
+        ]);
+        bytes.extend_from_slice(&neighbours.to_le_bytes());
+        bytes.extend_from_slice(&first_parameter.to_le_bytes());
+        bytes.extend_from_slice(&second_parameter.to_le_bytes());
+        bytes.extend_from_slice(&self.source_hash.to_le_bytes());
+
+        // only changes that were observed are stored
+        for precursor in &self.precursors {
+            let mut entries = Vec::new();
+            for from in 0..ActivityCategory::COUNT {
+                for to in 0..ActivityCategory::COUNT {
+                    let count = precursor.get_change_count(from, to);
+                    if count != 0.0 {
+                        entries.push((from as u8, to as u8, count));
+                    }
+                }
+            }
+
+            bytes.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+            for (from, to, count) in entries {
+                bytes.extend_from_slice(&[from, to]);
+                bytes.extend_from_slice(&count.to_le_bytes());
+            }
+        }
+
+        writer.write_all(&bytes).map_err(Error::io(path))?;
+        writer.flush().map_err(Error::io(path))?;
+        Ok(writer)
+    }
+}
+
+/// encodes an optional constraint on a characteristic as 0 for none, 1 for false and 2 for true
+fn encode_flag(flag: Option<bool>) -> u8 {
+    match flag {
+        None => 0,
+        Some(false) => 1,
+        Some(true) => 2,
+    }
+}
+
+/// decodes an optional constraint encoded by encode_flag, or None if the byte is not a valid encoding
+fn decode_flag(byte: u8) -> Option<Option<bool>> {
+    match byte {
+        0 => Some(None),
+        1 => Some(Some(false)),
+        2 => Some(Some(true)),
+        _ => None,
+    }
+}
+
+/// reads a fixed number of bytes, treating an early end of file as truncation
+fn read_bytes<R: Read, const N: usize>(reader: &mut R, path: &str) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::TruncatedModelFile { path: path.to_string() },
+        _ => Error::Io { path: path.to_string(), source: e },
+    })?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity_block::test_block_file;
+
+    fn model(days: &[[u8; 6]], options: &TrainingOptions) -> MarkovModel {
+        MarkovModel::train(&test_block_file(days), "test", options).unwrap()
+    }
+
+    /// the counts of every change in every block of a model
+    fn counts(model: &MarkovModel) -> Vec<f64> {
+        model.precursors().iter()
+            .flat_map(|precursor| (0..ActivityCategory::COUNT)
+                .flat_map(move |from| (0..ActivityCategory::COUNT).map(move |to| precursor.get_change_count(from, to))))
+            .collect()
+    }
+
+    #[test]
+    fn models_round_trip() {
+        let options = TrainingOptions::default()
+            .with_missing_data(MissingDataPolicy::OwnState)
+            .with_filter(DemographicFilter::all().with_income_band(IncomeBand::Middle).with_student(false))
+            .with_smoothing(Smoothing::Hierarchical { neighbours: 2, neighbour_strength: 0.5, pooled_strength: 1.5 });
+        let original = MarkovModel {
+            options,
+            ..model(&[[0, 0, 1, 1, 2, 0], [0, 3, 3, 20, 2, 0]], &TrainingOptions::default())
+        };
+
+        let bytes = original.write(Vec::new(), "test").unwrap();
+        let read = MarkovModel::read(bytes.as_slice(), "test").unwrap();
+
+        assert_eq!(read.block_duration(), original.block_duration());
+        assert_eq!(read.day_start(), original.day_start());
+        assert_eq!(read.source_hash(), original.source_hash());
+        assert_eq!(read.options().weighting, options.weighting);
+        assert_eq!(read.options().missing_data, options.missing_data);
+        assert_eq!(read.options().filter, options.filter);
+        assert_eq!(read.options().smoothing, options.smoothing);
+        assert_eq!(counts(&read), counts(&original));
+    }
+
+    #[test]
+    fn truncated_models_are_rejected() {
+        let bytes = model(&[[0, 0, 1, 1, 2, 0]], &TrainingOptions::default()).write(Vec::new(), "test").unwrap();
+
+        for len in [0, MarkovModel::HEADER_SIZE - 1, MarkovModel::HEADER_SIZE + 1, bytes.len() - 1] {
+            let result = MarkovModel::read(&bytes[..len], "test");
+            assert!(matches!(result, Err(Error::TruncatedModelFile { .. })), "a model truncated to {len} bytes was read");
+        }
+    }
+
+    #[test]
+    fn models_without_magic_bytes_are_rejected() {
+        let mut bytes = model(&[[0; 6]], &TrainingOptions::default()).write(Vec::new(), "test").unwrap();
+        bytes[0] = b'X';
+
+        let result = MarkovModel::read(bytes.as_slice(), "test");
+        assert!(matches!(result, Err(Error::InvalidModelFile { .. })));
+    }
+
+    #[test]
+    fn merging_then_subtracting_restores_the_model() {
+        let mut pooled = model(&[[0, 0, 1, 1, 2, 0], [0, 3, 3, 3, 2, 0]], &TrainingOptions::default());
+        let year = model(&[[0, 1, 1, 1, 2, 0]], &TrainingOptions::default());
+        let (original_counts, original_hash) = (counts(&pooled), pooled.source_hash());
+
+        pooled.merge(&year).unwrap();
+        // both models change from activity 1 to 2 between the fourth and fifth blocks
+        assert_eq!(pooled.precursors()[3].get_change_count(1, 2), 2.0);
+        assert_eq!(pooled.precursors()[1].get_change_count(1, 1), 1.0);
+        assert_ne!(pooled.source_hash(), original_hash);
+
+        pooled.subtract(&year).unwrap();
+        assert_eq!(counts(&pooled), original_counts);
+        assert_eq!(pooled.source_hash(), original_hash);
+    }
+
+    #[test]
+    fn scaling_multiplies_every_count() {
+        let mut pooled = model(&[[0, 0, 1, 1, 2, 0], [0, 3, 3, 3, 2, 0]], &TrainingOptions::default());
+        let expected: Vec<f64> = counts(&pooled).iter().map(|count| count * 2.5).collect();
+
+        pooled.scale(2.5);
+        assert_eq!(counts(&pooled), expected);
+    }
+
+    #[test]
+    fn models_trained_differently_cannot_be_merged() {
+        let days = [[0, 0, 1, 1, 2, 0]];
+        let mut pooled = model(&days, &TrainingOptions::default());
+
+        let own_state = model(&days, &TrainingOptions::default().with_missing_data(MissingDataPolicy::OwnState));
+        assert!(matches!(pooled.merge(&own_state), Err(Error::IncompatibleTrainingOptions { field: "missing data policies" })));
+
+        let students = MarkovModel { options: TrainingOptions::default().with_filter(DemographicFilter::all().with_student(true)), ..model(&days, &TrainingOptions::default()) };
+        assert!(matches!(pooled.subtract(&students), Err(Error::IncompatibleTrainingOptions { field: "demographic filters" })));
+
+        // the smoothing is only applied when building matrices, so it may differ
+        let smoothed = model(&days, &TrainingOptions::default()).with_smoothing(Smoothing::Additive { alpha: 1.0 });
+        assert!(pooled.merge(&smoothed).is_ok());
+    }
+}
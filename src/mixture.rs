@@ -0,0 +1,491 @@
+//! Implementation of a mixture of Markov chains, where every day is of one of several hidden day
+//! types (such as workdays and weekends) with transition matrices of its own, and a DayForecaster
+//! inferring the type of the day being forecast from what has been done so far.
+
+use std::{cell::RefCell, rc::Rc};
+
+use rand::{Rng, RngExt, SeedableRng, rngs::StdRng};
+
+use crate::{activity_block::ActivityBlockFile, day_forecaster::{DayForecaster, DistributionForecast, DistributionForecaster, Forecast, Reseed}, encodings::ActivityCategory, error::{Error, Result}, markov_chain::{self, BlockStateChangeMatrix, BlockStateChangeMatrixPrecursor, MissingDataPolicy, TrainingOptions}};
+
+/// options controlling how a mixture is trained
+#[derive(Debug, Clone, Copy)]
+pub struct MixtureOptions {
+    /// the number of day types
+    pub components: usize,
+
+    /// the greatest number of expectation-maximization iterations run, of which at least one always is
+    pub max_iterations: usize,
+
+    /// training stops once an iteration improves the mean log-likelihood of a day by less than this
+    pub tolerance: f64,
+
+    /// seeds the random assignment of days to day types training starts from
+    pub seed: u64,
+}
+
+impl Default for MixtureOptions {
+    /// trains 4 day types for at most 50 iterations
+    fn default() -> Self {
+        Self {
+            components: 4,
+            max_iterations: 50,
+            tolerance: 1e-4,
+            seed: 0,
+        }
+    }
+}
+
+impl MixtureOptions {
+    pub fn with_components(mut self, components: usize) -> Self {
+        self.components = components;
+        self
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// the natural log of the sum of the exponentials of some values, computed without overflow
+fn log_sum_exp(values: &[f64]) -> f64 {
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return max;
+    }
+    max + values.iter().map(|value| (value - max).exp()).sum::<f64>().ln()
+}
+
+/// one day type of a mixture
+pub struct MixtureComponent {
+    /// the proportion of days of this type
+    prior: f64,
+
+    /// the probability of each activity, indexed by code, in the first block of the day
+    initial: [f64; ActivityCategory::COUNT],
+
+    /// matrices[i] gives the transitions from block i to block i + 1
+    #[allow(clippy::vec_box)]
+    matrices: Vec<Box<BlockStateChangeMatrix>>,
+}
+
+impl MixtureComponent {
+    /// estimates a day type from the days of a file, each counted in proportion to its weight and
+    /// how likely it is to be of this type
+    fn estimate(
+        days: &[ActivityCategory],
+        weights: &[f64],
+        responsibilities: &[f64],
+        total_weight: f64,
+        options: &TrainingOptions,
+    ) -> Self {
+        let blocks_per_day = days.len() / weights.len();
+        let mut precursors = BlockStateChangeMatrixPrecursor::for_blocks(blocks_per_day);
+        let mut initial_counts = [0.0; ActivityCategory::COUNT];
+        let mut component_weight = 0.0;
+
+        for ((activities, &weight), &responsibility) in days.chunks_exact(blocks_per_day).zip(weights).zip(responsibilities) {
+            let weight = weight * responsibility;
+            if weight == 0.0 {
+                continue;
+            }
+
+            BlockStateChangeMatrixPrecursor::add_day(&mut precursors, activities, weight, options.missing_data);
+            if activities[0] != ActivityCategory::MissingData || options.missing_data == MissingDataPolicy::OwnState {
+                initial_counts[activities[0].into_code() as usize] += weight;
+            }
+            component_weight += weight;
+        }
+
+        Self {
+            prior: component_weight / total_weight,
+            initial: markov_chain::first_block_distribution(initial_counts, options.smoothing),
+            matrices: BlockStateChangeMatrix::from_precursors(&precursors, options.smoothing),
+        }
+    }
+
+    /// the proportion of days of this type
+    pub fn prior(&self) -> f64 {
+        self.prior
+    }
+
+    /// the probability of each activity, indexed by code, in the first block of the day
+    pub fn initial(&self) -> &[f64; ActivityCategory::COUNT] {
+        &self.initial
+    }
+
+    /// the transition matrices of each block of the day
+    pub fn matrices(&self) -> &[Box<BlockStateChangeMatrix>] {
+        &self.matrices
+    }
+
+    /// the natural log of the probability of a day beginning with activities, given that it is of
+    /// this type
+    /// blocks of missing data are only scored under MissingDataPolicy::OwnState
+    fn prefix_log_likelihood(&self, activities: &[ActivityCategory], missing_data: MissingDataPolicy) -> f64 {
+        let scored = |activity: ActivityCategory| {
+            activity != ActivityCategory::MissingData || missing_data == MissingDataPolicy::OwnState
+        };
+
+        let Some(&first) = activities.first() else {
+            return 0.0;
+        };
+
+        let mut log_likelihood = if scored(first) { self.initial[first.into_code() as usize].ln() } else { 0.0 };
+        for (matrix, pair) in self.matrices.iter().zip(activities.windows(2)) {
+            if scored(pair[0]) && scored(pair[1]) {
+                log_likelihood += matrix.transition_probability(pair[0].into_code(), pair[1].into_code()).ln();
+            }
+        }
+        log_likelihood
+    }
+}
+
+/// a mixture of per-block Markov chains, one for each hidden day type
+pub struct MarkovMixture {
+    components: Vec<MixtureComponent>,
+
+    /// how blocks of missing data were treated when training, and are treated when inferring day types
+    missing_data: MissingDataPolicy,
+
+    /// the mean log-likelihood of a training day after each iteration of training
+    log_likelihoods: Vec<f64>,
+}
+
+impl MarkovMixture {
+    /// trains a mixture on the days of a loaded activity block file selected by the training
+    /// options by expectation-maximization, with the smoothing of the training options applied to
+    /// every day type
+    /// fails if no days with a positive weight are selected or there are no components to train
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, training: &TrainingOptions, options: &MixtureOptions) -> Result<Self> {
+        if options.components == 0 {
+            return Err(Error::InvalidComponentCount(options.components));
+        }
+
+        let blocks_per_day = file.blocks_per_day();
+        let mut days = Vec::new();
+        let mut weights = Vec::new();
+        markov_chain::for_each_training_day(file, path, training, |activities, weight| {
+            if weight > 0.0 {
+                days.extend_from_slice(activities);
+                weights.push(weight);
+            }
+        })?;
+        if weights.is_empty() {
+            return Err(Error::NoTrainingDays { path: path.to_string() });
+        }
+        let total_weight: f64 = weights.iter().sum();
+
+        // responsibilities[c][d] is the probability that day d is of type c, starting from every
+        // day being assigned to a random type
+        let mut rng = StdRng::seed_from_u64(options.seed);
+        let assignments: Vec<usize> = weights.iter().map(|_| rng.random_range(0..options.components)).collect();
+        let mut responsibilities: Vec<Vec<f64>> = (0..options.components)
+            .map(|component| assignments.iter().map(|&assigned| (assigned == component) as u8 as f64).collect())
+            .collect();
+
+        let mut mixture = Self {
+            components: Vec::new(),
+            missing_data: training.missing_data,
+            log_likelihoods: Vec::new(),
+        };
+
+        let mut log_likelihoods = vec![0.0; options.components];
+        for _ in 0..options.max_iterations.max(1) {
+            mixture.components = responsibilities.iter()
+                .map(|responsibilities| MixtureComponent::estimate(&days, &weights, responsibilities, total_weight, training))
+                .collect();
+
+            let mut total_log_likelihood = 0.0;
+            for (day, (activities, &weight)) in days.chunks_exact(blocks_per_day).zip(&weights).enumerate() {
+                for (log_likelihood, component) in log_likelihoods.iter_mut().zip(&mixture.components) {
+                    *log_likelihood = component.prior.ln() + component.prefix_log_likelihood(activities, training.missing_data);
+                }
+
+                let day_log_likelihood = log_sum_exp(&log_likelihoods);
+                total_log_likelihood += weight * day_log_likelihood;
+                for (responsibilities, &log_likelihood) in responsibilities.iter_mut().zip(&log_likelihoods) {
+                    responsibilities[day] = (log_likelihood - day_log_likelihood).exp();
+                }
+            }
+
+            let mean_log_likelihood = total_log_likelihood / total_weight;
+            let improvement = mixture.log_likelihoods.last().map(|&previous| mean_log_likelihood - previous);
+            mixture.log_likelihoods.push(mean_log_likelihood);
+            if improvement.is_some_and(|improvement| improvement < options.tolerance) {
+                break;
+            }
+        }
+
+        Ok(mixture)
+    }
+
+    /// trains a mixture on the activity block file at the given path
+    pub fn from_block_file(path: &str, training: &TrainingOptions, options: &MixtureOptions) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, training, options)
+    }
+
+    /// the day types of the mixture
+    pub fn components(&self) -> &[MixtureComponent] {
+        &self.components
+    }
+
+    /// the mean log-likelihood of a training day after each iteration of training
+    /// smoothing keeps iterations from being guaranteed to improve it, so it may fall slightly
+    /// before training stops
+    pub fn log_likelihoods(&self) -> &[f64] {
+        &self.log_likelihoods
+    }
+
+    /// the number of blocks in each day of the mixture
+    pub fn blocks_per_day(&self) -> usize {
+        // a trained mixture always has at least one component
+        self.components[0].matrices.len()
+    }
+
+    /// the probability that a day beginning with activities is of each type
+    /// if the beginning is impossible under every type, the proportion of days of each type is given
+    pub fn posterior(&self, activities: &[ActivityCategory]) -> Vec<f64> {
+        let log_likelihoods: Vec<f64> = self.components.iter()
+            .map(|component| component.prior.ln() + component.prefix_log_likelihood(activities, self.missing_data))
+            .collect();
+
+        let total = log_sum_exp(&log_likelihoods);
+        if total == f64::NEG_INFINITY {
+            return self.components.iter().map(|component| component.prior).collect();
+        }
+        log_likelihoods.iter().map(|log_likelihood| (log_likelihood - total).exp()).collect()
+    }
+}
+
+/// forecasts days by inferring the type of the day from what has been done so far, then sampling
+/// paths from the chain of each type in proportion to how likely the day is to be of that type
+pub struct MixtureForecaster<R: Rng, const BLOCK_DURATION: u32> {
+    mixture: MarkovMixture,
+
+    /// the time the first block of each day begins, in minutes after midnight
+    day_start: u32,
+
+    /// the rng used to sample day types and transitions
+    rng_cell: RefCell<R>,
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> MixtureForecaster<R, BLOCK_DURATION> {
+    /// creates a new MixtureForecaster from a trained mixture, consuming the rng used to sample
+    /// day types and transitions
+    pub fn new(mixture: MarkovMixture, rng: R) -> Self {
+        assert!(
+            mixture.blocks_per_day() == Forecast::<BLOCK_DURATION>::block_count(),
+            "a forecaster with block_duration {} requires a mixture with {} blocks per day",
+            BLOCK_DURATION, Forecast::<BLOCK_DURATION>::block_count()
+        );
+
+        Self {
+            mixture,
+            day_start: 0,
+            rng_cell: RefCell::new(rng),
+        }
+    }
+
+    /// sets the time the first block of each day begins, in minutes after midnight
+    pub fn with_day_start(mut self, day_start: u32) -> Self {
+        self.day_start = day_start % (24 * 60);
+        self
+    }
+
+    /// creates a new MixtureForecaster trained on the activity block file at the given path
+    pub fn from_block_file(path: &str, training: &TrainingOptions, options: &MixtureOptions, rng: R) -> Result<Self> {
+        Self::train(&ActivityBlockFile::open(path)?, path, training, options, rng)
+    }
+
+    /// creates a new MixtureForecaster trained on a loaded activity block file
+    /// the file must contain as many blocks per day as there are blocks of BLOCK_DURATION in a day
+    /// path is only used for error reporting
+    pub fn train(file: &ActivityBlockFile, path: &str, training: &TrainingOptions, options: &MixtureOptions, rng: R) -> Result<Self> {
+        markov_chain::check_block_count::<BLOCK_DURATION>(file.blocks_per_day(), path)?;
+
+        let mixture = MarkovMixture::train(file, path, training, options)?;
+        Ok(Self::new(mixture, rng).with_day_start(file.header().day_start))
+    }
+
+    /// the mixture forecasts are sampled from
+    pub fn mixture(&self) -> &MarkovMixture {
+        &self.mixture
+    }
+
+    /// gets the rng from the forecaster, destroying it
+    pub fn rng(self) -> R {
+        self.rng_cell.into_inner()
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for MixtureForecaster<R, BLOCK_DURATION> {
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        let initial_block_count = initial_conditions.len();
+        assert!(initial_block_count > 0, "a markov forecast requires at least one initial block");
+
+        let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_block_count;
+        let posterior = self.mixture.posterior(&initial_conditions);
+        let mut rng = self.rng_cell.borrow_mut();
+
+        // the generated paths, alongside the probability of each path occurring under the mixture
+        let mut paths = Vec::with_capacity(forecast_count);
+        let mut total_probability = 0.0;
+
+        for _ in 0..forecast_count {
+            let mut remaining = rng.random::<f64>();
+            let sampled = posterior.iter()
+                .position(|&probability| {
+                    remaining -= probability;
+                    remaining < 0.0
+                })
+                .unwrap_or(posterior.len() - 1);
+
+            let matrices = &self.mixture.components[sampled].matrices[(initial_block_count - 1)..];
+            let mut previous = initial_conditions[initial_block_count - 1].into_code();
+            let mut forecast_data = Vec::with_capacity(additional_block_count);
+            for matrix in &matrices[..additional_block_count] {
+                let next = matrix.get_random_transition(previous, &mut *rng);
+                forecast_data.push(ActivityCategory::from_code(next).unwrap());
+                previous = next;
+            }
+
+            let probability: f64 = self.mixture.components.iter()
+                .zip(&posterior)
+                .map(|(component, &weight)| {
+                    weight * markov_chain::path_log_likelihood(&component.matrices, &initial_conditions, &forecast_data).exp()
+                })
+                .sum();
+
+            total_probability += probability;
+            paths.push((forecast_data, probability));
+        }
+
+        paths.into_iter()
+            .map(|(forecast_data, probability)| {
+                // normalize so that the certainties of all forecasts sum to 1
+                let certainty = if total_probability > 0.0 {
+                    probability / total_probability
+                } else {
+                    1.0 / forecast_count as f64
+                };
+
+                Box::new(Forecast::new(
+                    initial_conditions.clone(),
+                    forecast_data,
+                    certainty.clamp(0.0, 1.0),
+                ))
+            })
+            .collect()
+    }
+
+    fn day_start(&self) -> u32 {
+        self.day_start
+    }
+
+    fn log_likelihood(
+        &self,
+        initial_conditions: &[ActivityCategory],
+        continuation: &[ActivityCategory],
+    ) -> Option<f64> {
+        let log_likelihoods: Vec<f64> = self.mixture.components.iter()
+            .zip(self.mixture.posterior(initial_conditions))
+            .map(|(component, weight)| {
+                weight.ln() + markov_chain::path_log_likelihood(&component.matrices, initial_conditions, continuation)
+            })
+            .collect();
+        Some(log_sum_exp(&log_likelihoods))
+    }
+}
+
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for MixtureForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DistributionForecaster<BLOCK_DURATION> for MixtureForecaster<R, BLOCK_DURATION> {
+    /// the distributions of every day type, weighted by how likely the day is to be of that type
+    fn forecast_distribution(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+    ) -> DistributionForecast<BLOCK_DURATION> {
+        let additional_block_count = Forecast::<BLOCK_DURATION>::block_count() - initial_conditions.len();
+        let mut distributions = vec![[0.0; ActivityCategory::COUNT]; additional_block_count];
+
+        for (component, weight) in self.mixture.components.iter().zip(self.mixture.posterior(&initial_conditions)) {
+            let component_forecast = markov_chain::propagate_distribution::<BLOCK_DURATION>(&component.matrices, initial_conditions.clone());
+            for (distribution, component_distribution) in distributions.iter_mut().zip(component_forecast.distributions()) {
+                for (probability, component_probability) in distribution.iter_mut().zip(component_distribution) {
+                    *probability += weight * component_probability;
+                }
+            }
+        }
+
+        DistributionForecast::new(initial_conditions, distributions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity_block::test_block_file;
+
+    fn activities(codes: &[u8]) -> Vec<ActivityCategory> {
+        codes.iter().map(|&code| ActivityCategory::from_code(code).unwrap()).collect()
+    }
+
+    #[test]
+    fn training_separates_two_day_types() {
+        // a single chain cannot tell whether 1 or 2 follows a block of 0, but a day type can
+        let (workday, weekend) = ([1, 0, 1, 0, 1, 0], [2, 0, 2, 0, 2, 0]);
+        let days: Vec<[u8; 6]> = (0..20).map(|day| if day % 3 == 0 { weekend } else { workday }).collect();
+
+        let options = MixtureOptions::default().with_components(2).with_seed(1);
+        let mixture = MarkovMixture::train(&test_block_file(&days), "test", &TrainingOptions::default(), &options).unwrap();
+
+        let log_likelihoods = mixture.log_likelihoods();
+        assert!(log_likelihoods.len() > 1);
+        assert!(log_likelihoods.last().unwrap() > log_likelihoods.first().unwrap());
+
+        // each type of day is attributed to a different component with near certainty
+        let workday_posterior = mixture.posterior(&activities(&workday[..3]));
+        let weekend_posterior = mixture.posterior(&activities(&weekend[..3]));
+        let workday_component = if workday_posterior[0] > workday_posterior[1] { 0 } else { 1 };
+        assert!(workday_posterior[workday_component] > 0.99);
+        assert!(weekend_posterior[1 - workday_component] > 0.99);
+    }
+
+    #[test]
+    fn untrainable_mixtures_are_rejected() {
+        let file = test_block_file(&[[0, 0, 1, 1, 2, 0]]);
+
+        let no_components = MixtureOptions::default().with_components(0);
+        assert!(matches!(
+            MarkovMixture::train(&file, "test", &TrainingOptions::default(), &no_components),
+            Err(Error::InvalidComponentCount(0))
+        ));
+
+        let no_days = test_block_file(&[]);
+        assert!(matches!(
+            MarkovMixture::train(&no_days, "test", &TrainingOptions::default(), &MixtureOptions::default()),
+            Err(Error::NoTrainingDays { .. })
+        ));
+    }
+}
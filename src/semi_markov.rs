@@ -0,0 +1,507 @@
+//! Implementation of a semi-Markov DayForecaster, which samples whole activities alongside how
+//! long they last rather than a new activity for every block
+
+use std::{cell::RefCell, collections::BTreeMap, ops::Bound, rc::Rc};
+
+use rand::{Rng, RngExt, SeedableRng};
+
+use crate::{day_forecaster::{DayForecaster, Forecast, Reseed}, encodings::{self, ActivityCategory, ActivityRecord}, error::{Error, Result}, markov_chain::{MissingDataPolicy, TrainingOptions, Weighting}};
+
+/// the number of minutes in a day
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// the length of each time of day durations and transitions are conditioned on, in minutes
+const BIN_DURATION: u32 = 60;
+
+/// the number of times of day durations and transitions are conditioned on
+const BIN_COUNT: usize = (MINUTES_PER_DAY / BIN_DURATION) as usize;
+
+/// the weighted number of times each duration in minutes occurs
+type DurationCounts = BTreeMap<u32, f64>;
+
+/// an uninterrupted stretch of a single activity, in minutes after the start of the day
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    activity: ActivityCategory,
+    start: u32,
+    stop: u32,
+}
+
+/// the time of day bin containing a time in minutes after the start of the day
+fn bin(time: u32) -> usize {
+    (time / BIN_DURATION).min(BIN_COUNT as u32 - 1) as usize
+}
+
+/// splits the records of a day into segments in order of time, merging consecutive records of the
+/// same activity
+/// times are relative to day_start and cut off at the end of the day, so activities in progress
+/// when the diary begins or ends are truncated just as they are in the block files
+fn day_segments(records: &[ActivityRecord], day_start: u32, missing_data: MissingDataPolicy) -> Vec<Segment> {
+    let day_start = day_start as i32 * 60;
+
+    let mut segments: Vec<Segment> = records.iter()
+        .filter_map(|record| {
+            let activity = record.activity()?;
+            if activity == ActivityCategory::MissingData && missing_data != MissingDataPolicy::OwnState {
+                return None;
+            }
+
+            // the times of the record relative to the start of the day, such that it stops after it starts
+            let start = (record.start() - day_start).rem_euclid(24 * 60 * 60);
+            let mut stop = (record.stop() - day_start).rem_euclid(24 * 60 * 60);
+            if stop <= start {
+                stop += 24 * 60 * 60;
+            }
+
+            Some(Segment {
+                activity,
+                start: start as u32 / 60,
+                stop: (stop as u32 / 60).min(MINUTES_PER_DAY),
+            })
+        })
+        .filter(|segment| segment.stop > segment.start)
+        .collect();
+    segments.sort_by_key(|segment| segment.start);
+
+    if missing_data == MissingDataPolicy::Interpolate && !segments.is_empty() {
+        // split each gap between its neighbours, with the extra minute of an odd gap going to the earlier
+        for i in 1..segments.len() {
+            let (previous_stop, start) = (segments[i - 1].stop, segments[i].start);
+            if previous_stop < start {
+                let middle = previous_stop + (start - previous_stop).div_ceil(2);
+                segments[i - 1].stop = middle;
+                segments[i].start = middle;
+            }
+        }
+        segments[0].start = 0;
+        segments.last_mut().unwrap().stop = MINUTES_PER_DAY;
+    }
+
+    let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        if let Some(previous) = merged.last_mut()
+            && previous.activity == segment.activity
+            && previous.stop == segment.start {
+            previous.stop = segment.stop;
+        } else {
+            merged.push(segment);
+        }
+    }
+    merged
+}
+
+/// picks a key at random from weighted counts, returning it alongside its probability
+fn sample_weighted<K: Copy, R: Rng + ?Sized>(counts: &[(K, f64)], rng: &mut R) -> Option<(K, f64)> {
+    let total: f64 = counts.iter().map(|(_, count)| count).sum();
+    if total <= 0.0 {
+        return None;
+    }
+
+    let mut remaining = rng.random::<f64>() * total;
+    let mut last = None;
+    for &(key, count) in counts {
+        if count <= 0.0 {
+            continue;
+        }
+        if remaining < count {
+            return Some((key, count / total));
+        }
+        remaining -= count;
+        last = Some((key, count / total));
+    }
+
+    // rounding left the sample just past the end, so use the last possible key
+    last
+}
+
+/// a semi-Markov model of a day: the duration of each activity depends on the activity and the time
+/// of day it begins, and the activity that follows depends on the activity and the time it ends
+pub struct SemiMarkovModel {
+    /// the time each day begins in minutes after midnight
+    day_start: u32,
+
+    /// durations[b][a] counts the durations of activity a beginning in time of day bin b
+    durations: Vec<Vec<DurationCounts>>,
+
+    /// the durations of each activity at any time of day, used when a bin has no observations
+    pooled_durations: Vec<DurationCounts>,
+
+    /// transitions[b][i][j] counts changes from activity i to activity j in time of day bin b
+    transitions: Vec<[[f64; ActivityCategory::COUNT]; ActivityCategory::COUNT]>,
+
+    /// the changes between activities at any time of day, used when a bin has no observations
+    pooled_transitions: [[f64; ActivityCategory::COUNT]; ActivityCategory::COUNT],
+}
+
+impl SemiMarkovModel {
+    /// creates an empty model of days beginning at day_start minutes after midnight
+    pub fn new(day_start: u32) -> Result<Self> {
+        if day_start >= MINUTES_PER_DAY {
+            return Err(Error::InvalidDayStart(day_start));
+        }
+
+        Ok(Self {
+            day_start,
+            durations: vec![vec![DurationCounts::new(); ActivityCategory::COUNT]; BIN_COUNT],
+            pooled_durations: vec![DurationCounts::new(); ActivityCategory::COUNT],
+            transitions: vec![[[0.0; ActivityCategory::COUNT]; ActivityCategory::COUNT]; BIN_COUNT],
+            pooled_transitions: [[0.0; ActivityCategory::COUNT]; ActivityCategory::COUNT],
+        })
+    }
+
+    /// creates a model trained on the records of a day-id remapped csv file
+    /// day_start should match the block files the model is compared against, normally ATUS_DAY_START
+    pub fn from_day_records(input: &str, day_start: u32, options: &TrainingOptions) -> Result<Self> {
+        let mut model = Self::new(day_start)?;
+
+        for records in encodings::read_day_records(input)?.values() {
+            // every day has at least one record, as days are only created when a record is found
+            let first = &records[0];
+            if !options.filter.matches(&first.demographics()) {
+                continue;
+            }
+
+            let weight = match options.weighting {
+                Weighting::SurveyWeights => first.weight(),
+                Weighting::Unweighted => 1.0,
+            };
+            model.add_day(records, weight, options.missing_data);
+        }
+
+        Ok(model)
+    }
+
+    /// counts the durations of the activities of a day and the changes between them
+    pub fn add_day(&mut self, records: &[ActivityRecord], weight: f64, missing_data: MissingDataPolicy) {
+        let segments = day_segments(records, self.day_start, missing_data);
+
+        for segment in &segments {
+            let code = segment.activity.into_code() as usize;
+            let duration = segment.stop - segment.start;
+            *self.durations[bin(segment.start)][code].entry(duration).or_default() += weight;
+            *self.pooled_durations[code].entry(duration).or_default() += weight;
+        }
+
+        // only count changes between activities with no gap between them
+        for pair in segments.windows(2) {
+            if pair[0].stop == pair[1].start {
+                let (from, to) = (pair[0].activity.into_code() as usize, pair[1].activity.into_code() as usize);
+                self.transitions[bin(pair[0].stop)][from][to] += weight;
+                self.pooled_transitions[from][to] += weight;
+            }
+        }
+    }
+
+    /// the time each day begins in minutes after midnight
+    pub fn day_start(&self) -> u32 {
+        self.day_start
+    }
+
+    /// samples how long an activity beginning at start lasts in total, given it has already lasted
+    /// elapsed minutes, returning the duration alongside its probability
+    /// returns None if the activity was never seen lasting longer than elapsed
+    fn sample_duration<R: Rng + ?Sized>(
+        &self,
+        activity: ActivityCategory,
+        start: u32,
+        elapsed: u32,
+        rng: &mut R
+    ) -> Option<(u32, f64)> {
+        let code = activity.into_code() as usize;
+        let longer = |counts: &DurationCounts| counts.range((Bound::Excluded(elapsed), Bound::Unbounded))
+            .map(|(&duration, &count)| (duration, count))
+            .collect::<Vec<_>>();
+
+        sample_weighted(&longer(&self.durations[bin(start)][code]), rng)
+            .or_else(|| sample_weighted(&longer(&self.pooled_durations[code]), rng))
+    }
+
+    /// samples the activity following from at the given time, returning it alongside its probability
+    /// if from is None, the activity is sampled from every change at that time
+    /// if no change was seen, every other valid activity is equally likely
+    fn sample_transition<R: Rng + ?Sized>(
+        &self,
+        from: Option<ActivityCategory>,
+        time: u32,
+        rng: &mut R
+    ) -> (ActivityCategory, f64) {
+        let row = |matrix: &[[f64; ActivityCategory::COUNT]; ActivityCategory::COUNT]| match from {
+            Some(from) => matrix[from.into_code() as usize],
+            None => {
+                let mut row = [0.0; ActivityCategory::COUNT];
+                for counts in matrix {
+                    for (total, count) in row.iter_mut().zip(counts) {
+                        *total += count;
+                    }
+                }
+                row
+            }
+        };
+        let candidates = |row: [f64; ActivityCategory::COUNT]| row.into_iter()
+            .enumerate()
+            .map(|(code, count)| (ActivityCategory::from_code(code as u8).unwrap(), count))
+            .collect::<Vec<_>>();
+
+        sample_weighted(&candidates(row(&self.transitions[bin(time)])), rng)
+            .or_else(|| sample_weighted(&candidates(row(&self.pooled_transitions)), rng))
+            .unwrap_or_else(|| {
+                let others = ActivityCategory::valid_iter()
+                    .filter(|&activity| Some(activity) != from)
+                    .map(|activity| (activity, 1.0))
+                    .collect::<Vec<_>>();
+                sample_weighted(&others, rng).unwrap()
+            })
+    }
+
+    /// samples the segments of a day from the given time until the end of the day, continuing
+    /// current, which has already lasted elapsed minutes, returning them with the path probability
+    fn sample_rest_of_day<R: Rng + ?Sized>(
+        &self,
+        current: Option<ActivityCategory>,
+        now: u32,
+        elapsed: u32,
+        rng: &mut R
+    ) -> (Vec<Segment>, f64) {
+        let mut segments = Vec::new();
+        let mut probability = 1.0;
+        let mut time = now;
+
+        if let Some(activity) = current {
+            // an activity that has never lasted this long ends now
+            if let Some((duration, duration_probability)) = self.sample_duration(activity, now - elapsed, elapsed, rng) {
+                time = now - elapsed + duration;
+                probability *= duration_probability;
+                segments.push(Segment { activity, start: now, stop: time });
+            }
+        }
+
+        let mut previous = current;
+        while time < MINUTES_PER_DAY {
+            let (activity, transition_probability) = self.sample_transition(previous, time, rng);
+            probability *= transition_probability;
+
+            let duration = match self.sample_duration(activity, time, 0, rng) {
+                Some((duration, duration_probability)) => {
+                    probability *= duration_probability;
+                    duration
+                }
+                None => BIN_DURATION,
+            };
+
+            segments.push(Segment { activity, start: time, stop: time + duration });
+            previous = Some(activity);
+            time += duration;
+        }
+
+        (segments, probability)
+    }
+}
+
+/// the activity performing the most of the block [block_start, block_end) among time ordered segments
+fn block_activity(segments: &[Segment], block_start: u32, block_end: u32) -> ActivityCategory {
+    let mut minutes_per_code = [0; ActivityCategory::COUNT];
+    for segment in segments {
+        let overlap = segment.stop.clamp(block_start, block_end) - segment.start.clamp(block_start, block_end);
+        minutes_per_code[segment.activity.into_code() as usize] += overlap;
+    }
+
+    let mut max_code = ActivityCategory::MissingData.into_code();
+    let mut max_minutes = 0;
+    for (code, &minutes) in minutes_per_code.iter().enumerate() {
+        if minutes > max_minutes {
+            max_minutes = minutes;
+            max_code = code as u8;
+        }
+    }
+    ActivityCategory::from_code(max_code).unwrap()
+}
+
+/// forecasts days by sampling whole activities and their durations from a semi-Markov model
+pub struct SemiMarkovForecaster<R: Rng, const BLOCK_DURATION: u32> {
+    model: SemiMarkovModel,
+
+    /// the rng used to sample activities and durations
+    rng_cell: RefCell<R>,
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> SemiMarkovForecaster<R, BLOCK_DURATION> {
+    /// creates a new SemiMarkovForecaster from a trained model, consuming the rng used to sample
+    pub fn new(model: SemiMarkovModel, rng: R) -> Self {
+        Self {
+            model,
+            rng_cell: RefCell::new(rng),
+        }
+    }
+
+    /// creates a new SemiMarkovForecaster trained on the records of a day-id remapped csv file
+    pub fn from_day_records(input: &str, day_start: u32, options: &TrainingOptions, rng: R) -> Result<Self> {
+        Ok(Self::new(SemiMarkovModel::from_day_records(input, day_start, options)?, rng))
+    }
+
+    /// the model forecasts are sampled from
+    pub fn model(&self) -> &SemiMarkovModel {
+        &self.model
+    }
+
+    /// gets the rng from the forecaster, destroying it
+    pub fn rng(self) -> R {
+        self.rng_cell.into_inner()
+    }
+}
+
+impl<R: Rng, const BLOCK_DURATION: u32> DayForecaster<BLOCK_DURATION> for SemiMarkovForecaster<R, BLOCK_DURATION> {
+    fn forecast(
+        &self,
+        initial_conditions: Rc<Vec<ActivityCategory>>,
+        forecast_count: usize,
+    ) -> Vec<Box<Forecast<BLOCK_DURATION>>> {
+        let initial_block_count = initial_conditions.len();
+        assert!(initial_block_count > 0, "a semi-markov forecast requires at least one initial block");
+
+        let block_count = Forecast::<BLOCK_DURATION>::block_count();
+        let now = initial_block_count as u32 * BLOCK_DURATION;
+
+        // the activity in progress and how long it has lasted, judging by the trailing run of blocks
+        let last = initial_conditions[initial_block_count - 1];
+        let run = initial_conditions.iter().rev().take_while(|&&activity| activity == last).count();
+        let current = (last != ActivityCategory::MissingData
+            || self.model.pooled_durations[last.into_code() as usize].values().any(|&count| count > 0.0))
+            .then_some(last);
+
+        let mut rng = self.rng_cell.borrow_mut();
+
+        // the generated paths, alongside the probability of each path occurring
+        let mut paths = Vec::with_capacity(forecast_count);
+        let mut total_probability = 0.0;
+
+        for _ in 0..forecast_count {
+            let (segments, probability) = self.model.sample_rest_of_day(
+                current,
+                now,
+                run as u32 * BLOCK_DURATION,
+                &mut *rng
+            );
+
+            let forecast_data = (initial_block_count..block_count)
+                .map(|block_idx| {
+                    let block_start = block_idx as u32 * BLOCK_DURATION;
+                    block_activity(&segments, block_start, block_start + BLOCK_DURATION)
+                })
+                .collect::<Vec<_>>();
+
+            total_probability += probability;
+            paths.push((forecast_data, probability));
+        }
+
+        paths.into_iter()
+            .map(|(forecast_data, probability)| {
+                // normalize so that the certainties of all forecasts sum to 1
+                let certainty = if total_probability > 0.0 {
+                    probability / total_probability
+                } else {
+                    1.0 / forecast_count as f64
+                };
+
+                Box::new(Forecast::new(
+                    initial_conditions.clone(),
+                    forecast_data,
+                    certainty.clamp(0.0, 1.0),
+                ))
+            })
+            .collect()
+    }
+
+    fn day_start(&self) -> u32 {
+        self.model.day_start
+    }
+}
+
+impl<R: Rng + SeedableRng, const BLOCK_DURATION: u32> Reseed for SemiMarkovForecaster<R, BLOCK_DURATION> {
+    fn reseed(&self, seed: u64) {
+        *self.rng_cell.borrow_mut() = R::seed_from_u64(seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encodings::{ATUS_DAY_START, test_record};
+    use rand::rngs::StdRng;
+
+    /// a record of an activity between two times given in minutes after midnight
+    fn record(start: i32, stop: i32, activity: u8) -> ActivityRecord {
+        test_record(start * 60, stop * 60, activity)
+    }
+
+    /// the activity code, start and stop of every segment
+    fn spans(segments: &[Segment]) -> Vec<(u8, u32, u32)> {
+        segments.iter().map(|segment| (segment.activity.into_code(), segment.start, segment.stop)).collect()
+    }
+
+    #[test]
+    fn records_wrap_around_the_start_of_the_day() {
+        // a diary day from 4am, ending with sleep from 10pm past midnight to 6am
+        let records = [record(4 * 60, 12 * 60, 1), record(12 * 60, 22 * 60, 2), record(22 * 60, 6 * 60, 0)];
+        let segments = day_segments(&records, ATUS_DAY_START, MissingDataPolicy::Skip);
+
+        // the sleep is cut off at the end of the day, as it is in the block files
+        assert_eq!(spans(&segments), [(1, 0, 480), (2, 480, 1080), (0, 1080, 1440)]);
+    }
+
+    #[test]
+    fn interpolation_splits_gaps_between_neighbours() {
+        let records = [record(10, 60, 1), record(63, 24 * 60, 2)];
+
+        // the extra minute of the odd gap goes to the earlier activity
+        let segments = day_segments(&records, 0, MissingDataPolicy::Interpolate);
+        assert_eq!(spans(&segments), [(1, 0, 62), (2, 62, 1440)]);
+
+        let segments = day_segments(&records, 0, MissingDataPolicy::Skip);
+        assert_eq!(spans(&segments), [(1, 10, 60), (2, 63, 1440)]);
+    }
+
+    #[test]
+    fn missing_data_is_only_kept_as_its_own_state() {
+        let missing = ActivityCategory::MissingData.into_code();
+        let records = [record(0, 60, 1), record(60, 120, missing), record(120, 24 * 60, 1)];
+
+        let segments = day_segments(&records, 0, MissingDataPolicy::OwnState);
+        assert_eq!(spans(&segments), [(1, 0, 60), (missing, 60, 120), (1, 120, 1440)]);
+
+        let segments = day_segments(&records, 0, MissingDataPolicy::Skip);
+        assert_eq!(spans(&segments), [(1, 0, 60), (1, 120, 1440)]);
+
+        // once the gap is filled, the records either side of it are merged
+        let segments = day_segments(&records, 0, MissingDataPolicy::Interpolate);
+        assert_eq!(spans(&segments), [(1, 0, 1440)]);
+    }
+
+    #[test]
+    fn blocks_take_the_activity_covering_most_of_them() {
+        let segments = [
+            Segment { activity: ActivityCategory::from_code(1).unwrap(), start: 0, stop: 40 },
+            Segment { activity: ActivityCategory::from_code(2).unwrap(), start: 40, stop: 90 },
+        ];
+
+        assert_eq!(block_activity(&segments, 0, 60).into_code(), 1);
+        assert_eq!(block_activity(&segments, 60, 120).into_code(), 2);
+        assert_eq!(block_activity(&segments, 120, 180), ActivityCategory::MissingData);
+    }
+
+    #[test]
+    fn in_progress_activities_last_at_least_as_long_as_they_have_run() {
+        // activity 1 lasts either 2 or 8 hours from the start of the day
+        let mut model = SemiMarkovModel::new(0).unwrap();
+        model.add_day(&[record(0, 120, 1), record(120, 24 * 60, 2)], 1.0, MissingDataPolicy::Skip);
+        model.add_day(&[record(0, 480, 1), record(480, 24 * 60, 3)], 1.0, MissingDataPolicy::Skip);
+        let forecaster = SemiMarkovForecaster::<_, 60>::new(model, StdRng::seed_from_u64(0));
+
+        // after 5 hours of activity 1, it can only be the 8 hour stretch
+        let initial_conditions = Rc::new(vec![ActivityCategory::from_code(1).unwrap(); 5]);
+        for forecast in forecaster.forecast(initial_conditions, 20) {
+            assert_eq!(forecast.forecast_data().len(), 24 - 5);
+            assert!(forecast.forecast_data()[..3].iter().all(|activity| activity.into_code() == 1));
+            assert_eq!(forecast.forecast_data()[3].into_code(), 3);
+        }
+    }
+}
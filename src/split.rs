@@ -0,0 +1,310 @@
+//! Deterministic splitting of activity block files into train, validation and test sets, and into
+//! k folds for cross-validation.
+//!
+//! Days are assigned by hashing a key, so the same options always produce the same split, and every
+//! day sharing a key (such as every day of one respondent) lands in the same set.
+
+use crate::{activity_block::{self, ActivityBlockFile}, error::{Error, Result}};
+
+/// the key days are grouped by before being assigned to a set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SplitKey {
+    /// every day is assigned on its own, by its index in the file
+    #[default]
+    DayIndex,
+
+    /// every day of a survey year is assigned to the same set, which requires metadata
+    SurveyYear,
+
+    /// every day of a respondent is assigned to the same set, which requires metadata
+    CaseId,
+}
+
+/// the sets days are split into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Partition {
+    Train,
+    Validation,
+    Test,
+}
+
+/// options controlling how days are split
+#[derive(Debug, Clone, Copy)]
+pub struct SplitOptions {
+    pub key: SplitKey,
+
+    /// mixed into the hash of every key, so different seeds give different splits
+    pub seed: u64,
+
+    /// the expected proportion of keys assigned to the training set
+    pub train_fraction: f64,
+
+    /// the expected proportion of keys assigned to the validation set, with the rest assigned to
+    /// the test set
+    pub validation_fraction: f64,
+}
+
+impl Default for SplitOptions {
+    /// splits individual days 80/10/10
+    fn default() -> Self {
+        Self {
+            key: SplitKey::DayIndex,
+            seed: 0,
+            train_fraction: 0.8,
+            validation_fraction: 0.1,
+        }
+    }
+}
+
+impl SplitOptions {
+    pub fn with_key(mut self, key: SplitKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_fractions(mut self, train_fraction: f64, validation_fraction: f64) -> Self {
+        self.train_fraction = train_fraction;
+        self.validation_fraction = validation_fraction;
+        self
+    }
+
+    /// checks the fractions are between 0 and 1 and leave a non-negative fraction for the test set
+    fn check_fractions(&self) -> Result<()> {
+        let valid = |fraction: f64| (0.0..=1.0).contains(&fraction);
+        if valid(self.train_fraction) && valid(self.validation_fraction)
+            && self.train_fraction + self.validation_fraction <= 1.0 {
+            Ok(())
+        } else {
+            Err(Error::InvalidSplitFractions {
+                train_fraction: self.train_fraction,
+                validation_fraction: self.validation_fraction,
+            })
+        }
+    }
+
+    /// the hash of the key of a day, mixed with the seed
+    fn day_hash(&self, file: &ActivityBlockFile, path: &str, day_index: usize) -> Result<u64> {
+        let key = match self.key {
+            SplitKey::DayIndex => day_index as u64,
+            SplitKey::SurveyYear | SplitKey::CaseId => {
+                let metadata = file.day_metadata(day_index)
+                    .ok_or_else(|| Error::MissingMetadata { path: path.to_string() })?;
+                match self.key {
+                    SplitKey::SurveyYear => metadata.year as u64,
+                    _ => metadata.case_id,
+                }
+            }
+        };
+
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.seed.to_le_bytes());
+        bytes[8..].copy_from_slice(&key.to_le_bytes());
+        Ok(activity_block::fnv1a(&bytes))
+    }
+
+    /// the set a day of a file is assigned to
+    /// fails if the fractions are not between 0 and 1 or sum to more than 1
+    /// path is only used for error reporting
+    pub fn partition(&self, file: &ActivityBlockFile, path: &str, day_index: usize) -> Result<Partition> {
+        self.check_fractions()?;
+
+        // the hash as a number uniformly distributed in [0, 1)
+        let position = (self.day_hash(file, path, day_index)? >> 11) as f64 / (1u64 << 53) as f64;
+
+        Ok(if position < self.train_fraction {
+            Partition::Train
+        } else if position < self.train_fraction + self.validation_fraction {
+            Partition::Validation
+        } else {
+            Partition::Test
+        })
+    }
+
+    /// the fold a day of a file is assigned to, out of fold_count folds
+    /// fails if fold_count is zero
+    /// path is only used for error reporting
+    pub fn fold(&self, file: &ActivityBlockFile, path: &str, day_index: usize, fold_count: usize) -> Result<usize> {
+        if fold_count == 0 {
+            return Err(Error::InvalidFoldCount(fold_count));
+        }
+        Ok((self.day_hash(file, path, day_index)? % fold_count as u64) as usize)
+    }
+}
+
+/// the days of a file split into train, validation and test sets
+pub struct Split {
+    pub train: ActivityBlockFile,
+    pub validation: ActivityBlockFile,
+    pub test: ActivityBlockFile,
+}
+
+/// splits the days of a loaded activity block file into train, validation and test sets, keeping
+/// the days of each set in the order they appear in the file
+/// fails if the fractions of the options are not between 0 and 1 or sum to more than 1
+/// path is only used for error reporting
+pub fn split(file: &ActivityBlockFile, path: &str, options: &SplitOptions) -> Result<Split> {
+    options.check_fractions()?;
+
+    let mut indices = [Vec::new(), Vec::new(), Vec::new()];
+    for day_index in 0..file.day_count() {
+        let set = match options.partition(file, path, day_index)? {
+            Partition::Train => 0,
+            Partition::Validation => 1,
+            Partition::Test => 2,
+        };
+        indices[set].push(day_index);
+    }
+
+    let [train, validation, test] = indices;
+    Ok(Split {
+        train: file.subset(train),
+        validation: file.subset(validation),
+        test: file.subset(test),
+    })
+}
+
+/// the indices of the days of a loaded activity block file in each of fold_count folds, in the
+/// order they appear in the file
+/// fails if fold_count is zero
+/// path is only used for error reporting
+pub fn k_fold_indices(file: &ActivityBlockFile, path: &str, options: &SplitOptions, fold_count: usize) -> Result<Vec<Vec<usize>>> {
+    if fold_count == 0 {
+        return Err(Error::InvalidFoldCount(fold_count));
+    }
+
+    let mut indices = vec![Vec::new(); fold_count];
+    for day_index in 0..file.day_count() {
+        indices[options.fold(file, path, day_index, fold_count)?].push(day_index);
+    }
+    Ok(indices)
+}
+
+/// splits the days of a loaded activity block file into fold_count folds, keeping the days of each
+/// fold in the order they appear in the file
+/// fails if fold_count is zero
+/// path is only used for error reporting
+pub fn k_fold(file: &ActivityBlockFile, path: &str, options: &SplitOptions, fold_count: usize) -> Result<Vec<ActivityBlockFile>> {
+    let indices = k_fold_indices(file, path, options, fold_count)?;
+    Ok(indices.into_iter().map(|fold| file.subset(fold)).collect())
+}
+
+/// splits an activity block file into train, validation and test sets, writing the results to
+/// "{output}_train.ablk", "{output}_validation.ablk" and "{output}_test.ablk"
+pub fn split_block_file(input: &str, output: &str, options: &SplitOptions) -> Result<()> {
+    let split = split(&ActivityBlockFile::open(input)?, input, options)?;
+    split.train.save(&format!("{output}_train.ablk"))?;
+    split.validation.save(&format!("{output}_validation.ablk"))?;
+    split.test.save(&format!("{output}_test.ablk"))
+}
+
+/// splits an activity block file into fold_count folds, writing fold i to "{output}_fold{i}.ablk"
+pub fn k_fold_block_file(input: &str, output: &str, options: &SplitOptions, fold_count: usize) -> Result<()> {
+    let folds = k_fold(&ActivityBlockFile::open(input)?, input, options, fold_count)?;
+    for (i, fold) in folds.iter().enumerate() {
+        fold.save(&format!("{output}_fold{i}.ablk"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{activity_block::{ActivityBlockHeader, ActivityBlockWriter, DayMetadata, test_block_file}, demographics::Demographics};
+
+    /// a file of 60 days with metadata, where every 3 consecutive days are of the same respondent
+    fn respondent_file() -> ActivityBlockFile {
+        let header = ActivityBlockHeader { has_metadata: true, ..ActivityBlockHeader::with_block_duration(4 * 60, 60).unwrap() };
+        let mut writer = ActivityBlockWriter::new(Vec::new(), "test", header).unwrap();
+        for day in 0..60 {
+            writer.write_codes(&[(day % 20) as u8; 6]).unwrap();
+            writer.write_metadata(DayMetadata {
+                year: 2020 + (day % 4) as u32,
+                case_id: day as u64 / 3,
+                weight: 1.0,
+                demographics: Demographics::default(),
+            }).unwrap();
+        }
+        ActivityBlockFile::from_bytes(&writer.finish().unwrap(), "test").unwrap()
+    }
+
+    fn partitions(file: &ActivityBlockFile, options: &SplitOptions) -> Vec<Partition> {
+        (0..file.day_count()).map(|day| options.partition(file, "test", day).unwrap()).collect()
+    }
+
+    #[test]
+    fn splits_depend_only_on_the_seed() {
+        let file = respondent_file();
+        let options = SplitOptions::default().with_fractions(0.5, 0.25);
+
+        assert_eq!(partitions(&file, &options), partitions(&file, &options));
+        assert_eq!(partitions(&file, &options.with_seed(1)), partitions(&file, &options.with_seed(1)));
+        assert_ne!(partitions(&file, &options), partitions(&file, &options.with_seed(1)));
+
+        let folds = |seed| (0..file.day_count())
+            .map(|day| options.with_seed(seed).fold(&file, "test", day, 5).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(folds(0), folds(0));
+        assert_ne!(folds(0), folds(1));
+    }
+
+    #[test]
+    fn days_of_a_respondent_are_never_split_up() {
+        let file = respondent_file();
+        let options = SplitOptions::default().with_key(SplitKey::CaseId).with_fractions(0.5, 0.25);
+
+        let partitions = partitions(&file, &options);
+        for respondent in partitions.chunks(3) {
+            assert!(respondent.iter().all(|&partition| partition == respondent[0]));
+        }
+        // not every respondent lands in the same set
+        assert!(partitions.iter().any(|&partition| partition != partitions[0]));
+
+        let folds = k_fold(&file, "test", &options, 4).unwrap();
+        for fold in &folds {
+            for day in 0..fold.day_count() {
+                let case_id = fold.day_metadata(day).unwrap().case_id;
+                let days_of_case = (0..fold.day_count()).filter(|&other| fold.day_metadata(other).unwrap().case_id == case_id).count();
+                assert_eq!(days_of_case, 3);
+            }
+        }
+        assert_eq!(folds.iter().map(ActivityBlockFile::day_count).sum::<usize>(), 60);
+    }
+
+    #[test]
+    fn keys_from_metadata_require_metadata() {
+        let file = test_block_file(&[[0; 6], [1; 6]]);
+
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&6u32.to_le_bytes());
+        legacy.extend_from_slice(&1u64.to_le_bytes());
+        legacy.extend_from_slice(&[0; 6]);
+        let legacy = ActivityBlockFile::from_bytes(&legacy, "test").unwrap();
+
+        for key in [SplitKey::SurveyYear, SplitKey::CaseId] {
+            let options = SplitOptions::default().with_key(key);
+            for file in [&file, &legacy] {
+                assert!(matches!(split(file, "test", &options), Err(Error::MissingMetadata { .. })));
+                assert!(matches!(k_fold(file, "test", &options, 2), Err(Error::MissingMetadata { .. })));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_fractions_and_fold_counts_are_rejected() {
+        let file = respondent_file();
+
+        for (train_fraction, validation_fraction) in [(-0.1, 0.5), (0.5, f64::NAN), (0.8, 0.3), (1.5, 0.0)] {
+            let options = SplitOptions::default().with_fractions(train_fraction, validation_fraction);
+            assert!(matches!(split(&file, "test", &options), Err(Error::InvalidSplitFractions { .. })));
+        }
+        assert!(split(&file, "test", &SplitOptions::default().with_fractions(1.0, 0.0)).is_ok());
+
+        assert!(matches!(k_fold(&file, "test", &SplitOptions::default(), 0), Err(Error::InvalidFoldCount(0))));
+        assert!(matches!(SplitOptions::default().fold(&file, "test", 0, 0), Err(Error::InvalidFoldCount(0))));
+    }
+}